@@ -0,0 +1,8 @@
+/// File timestamps in seconds since the Unix epoch, as returned by the
+/// GetFileTimes syscall. Zero where the filesystem doesn't record a time.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileTimes {
+    pub created: u64,
+    pub modified: u64,
+}
@@ -0,0 +1,70 @@
+/// Identifies a physical key. Values are PS/2 set 1 make codes, with keys
+/// sent behind an E0 prefix in the high byte, eg. right ctrl is 0xe01d.
+pub type KeyCode = u16;
+
+pub const KEY_ESCAPE: KeyCode = 0x01;
+pub const KEY_BACKSPACE: KeyCode = 0x0e;
+pub const KEY_TAB: KeyCode = 0x0f;
+pub const KEY_ENTER: KeyCode = 0x1c;
+pub const KEY_LEFT_CTRL: KeyCode = 0x1d;
+pub const KEY_LEFT_SHIFT: KeyCode = 0x2a;
+pub const KEY_RIGHT_SHIFT: KeyCode = 0x36;
+pub const KEY_LEFT_ALT: KeyCode = 0x38;
+pub const KEY_SPACE: KeyCode = 0x39;
+pub const KEY_CAPS_LOCK: KeyCode = 0x3a;
+pub const KEY_NUM_LOCK: KeyCode = 0x45;
+pub const KEY_SCROLL_LOCK: KeyCode = 0x46;
+pub const KEY_KEYPAD_ENTER: KeyCode = 0xe01c;
+pub const KEY_RIGHT_CTRL: KeyCode = 0xe01d;
+pub const KEY_KEYPAD_SLASH: KeyCode = 0xe035;
+pub const KEY_RIGHT_ALT: KeyCode = 0xe038;
+pub const KEY_HOME: KeyCode = 0xe047;
+pub const KEY_UP: KeyCode = 0xe048;
+pub const KEY_PAGE_UP: KeyCode = 0xe049;
+pub const KEY_LEFT: KeyCode = 0xe04b;
+pub const KEY_RIGHT: KeyCode = 0xe04d;
+pub const KEY_END: KeyCode = 0xe04f;
+pub const KEY_DOWN: KeyCode = 0xe050;
+pub const KEY_PAGE_DOWN: KeyCode = 0xe051;
+pub const KEY_INSERT: KeyCode = 0xe052;
+pub const KEY_DELETE: KeyCode = 0xe053;
+pub const KEY_PAUSE: KeyCode = 0xe11d;
+
+// KeyEvent::modifiers bits
+pub const MOD_SHIFT: u8 = 0x01;
+pub const MOD_CTRL: u8 = 0x02;
+pub const MOD_ALT: u8 = 0x04;
+pub const MOD_ALT_GR: u8 = 0x08;
+pub const MOD_CAPS_LOCK: u8 = 0x10;
+pub const MOD_NUM_LOCK: u8 = 0x20;
+pub const MOD_SCROLL_LOCK: u8 = 0x40;
+
+/// A key press or release, as read from the raw keyboard device. Modifier
+/// state is as it was after the event was applied.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyEvent {
+    pub keycode: KeyCode,
+    pub pressed: u8,
+    pub modifiers: u8,
+}
+
+/// Layouts used to turn key events into console text
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keymap {
+    Us = 0,
+    Uk = 1,
+    De = 2,
+}
+
+impl Keymap {
+    pub fn from_u64(keymap: u64) -> Option<Keymap> {
+        match keymap {
+            0 => Some(Keymap::Us),
+            1 => Some(Keymap::Uk),
+            2 => Some(Keymap::De),
+            _ => None,
+        }
+    }
+}
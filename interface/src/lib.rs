@@ -24,6 +24,22 @@ mod macros {
     }
 }
 
+mod fs;
+mod keyboard;
+mod log;
+mod mouse;
+mod pci;
+mod process;
 mod syscall;
+mod task;
+mod tty;
 
+pub use fs::*;
+pub use keyboard::*;
+pub use log::*;
+pub use mouse::*;
+pub use pci::*;
+pub use process::*;
 pub use syscall::*;
+pub use task::*;
+pub use tty::*;
@@ -0,0 +1,47 @@
+/// Severity of a kernel log record. Lower values are more severe.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    pub fn from_u8(level: u8) -> Option<LogLevel> {
+        match level {
+            1 => Some(LogLevel::Error),
+            2 => Some(LogLevel::Warn),
+            3 => Some(LogLevel::Info),
+            4 => Some(LogLevel::Debug),
+            5 => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+/// Precedes each record returned by the ReadLog syscall. The module tag and
+/// then the message follow immediately, and the next record starts `len`
+/// bytes after the start of this header.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogRecordHeader {
+    pub seq: u64,
+    pub len: u16,
+    pub level: u8,
+    pub module_len: u8,
+    pub message_len: u16,
+    pub _reserved: u16,
+}
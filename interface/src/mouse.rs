@@ -0,0 +1,17 @@
+// MouseEvent::buttons bits
+pub const MOUSE_LEFT: u8 = 0x01;
+pub const MOUSE_RIGHT: u8 = 0x02;
+pub const MOUSE_MIDDLE: u8 = 0x04;
+
+/// Relative pointer motion and button state, as read from the raw mouse
+/// device. Positive dy is down the screen, positive wheel is towards the
+/// user.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub wheel: i8,
+    pub buttons: u8,
+    pub _reserved: u16,
+}
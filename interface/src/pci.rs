@@ -0,0 +1,17 @@
+/// Describes a single PCI function, as returned by the ListPciDevices syscall
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PciDeviceInfo {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub _reserved: u8,
+}
@@ -0,0 +1,70 @@
+/// A slice in the caller's memory, passed to syscalls by address. `len` is
+/// in elements, not bytes.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UserSlice {
+    pub addr: u64,
+    pub len: u64,
+}
+
+/// Arguments to SpawnProcess
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SpawnArgs {
+    /// Path of the executable, in bytes
+    pub path: UserSlice,
+    /// UserSlices of the argument strings, the program name first by
+    /// convention
+    pub argv: UserSlice,
+    /// UserSlices of the environment strings, each `NAME=value`
+    pub env: UserSlice,
+    /// Handles to give the new process, which it sees numbered from 1 in
+    /// the same order
+    pub handles: UserSlice,
+    /// Size of the stack to start on, zero for DEFAULT_STACK_PAGES
+    pub stack_pages: u64,
+}
+
+/// Pages of stack a task gets unless it asks for another size, at
+/// SpawnProcess or CreateTask
+pub const DEFAULT_STACK_PAGES: u64 = 16;
+pub const MAX_STACK_PAGES: u64 = 4096;
+
+// A new process starts with rsp pointing at its start info, laid out as the
+// System V ABI has it:
+//
+//     argc
+//     argv[0] .. argv[argc - 1], 0
+//     envp[0] .. envp[n - 1], 0
+//     auxv, AuxEntry pairs ending in AT_NULL
+//
+// with the nul terminated strings they point to above. rsp is 16 byte
+// aligned.
+
+/// An entry in the auxiliary vector
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AuxEntry {
+    pub kind: u64,
+    pub value: u64,
+}
+
+/// Ends the auxiliary vector
+pub const AT_NULL: u64 = 0;
+/// Address of the executable's program headers
+pub const AT_PHDR: u64 = 3;
+/// Size of a program header
+pub const AT_PHENT: u64 = 4;
+/// Number of program headers
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+/// Entry point of the executable
+pub const AT_ENTRY: u64 = 9;
+
+// our own, above anything other systems use:
+
+/// First handle the process was started with
+pub const AT_HANDLE_BASE: u64 = 0x1000;
+/// Number of handles the process was started with, numbered consecutively
+/// from AT_HANDLE_BASE
+pub const AT_HANDLE_COUNT: u64 = 0x1001;
@@ -15,6 +15,22 @@ enum64! {
         13  => ReadStream,
         14  => WriteStream,
         15  => OpenFile,
+        16  => ListPciDevices,
+        17  => ReadLog,
+        18  => SetLogLevel,
+        19  => SetKeymap,
+        20  => SetTtyMode,
+        21  => GetTtySize,
+        22  => Sleep,
+        23  => GetMonotonicTime,
+        24  => GetWallTime,
+        25  => GetFileTimes,
+        26  => SetPriority,
+        27  => SpawnProcess,
+        28  => ReservePages,
+        29  => ClonePageContext,
+        30  => CreateMemoryObject,
+        31  => MapMemoryObject,
     }
 }
 
@@ -30,6 +46,8 @@ enum64! {
         0xffff_ffff_0000_0008 => IoError,
         0xffff_ffff_0000_0009 => NoFile,
         0xffff_ffff_0000_0010 => InvalidOperation,
+        0xffff_ffff_0000_0011 => Interrupted,
+        0xffff_ffff_0000_0012 => BadExecutable,
     }
 }
 
@@ -0,0 +1,27 @@
+/// Scheduling priority of a task. Ready tasks always run before ready tasks
+/// of a lower priority, and take turns with those of the same priority.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    // runs only when nothing else wants the CPU
+    Idle = 0,
+    // background work which can wait for anything interactive
+    Low = 1,
+    #[default]
+    Normal = 2,
+    High = 3,
+}
+
+pub const PRIORITY_COUNT: usize = 4;
+
+impl Priority {
+    pub fn from_u64(priority: u64) -> Option<Priority> {
+        match priority {
+            0 => Some(Priority::Idle),
+            1 => Some(Priority::Low),
+            2 => Some(Priority::Normal),
+            3 => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,27 @@
+/// How console reads are processed before they reach the reader
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyMode {
+    // reads return whole lines, edited and echoed by the kernel
+    Canonical = 0,
+    // reads return text as soon as it is typed, with no echo or editing
+    Raw = 1,
+}
+
+impl TtyMode {
+    pub fn from_u64(mode: u64) -> Option<TtyMode> {
+        match mode {
+            0 => Some(TtyMode::Canonical),
+            1 => Some(TtyMode::Raw),
+            _ => None,
+        }
+    }
+}
+
+/// Console dimensions in character cells
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TtySize {
+    pub cols: u16,
+    pub rows: u16,
+}
@@ -2,4 +2,5 @@ fn main() {
     println!("cargo:rerun-if-changed=target/x86_64-kernel/start.o");
     println!("cargo:rerun-if-changed=target/x86_64-kernel/aux.o");
     println!("cargo:rerun-if-changed=target/x86_64-kernel/isrs.o");
+    println!("cargo:rerun-if-changed=target/x86_64-kernel/trampoline.bin");
 }
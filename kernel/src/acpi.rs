@@ -0,0 +1,310 @@
+use core::mem;
+use core::ptr;
+use core::slice;
+
+use arrayvec::ArrayVec;
+
+use crate::mem::kvirt;
+use crate::mem::page::{PageFlags, PAGE_SIZE};
+use crate::mem::phys::RawPhys;
+use crate::util::EarlyInit;
+
+static TABLES: EarlyInit<ArrayVec<[&'static SdtHeader; 32]>> = EarlyInit::new();
+
+// the BIOS data area holds the real mode segment of the EBDA at this address:
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+
+const BIOS_ROM_BEGIN: u64 = 0xe0000;
+const BIOS_ROM_END: u64 = 0x100000;
+
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the following fields are only valid for revision >= 2:
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Returns the table body following the header
+    pub fn data(&self) -> &[u8] {
+        let header_len = mem::size_of::<SdtHeader>();
+
+        // map_table never lets through a table shorter than its header:
+        let data_len = self.data_len()
+            .expect("SdtHeader::data on table shorter than its header");
+
+        unsafe {
+            let base = self as *const SdtHeader as *const u8;
+            slice::from_raw_parts(base.add(header_len), data_len)
+        }
+    }
+
+    fn data_len(&self) -> Option<usize> {
+        (self.length as usize).checked_sub(mem::size_of::<SdtHeader>())
+    }
+}
+
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Maps an arbitrary physical byte range into kernel space
+unsafe fn map_phys_range(addr: u64, len: usize) -> *const u8 {
+    let offset = (addr % PAGE_SIZE as u64) as usize;
+    let base = RawPhys(addr - offset as u64);
+    let page_count = (offset + len + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    let virt = kvirt::map_physical(base, page_count, PageFlags::PRESENT)
+        .expect("kvirt::map_physical in acpi");
+
+    virt.as_ptr().add(offset)
+}
+
+unsafe fn scan_rsdp(addr: u64, len: usize) -> Option<Rsdp> {
+    let region = map_phys_range(addr, len);
+
+    for offset in (0..len).step_by(16) {
+        let candidate = region.add(offset);
+
+        if slice::from_raw_parts(candidate, 8) != b"RSD PTR " {
+            continue;
+        }
+
+        // the checksum only covers the revision 0 structure:
+        if !checksum_valid(slice::from_raw_parts(candidate, 20)) {
+            continue;
+        }
+
+        return Some(ptr::read_unaligned(candidate as *const Rsdp));
+    }
+
+    None
+}
+
+unsafe fn find_rsdp() -> Option<Rsdp> {
+    let ebda_segment = ptr::read_unaligned(map_phys_range(EBDA_SEGMENT_PTR, 2) as *const u16);
+    let ebda = (ebda_segment as u64) << 4;
+
+    if ebda != 0 {
+        if let Some(rsdp) = scan_rsdp(ebda, 1024) {
+            return Some(rsdp);
+        }
+    }
+
+    scan_rsdp(BIOS_ROM_BEGIN, (BIOS_ROM_END - BIOS_ROM_BEGIN) as usize)
+}
+
+unsafe fn map_table(addr: u64) -> Option<&'static SdtHeader> {
+    let header = &*(map_phys_range(addr, mem::size_of::<SdtHeader>()) as *const SdtHeader);
+
+    if header.data_len().is_none() {
+        crate::warn!("table at {:#x} is shorter than its header", addr);
+        return None;
+    }
+
+    let table = map_phys_range(addr, header.length as usize);
+
+    if !checksum_valid(slice::from_raw_parts(table, header.length as usize)) {
+        crate::warn!("bad checksum for table at {:#x}", addr);
+        return None;
+    }
+
+    Some(&*(table as *const SdtHeader))
+}
+
+// Safety: must not be called more than once
+pub unsafe fn init() {
+    let mut tables = ArrayVec::new();
+
+    match find_rsdp() {
+        None => {
+            crate::warn!("no RSDP found");
+        }
+        Some(rsdp) => {
+            let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+                (map_table(rsdp.xsdt_address), mem::size_of::<u64>())
+            } else {
+                (map_table(rsdp.rsdt_address as u64), mem::size_of::<u32>())
+            };
+
+            if let Some(root) = root {
+                for entry in root.data().chunks_exact(entry_size) {
+                    let addr = match entry_size {
+                        4 => u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as u64,
+                        _ => u64::from_le_bytes([
+                            entry[0], entry[1], entry[2], entry[3],
+                            entry[4], entry[5], entry[6], entry[7],
+                        ]),
+                    };
+
+                    if let Some(table) = map_table(addr) {
+                        crate::info!("found {}",
+                            core::str::from_utf8(&table.signature).unwrap_or("????"));
+
+                        if tables.try_push(table).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    EarlyInit::set(&TABLES, tables);
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    TABLES.iter()
+        .find(|table| &table.signature == signature)
+        .cloned()
+}
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS: u8 = 5;
+
+// set in the MADT flags when the legacy 8259 PICs are present
+const MADT_PCAT_COMPAT: u32 = 0x1;
+
+// set in a local APIC entry's flags when the processor can be used
+const LOCAL_APIC_ENABLED: u32 = 0x1;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+/// Multiple APIC Description Table, which describes the interrupt
+/// controllers in the system
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic_addr: u64,
+    pub has_8259: bool,
+    entries: &'static [u8],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    LocalApic { processor_id: u8, apic_id: u8, enabled: bool },
+    IoApic { id: u8, addr: u32, gsi_base: u32 },
+    /// An ISA IRQ wired to a different global system interrupt, or with a
+    /// polarity or trigger mode other than the ISA default
+    InterruptOverride { irq: u8, gsi: u32, flags: u16 },
+    LocalApicAddress(u64),
+    Other(u8),
+}
+
+impl Madt {
+    pub fn entries(&self) -> MadtEntries {
+        MadtEntries { data: self.entries }
+    }
+}
+
+pub struct MadtEntries {
+    data: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        loop {
+            if self.data.len() < 2 {
+                return None;
+            }
+
+            let (kind, len) = (self.data[0], self.data[1] as usize);
+
+            if len < 2 || len > self.data.len() {
+                crate::warn!("malformed MADT entry");
+                self.data = &[];
+                return None;
+            }
+
+            let entry = &self.data[..len];
+            self.data = &self.data[len..];
+
+            return Some(match (kind, len) {
+                (MADT_LOCAL_APIC, 8) => MadtEntry::LocalApic {
+                    processor_id: entry[2],
+                    apic_id: entry[3],
+                    enabled: (read_u32(entry, 4) & LOCAL_APIC_ENABLED) != 0,
+                },
+                (MADT_IO_APIC, 12) => MadtEntry::IoApic {
+                    id: entry[2],
+                    addr: read_u32(entry, 4),
+                    gsi_base: read_u32(entry, 8),
+                },
+                (MADT_INTERRUPT_OVERRIDE, 10) => MadtEntry::InterruptOverride {
+                    irq: entry[3],
+                    gsi: read_u32(entry, 4),
+                    flags: read_u16(entry, 8),
+                },
+                (MADT_LOCAL_APIC_ADDRESS, 12) => {
+                    MadtEntry::LocalApicAddress(read_u64(entry, 4))
+                }
+                (MADT_LOCAL_APIC, _) |
+                (MADT_IO_APIC, _) |
+                (MADT_INTERRUPT_OVERRIDE, _) |
+                (MADT_LOCAL_APIC_ADDRESS, _) => {
+                    crate::warn!("bad length {} for MADT entry type {}", len, kind);
+                    continue;
+                }
+                (kind, _) => MadtEntry::Other(kind),
+            });
+        }
+    }
+}
+
+pub fn madt() -> Option<Madt> {
+    let data = find_table(b"APIC")?.data();
+
+    if data.len() < 8 {
+        return None;
+    }
+
+    let mut madt = Madt {
+        local_apic_addr: read_u32(data, 0) as u64,
+        has_8259: (read_u32(data, 4) & MADT_PCAT_COMPAT) != 0,
+        entries: &data[8..],
+    };
+
+    // a 64 bit address entry takes precedence over the one in the header:
+    for entry in madt.entries() {
+        if let MadtEntry::LocalApicAddress(addr) = entry {
+            madt.local_apic_addr = addr;
+        }
+    }
+
+    Some(madt)
+}
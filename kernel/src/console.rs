@@ -1,6 +1,9 @@
 use core::fmt::{self, Write};
 
+use interface::TtySize;
+
 use crate::critical::Critical;
+use crate::device::serial;
 use crate::sync::{Mutex, MutexGuard};
 
 mod vga;
@@ -14,6 +17,9 @@ pub(self) enum Console {
 
 impl Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        // all console output is mirrored to the serial port for headless runs
+        serial::Console.write_str(s)?;
+
         match self {
             Console::PortE9(con) => con.write_str(s),
             Console::VgaText(con) => con.write_str(s),
@@ -21,16 +27,42 @@ impl Write for Console {
     }
 }
 
+impl Console {
+    fn size(&self) -> TtySize {
+        match self {
+            // nothing to measure, assume a standard terminal
+            Console::PortE9(_) => TtySize { cols: 80, rows: 25 },
+            Console::VgaText(con) => TtySize {
+                cols: con.cols() as u16,
+                rows: con.rows() as u16,
+            },
+        }
+    }
+}
+
 pub fn get() -> MutexGuard<'static, impl Write> {
     CONSOLE.lock()
 }
 
+pub fn size() -> TtySize {
+    CONSOLE.lock().size()
+}
+
 pub(self) fn set(console: Console) {
     *CONSOLE.lock() = console;
 }
 
 pub fn failsafe<'a>(_crit: &'a Critical) -> impl Write + 'a {
-    PortE9
+    Failsafe
+}
+
+struct Failsafe;
+
+impl Write for Failsafe {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::Console.write_str(s)?;
+        PortE9.write_str(s)
+    }
 }
 
 #[macro_export]
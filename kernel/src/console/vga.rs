@@ -131,11 +131,11 @@ impl VgaText {
         }
     }
 
-    fn rows(&self) -> usize {
+    pub(super) fn rows(&self) -> usize {
         self.height / CHAR_HEIGHT
     }
 
-    fn cols(&self) -> usize {
+    pub(super) fn cols(&self) -> usize {
         (self.width - 256) / CHAR_WIDTH
     }
 
@@ -169,6 +169,34 @@ impl VgaText {
         }
     }
 
+    /// Moves the cursor back one cell, wrapping to the previous row, and
+    /// blanks the cell it lands on
+    fn backspace(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.col = self.cols() - 1;
+        } else {
+            return;
+        }
+
+        let pos = self.row * CHAR_HEIGHT * self.pitch
+                + self.col * CHAR_WIDTH * STRIDE;
+
+        unsafe {
+            for y in 0..CHAR_HEIGHT {
+                let line = self.vram.add(pos + y * self.pitch);
+
+                for x in 0..CHAR_WIDTH {
+                    ptr::write_volatile(line.add(x * STRIDE + 0), BLUE);
+                    ptr::write_volatile(line.add(x * STRIDE + 1), GREEN);
+                    ptr::write_volatile(line.add(x * STRIDE + 2), RED);
+                }
+            }
+        }
+    }
+
     fn write_cp437(&mut self, b: u8) {
         let pos = self.row * CHAR_HEIGHT * self.pitch
                 + self.col * CHAR_WIDTH * STRIDE;
@@ -220,6 +248,7 @@ impl Write for VgaText {
         for c in s.chars() {
             match c {
                 '\n' => self.newline(),
+                '\x08' => self.backspace(),
                 c if c.is_ascii() => self.write_cp437(c as u8),
                 _ => self.write_cp437(b'?'),
             }
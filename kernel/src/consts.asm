@@ -16,6 +16,10 @@
 %define EARLY_BIOS_FONT         0x00005000
 %define EARLY_VBE_MODE_INFO     0x00006000
 
+; application processors start in real mode here, see trampoline.asm
+%define AP_TRAMPOLINE           0x00007000
+%define AP_TRAMPOLINE_PARAMS    0x00007f00
+
 %define SEG_KCODE               0x08
 %define SEG_KDATA               0x10
 %define SEG_UCODE               0x1b
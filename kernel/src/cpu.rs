@@ -0,0 +1,205 @@
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use static_assertions::const_assert;
+use x86_64::instructions::tables::{lgdt, load_tss, DescriptorTablePointer};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::PrivilegeLevel;
+
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::MemoryExhausted;
+use crate::sync::Mutex;
+use crate::task::{TaskId, RunQueue};
+
+pub const MAX_CPUS: usize = 16;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+// null, kernel code and data, user code and data, then the two halves of
+// the TSS descriptor. see gdt in start.asm
+const GDT_ENTRIES: usize = 7;
+const GDT_SEGMENT_ENTRIES: usize = 5;
+const SEG_TSS: u16 = 0x28;
+
+const TSS_AVAILABLE: u64 = 0x89;
+
+#[repr(C, packed)]
+struct Tss {
+    _reserved0: u32,
+    rsp0: u64,
+    rsp1: u64,
+    rsp2: u64,
+    _reserved1: u64,
+    ist: [u64; 7],
+    _reserved2: u64,
+    _reserved3: u16,
+    iopb_offset: u16,
+}
+
+/// State private to each CPU, reached through the GS base while in the
+/// kernel. User mode runs with the GS bases swapped, see isrs.asm.
+#[repr(C)]
+pub struct Cpu {
+    // read directly through gs by current() and id(), these must come first
+    this: *const Cpu,
+    id: usize,
+    pub apic_id: u8,
+    pub current_task: Mutex<Option<TaskId>>,
+    pub run_queue: Mutex<RunQueue>,
+    // page waiting to be invalidated on behalf of another CPU, or all of
+    // them, see smp
+    pub tlb_flush: AtomicU64,
+    // halted waiting for work, so wants an IPI when some is queued
+    pub idle: AtomicBool,
+    // monotonic nanoseconds when the user task running here should make
+    // way for others, zero while in the kernel
+    pub slice_end: AtomicU64,
+    // the bootstrap processor keeps the TSS and GDT from start.asm
+    tss: Tss,
+    gdt: [u64; GDT_ENTRIES],
+}
+
+// each Cpu lives in a page of its own
+const_assert!(cpu_fits_page; mem::size_of::<Cpu>() <= PAGE_SIZE);
+
+extern "C" {
+    // zeroed page from start.asm, which GS points at from boot. id() reads
+    // zero from it before init_bsp runs.
+    static mut bsp_cpu: u8;
+    static gdt: [u64; GDT_ENTRIES];
+}
+
+static CPUS: [AtomicPtr<Cpu>; MAX_CPUS] = [
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+];
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+unsafe fn construct(cpu: *mut Cpu, id: usize, apic_id: u8) {
+    ptr::write(cpu, Cpu {
+        this: cpu,
+        id,
+        apic_id,
+        current_task: Mutex::new(None),
+        run_queue: Mutex::new(RunQueue::new()),
+        tlb_flush: AtomicU64::new(0),
+        idle: AtomicBool::new(false),
+        slice_end: AtomicU64::new(0),
+        tss: mem::zeroed(),
+        gdt: [0; GDT_ENTRIES],
+    });
+}
+
+// Safety: must not be called more than once, on the bootstrap processor
+pub unsafe fn init_bsp(apic_id: u8) {
+    let cpu = &mut bsp_cpu as *mut u8 as *mut Cpu;
+    construct(cpu, 0, apic_id);
+    CPUS[0].store(cpu, Ordering::SeqCst);
+}
+
+/// Allocates the per-CPU area for an application processor, which is
+/// brought online by `load` once it's running
+pub fn alloc(id: usize, apic_id: u8) -> Result<&'static Cpu, MemoryExhausted> {
+    assert!(id < MAX_CPUS);
+
+    let page = kvirt::alloc_page::<u8>()?;
+    let cpu = page.as_ptr() as *mut Cpu;
+
+    unsafe {
+        construct(cpu, id, apic_id);
+        Ok(&*cpu)
+    }
+}
+
+/// Points GS at `cpu` and loads its GDT and TSS, with `stack_top` as the
+/// stack for interrupts from user mode. Runs on the application processor.
+pub unsafe fn load(cpu: &'static Cpu, stack_top: *mut u8) {
+    let cpu_ptr = cpu as *const Cpu as *mut Cpu;
+    let cpu_mut = &mut *cpu_ptr;
+
+    cpu_mut.tss.rsp0 = stack_top as u64;
+    cpu_mut.tss.iopb_offset = mem::size_of::<Tss>() as u16;
+
+    cpu_mut.gdt[..GDT_SEGMENT_ENTRIES].copy_from_slice(&gdt[..GDT_SEGMENT_ENTRIES]);
+
+    let base = &cpu_mut.tss as *const Tss as u64;
+    let limit = (mem::size_of::<Tss>() - 1) as u64;
+
+    cpu_mut.gdt[GDT_SEGMENT_ENTRIES] = (limit & 0xffff)
+        | (base & 0xff_ffff) << 16
+        | TSS_AVAILABLE << 40
+        | ((limit >> 16) & 0xf) << 48
+        | ((base >> 24) & 0xff) << 56;
+    cpu_mut.gdt[GDT_SEGMENT_ENTRIES + 1] = base >> 32;
+
+    lgdt(&DescriptorTablePointer {
+        limit: (mem::size_of::<[u64; GDT_ENTRIES]>() - 1) as u16,
+        base: cpu_mut.gdt.as_ptr() as u64,
+    });
+
+    load_tss(SegmentSelector::new(SEG_TSS >> 3, PrivilegeLevel::Ring0));
+
+    set_gs_base(cpu_ptr);
+
+    CPUS[cpu.id].store(cpu_ptr, Ordering::SeqCst);
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Points GS at `cpu`, with the base user mode gets after swapgs cleared
+pub unsafe fn set_gs_base(cpu: *mut Cpu) {
+    Msr::new(IA32_GS_BASE).write(cpu as u64);
+    Msr::new(IA32_KERNEL_GS_BASE).write(0);
+}
+
+/// Index of the CPU we're running on, zero for the bootstrap processor
+pub fn id() -> usize {
+    let id: usize;
+    unsafe { asm!("movq %gs:8, $0" : "=r"(id) ::: "volatile"); }
+    id
+}
+
+pub fn current() -> &'static Cpu {
+    let cpu: *const Cpu;
+
+    unsafe {
+        asm!("movq %gs:0, $0" : "=r"(cpu) ::: "volatile");
+        &*cpu
+    }
+}
+
+/// Number of CPUs online
+pub fn count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+pub fn get(id: usize) -> Option<&'static Cpu> {
+    let cpu = CPUS.get(id)?.load(Ordering::SeqCst);
+
+    if cpu.is_null() {
+        None
+    } else {
+        Some(unsafe { &*cpu })
+    }
+}
+
+/// Iterates over the CPUs online
+pub fn all() -> impl Iterator<Item = &'static Cpu> {
+    (0..MAX_CPUS).filter_map(get)
+}
+
+impl Cpu {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
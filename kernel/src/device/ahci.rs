@@ -0,0 +1,568 @@
+use core::fmt::{self, Debug};
+use core::ptr;
+use core::sync::atomic;
+use core::task::Poll;
+use core::time::Duration;
+
+use arrayvec::{ArrayString, ArrayVec};
+
+use crate::device::block::{self, BlockDevice, Sector, SECTOR_SIZE};
+use crate::device::pci::{self, Command, Match, PciDevice, ProbeError};
+use crate::interrupt::{self, IrqHandler};
+use crate::mem::MemoryExhausted;
+use crate::mem::dma::DmaRegion;
+use crate::mem::kvirt;
+use crate::mem::page::{PageFlags, PAGE_SIZE};
+use crate::mem::phys::RawPhys;
+use crate::sync::{Arc, AsyncMutex};
+use crate::time;
+use crate::util::{self, IrqWakers};
+
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SATA: u8 = 0x06;
+
+const ABAR_INDEX: usize = 5;
+const ABAR_SIZE: usize = 0x1100;
+
+// generic host control registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0c;
+
+const CAP_S64A: u32 = 1 << 31;
+
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+// port registers, relative to the port's register block
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const IS_DHRS: u32 = 1 << 0;
+const IS_TFES: u32 = 1 << 30;
+
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_BSY: u32 = 0x80;
+
+const SSTS_DET_PRESENT: u32 = 0x3;
+const SIG_SATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_IDENTIFY: u8 = 0xec;
+
+// per port DMA layout: the command list, received FIS area and our single
+// command table share the first page, followed by a bounce buffer
+const COMMAND_LIST_OFFSET: usize = 0x000;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x800;
+const PRDT_OFFSET: usize = COMMAND_TABLE_OFFSET + 0x80;
+const BOUNCE_OFFSET: usize = PAGE_SIZE;
+const BOUNCE_PAGES: usize = 16;
+const BOUNCE_SECTORS: usize = BOUNCE_PAGES * PAGE_SIZE / SECTOR_SIZE;
+
+// each PRD entry can describe up to 4 MiB, but we keep them to a page so
+// that the table is trivially bounded by the bounce buffer size
+const PRD_BYTES: usize = PAGE_SIZE;
+
+// how long a port gets to clear a busy bit or answer IDENTIFY before we
+// give up on it
+const PORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "ahci",
+    matches: &[Match::Class { class: CLASS_STORAGE, subclass: SUBCLASS_SATA }],
+    probe,
+};
+
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+#[derive(Debug)]
+pub enum AhciError {
+    MemoryExhausted,
+    OutOfRange,
+    TaskFile(u32),
+    Timeout,
+}
+
+impl From<MemoryExhausted> for AhciError {
+    fn from(_: MemoryExhausted) -> Self {
+        AhciError::MemoryExhausted
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mmio(usize);
+
+impl Mmio {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.0 + offset) as *mut u32, value) }
+    }
+
+    fn offset(&self, offset: usize) -> Mmio {
+        Mmio(self.0 + offset)
+    }
+
+    fn wait_clear(&self, offset: usize, mask: u32) -> Result<(), AhciError> {
+        let deadline = time::monotonic() + PORT_TIMEOUT;
+
+        while (self.read(offset) & mask) != 0 {
+            if time::monotonic() >= deadline {
+                return Err(AhciError::Timeout);
+            }
+
+            atomic::spin_loop_hint();
+        }
+
+        Ok(())
+    }
+}
+
+#[repr(C)]
+struct CommandHeader {
+    flags: u16,
+    prdt_length: u16,
+    prd_byte_count: u32,
+    table_base: u32,
+    table_base_upper: u32,
+    _reserved: [u32; 4],
+}
+
+#[repr(C)]
+struct PrdEntry {
+    data_base: u32,
+    data_base_upper: u32,
+    _reserved: u32,
+    byte_count: u32,
+}
+
+/// Register block of a single port
+#[derive(Debug, Clone, Copy)]
+struct PortRegs {
+    regs: Mmio,
+}
+
+impl PortRegs {
+    fn stop(&self) -> Result<(), AhciError> {
+        let cmd = self.regs.read(PX_CMD);
+        self.regs.write(PX_CMD, cmd & !CMD_ST);
+        self.regs.wait_clear(PX_CMD, CMD_CR)?;
+
+        let cmd = self.regs.read(PX_CMD);
+        self.regs.write(PX_CMD, cmd & !CMD_FRE);
+        self.regs.wait_clear(PX_CMD, CMD_FR)
+    }
+
+    fn start(&self) -> Result<(), AhciError> {
+        self.regs.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ)?;
+
+        let cmd = self.regs.read(PX_CMD);
+        self.regs.write(PX_CMD, cmd | CMD_FRE);
+
+        let cmd = self.regs.read(PX_CMD);
+        self.regs.write(PX_CMD, cmd | CMD_ST);
+
+        Ok(())
+    }
+
+    /// Builds a command in slot 0 and issues it. `byte_len` bytes of the
+    /// bounce buffer are transferred.
+    fn issue(&self, dma: &DmaRegion, command: u8, lba: u64, sector_count: u16, byte_len: usize, write: bool)
+        -> Result<(), AhciError>
+    {
+        let prd_count = (byte_len + PRD_BYTES - 1) / PRD_BYTES;
+
+        unsafe {
+            // FIS length in dwords, plus the write direction bit:
+            let mut flags = 5u16;
+
+            if write {
+                flags |= 1 << 6;
+            }
+
+            ptr::write_volatile(dma.ptr::<CommandHeader>(COMMAND_LIST_OFFSET), CommandHeader {
+                flags,
+                prdt_length: prd_count as u16,
+                prd_byte_count: 0,
+                table_base: dma.phys(COMMAND_TABLE_OFFSET) as u32,
+                table_base_upper: (dma.phys(COMMAND_TABLE_OFFSET) >> 32) as u32,
+                _reserved: [0; 4],
+            });
+
+            let fis = dma.ptr::<u8>(COMMAND_TABLE_OFFSET);
+            ptr::write_bytes(fis, 0, 0x80);
+
+            let lba = lba.to_le_bytes();
+            let count = sector_count.to_le_bytes();
+
+            let fis_bytes = [
+                FIS_TYPE_REG_H2D,
+                0x80, // command, not control
+                command,
+                0,
+                lba[0], lba[1], lba[2],
+                1 << 6, // LBA mode
+                lba[3], lba[4], lba[5],
+                0,
+                count[0], count[1],
+                0,
+                0,
+            ];
+
+            ptr::copy(fis_bytes.as_ptr(), fis, fis_bytes.len());
+
+            for index in 0..prd_count {
+                let offset = index * PRD_BYTES;
+                let len = core::cmp::min(PRD_BYTES, byte_len - offset);
+                let phys = dma.phys(BOUNCE_OFFSET + offset);
+
+                ptr::write_volatile(dma.ptr::<PrdEntry>(PRDT_OFFSET).add(index), PrdEntry {
+                    data_base: phys as u32,
+                    data_base_upper: (phys >> 32) as u32,
+                    _reserved: 0,
+                    byte_count: (len - 1) as u32,
+                });
+            }
+        }
+
+        self.regs.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ)?;
+        self.regs.write(PX_CI, 1);
+
+        Ok(())
+    }
+
+    fn poll_complete(&self) -> Option<Result<(), AhciError>> {
+        let tfd = self.regs.read(PX_TFD);
+
+        if (tfd & TFD_ERR) != 0 {
+            return Some(Err(AhciError::TaskFile(tfd)));
+        }
+
+        if (self.regs.read(PX_CI) & 1) != 0 {
+            return None;
+        }
+
+        Some(Ok(()))
+    }
+}
+
+struct PortShared {
+    port: PortRegs,
+    number: usize,
+    wakers: IrqWakers,
+    dma: AsyncMutex<DmaRegion>,
+    sectors: u64,
+    model: ArrayString<[u8; 40]>,
+}
+
+impl PortShared {
+    async fn command(&self, dma: &DmaRegion, command: u8, lba: u64, sector_count: u16, write: bool)
+        -> Result<(), AhciError>
+    {
+        self.port.issue(dma, command, lba, sector_count, sector_count as usize * SECTOR_SIZE, write)?;
+
+        self.wakers.wait(|| {
+            match self.port.poll_complete() {
+                Some(result) => Poll::Ready(result),
+                None => Poll::Pending,
+            }
+        }).await?
+    }
+}
+
+struct Controller {
+    hba: Mmio,
+    ports: ArrayVec<[Arc<PortShared>; 32]>,
+}
+
+impl IrqHandler for Controller {
+    fn handle_irq(&self) {
+        let pending = self.hba.read(HBA_IS);
+
+        if pending == 0 {
+            return;
+        }
+
+        for port in self.ports.iter() {
+            if (pending & (1 << port.number)) == 0 {
+                continue;
+            }
+
+            // port interrupt status is write one to clear:
+            let status = port.port.regs.read(PX_IS);
+            port.port.regs.write(PX_IS, status);
+
+            port.wakers.wake_all();
+        }
+
+        self.hba.write(HBA_IS, pending);
+    }
+}
+
+pub struct AhciDrive {
+    port: Arc<PortShared>,
+}
+
+impl Debug for AhciDrive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AhciDrive {{ port: {}, model: {:?}, sectors: {} }}",
+            self.port.number, self.port.model, self.port.sectors)
+    }
+}
+
+fn identify(port: &PortRegs, dma: &DmaRegion) -> Result<(ArrayString<[u8; 40]>, u64), AhciError> {
+    port.issue(dma, ATA_IDENTIFY, 0, 0, SECTOR_SIZE, false)?;
+
+    // no task is waiting yet at probe time, so just spin:
+    let deadline = time::monotonic() + PORT_TIMEOUT;
+
+    let result = loop {
+        if let Some(result) = port.poll_complete() {
+            break result;
+        }
+
+        if time::monotonic() >= deadline {
+            break Err(AhciError::Timeout);
+        }
+
+        atomic::spin_loop_hint();
+    };
+
+    result?;
+
+    let mut identify_data = [0u8; SECTOR_SIZE];
+    unsafe { ptr::copy(dma.ptr::<u8>(BOUNCE_OFFSET), identify_data.as_mut_ptr(), SECTOR_SIZE); }
+
+    // ASCII strings in the identify response are big endian
+    for idx in (54..94).step_by(2) {
+        identify_data.swap(idx, idx + 1);
+    }
+
+    let mut model = util::array_string(&identify_data[54..94])
+        .unwrap_or_else(|_| ArrayString::new());
+
+    while let Some(' ') = model.chars().rev().nth(0) {
+        model.pop();
+    }
+
+    // words 100..=103 hold the LBA48 sector count:
+    let mut sectors = 0u64;
+
+    for word in (100..104).rev() {
+        let lo = identify_data[word * 2] as u64;
+        let hi = identify_data[word * 2 + 1] as u64;
+        sectors = (sectors << 16) | (hi << 8) | lo;
+    }
+
+    Ok((model, sectors))
+}
+
+fn init_port(hba: Mmio, number: usize, dma_64bit: bool) -> Result<Option<Arc<PortShared>>, ProbeError> {
+    let regs = hba.offset(PORT_BASE + number * PORT_SIZE);
+
+    if (regs.read(PX_SSTS) & 0xf) != SSTS_DET_PRESENT {
+        return Ok(None);
+    }
+
+    if regs.read(PX_SIG) != SIG_SATA {
+        // ATAPI and port multipliers are not supported here
+        return Ok(None);
+    }
+
+    // without S64A the upper halves of the addresses we give the port are
+    // ignored, so everything has to be in the first 4 GiB:
+    let dma = if dma_64bit {
+        DmaRegion::alloc(1 + BOUNCE_PAGES)?
+    } else {
+        DmaRegion::alloc_32bit(1 + BOUNCE_PAGES)?
+    };
+
+    let port = PortRegs { regs };
+
+    if let Err(e) = port.stop() {
+        crate::warn!("port {} won't stop: {:?}", number, e);
+        return Ok(None);
+    }
+
+    let command_list = dma.phys(COMMAND_LIST_OFFSET);
+    let received_fis = dma.phys(RECEIVED_FIS_OFFSET);
+
+    regs.write(PX_CLB, command_list as u32);
+    regs.write(PX_CLBU, (command_list >> 32) as u32);
+    regs.write(PX_FB, received_fis as u32);
+    regs.write(PX_FBU, (received_fis >> 32) as u32);
+
+    // clear any stale errors and interrupts:
+    regs.write(PX_SERR, 0xffff_ffff);
+    regs.write(PX_IS, 0xffff_ffff);
+
+    if let Err(e) = port.start() {
+        crate::warn!("port {} won't start: {:?}", number, e);
+        return Ok(None);
+    }
+
+    let (model, sectors) = match identify(&port, &dma) {
+        Ok(identity) => identity,
+        Err(e) => {
+            crate::warn!("port {} identify failed: {:?}", number, e);
+            return Ok(None);
+        }
+    };
+
+    crate::info!("port {}: {} ({} sectors)", number, model, sectors);
+
+    regs.write(PX_IE, IS_DHRS | IS_TFES);
+
+    Ok(Some(Arc::new(PortShared {
+        port,
+        number,
+        wakers: IrqWakers::new(),
+        dma: AsyncMutex::new(dma),
+        sectors,
+        model,
+    })?))
+}
+
+fn probe(dev: &PciDevice) -> Result<(), ProbeError> {
+    let abar = dev.bars[ABAR_INDEX]
+        .and_then(|bar| bar.memory_address())
+        .ok_or(ProbeError::Unsupported)?;
+
+    let irq = dev.irq().ok_or(ProbeError::Unsupported)?;
+
+    dev.enable(Command::MEMORY_SPACE | Command::BUS_MASTER);
+
+    let page_count = (ABAR_SIZE + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    let hba = unsafe {
+        kvirt::map_physical(RawPhys(abar), page_count,
+            PageFlags::PRESENT | PageFlags::WRITE | PageFlags::CACHE_DISABLED)?
+    };
+
+    let hba = Mmio(hba.as_ptr() as usize);
+
+    // switch the controller into AHCI mode before touching ports:
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_AE);
+
+    let dma_64bit = (hba.read(HBA_CAP) & CAP_S64A) != 0;
+    let implemented = hba.read(HBA_PI);
+    let mut ports = ArrayVec::new();
+
+    for number in 0..32 {
+        if (implemented & (1 << number)) == 0 {
+            continue;
+        }
+
+        if let Some(port) = init_port(hba, number, dma_64bit)? {
+            ports.push(port);
+        }
+    }
+
+    if ports.is_empty() {
+        return Ok(());
+    }
+
+    let drives = ports.iter()
+        .cloned()
+        .collect::<ArrayVec<[Arc<PortShared>; 32]>>();
+
+    let controller = Arc::new(Controller { hba, ports })?;
+    interrupt::register_irq(irq, controller)?;
+
+    hba.write(HBA_IS, 0xffff_ffff);
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_IE);
+
+    for port in drives {
+        block::register(BlockDevice::Ahci(AhciDrive { port }));
+    }
+
+    Ok(())
+}
+
+impl AhciDrive {
+    pub fn sector_count(&self) -> u64 {
+        self.port.sectors
+    }
+
+    fn check_range(&self, lba: usize, count: usize) -> Result<(), AhciError> {
+        match (lba as u64).checked_add(count as u64) {
+            Some(end) if end <= self.port.sectors => Ok(()),
+            _ => Err(AhciError::OutOfRange),
+        }
+    }
+
+    pub async fn read_sectors(&self, lba: usize, buffs: &mut [&mut Sector]) -> Result<(), AhciError> {
+        self.check_range(lba, buffs.len())?;
+
+        let dma = self.port.dma.lock().await?;
+        let mut lba = lba;
+
+        for chunk in buffs.chunks_mut(BOUNCE_SECTORS) {
+            self.port.command(&dma, ATA_READ_DMA_EXT, lba as u64, chunk.len() as u16, false).await?;
+
+            for (index, buff) in chunk.iter_mut().enumerate() {
+                let bounce = dma.ptr::<u8>(BOUNCE_OFFSET + index * SECTOR_SIZE);
+                unsafe { ptr::copy(bounce, buff.as_mut_ptr(), SECTOR_SIZE); }
+            }
+
+            lba += chunk.len();
+        }
+
+        Ok(())
+    }
+
+    pub async fn write_sectors(&self, lba: usize, buffs: &[&Sector]) -> Result<(), AhciError> {
+        self.check_range(lba, buffs.len())?;
+
+        let dma = self.port.dma.lock().await?;
+        let mut lba = lba;
+
+        for chunk in buffs.chunks(BOUNCE_SECTORS) {
+            for (index, buff) in chunk.iter().enumerate() {
+                let bounce = dma.ptr::<u8>(BOUNCE_OFFSET + index * SECTOR_SIZE);
+                unsafe { ptr::copy(buff.as_ptr(), bounce, SECTOR_SIZE); }
+            }
+
+            self.port.command(&dma, ATA_WRITE_DMA_EXT, lba as u64, chunk.len() as u16, true).await?;
+
+            lba += chunk.len();
+        }
+
+        Ok(())
+    }
+
+    pub async fn flush(&self) -> Result<(), AhciError> {
+        let dma = self.port.dma.lock().await?;
+
+        self.port.command(&dma, ATA_FLUSH_CACHE_EXT, 0, 0, false).await
+    }
+}
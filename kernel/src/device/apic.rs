@@ -0,0 +1,405 @@
+use core::cmp;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+
+use crate::acpi::{self, MadtEntry};
+use crate::critical;
+use crate::device::pit;
+use crate::interrupt::{RegisterIrqError, IRQ_BASE};
+use crate::mem::kvirt;
+use crate::mem::page::{PageFlags, PAGE_SIZE};
+use crate::mem::phys::RawPhys;
+use crate::sync::Mutex;
+
+/// Vector of the local APIC timer, which takes over from the PIT as the
+/// timer interrupt once the APIC is enabled
+pub const TIMER_VECTOR: u8 = 0x40;
+
+/// Raised by the local APIC when the interrupt it was about to deliver went
+/// away. These need no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// the 8259s are moved here before being masked, so the spurious interrupts
+// they still raise land on 0xf7 and 0xff rather than on IRQ vectors
+const PIC1_VECTOR_BASE: u8 = 0xf0;
+const PIC2_VECTOR_BASE: u8 = 0xf8;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 0x800;
+
+// local APIC registers
+const LAPIC_ID: usize = 0x020;
+const LAPIC_TPR: usize = 0x080;
+const LAPIC_EOI: usize = 0x0b0;
+const LAPIC_SVR: usize = 0x0f0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const SVR_ENABLE: u32 = 0x100;
+const LVT_MASKED: u32 = 0x1_0000;
+const TIMER_DIVIDE_16: u32 = 0x3;
+
+const ICR_INIT: u32 = 0x500;
+const ICR_STARTUP: u32 = 0x600;
+const ICR_PENDING: u32 = 0x1000;
+const ICR_ASSERT: u32 = 0x4000;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0xc_0000;
+const ICR_DEST_SHIFT: u32 = 24;
+
+// IO-APIC registers, accessed indirectly through IOREGSEL and IOWIN
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DEST_SHIFT: u64 = 56;
+
+// polarity and trigger mode bits in MADT interrupt override flags
+const OVERRIDE_POLARITY: u16 = 0x3;
+const OVERRIDE_ACTIVE_LOW: u16 = 0x3;
+const OVERRIDE_TRIGGER: u16 = 0xc;
+const OVERRIDE_LEVEL: u16 = 0xc;
+
+// lines below this are ISA IRQs, which default to edge triggered and active
+// high. Lines above are PCI, which are level triggered and active low.
+const ISA_IRQ_COUNT: u8 = 16;
+
+extern "C" {
+    // gates the 8259 spurious interrupt checks in isrs.asm
+    static pic_active: AtomicU8;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mmio(usize);
+
+impl Mmio {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.0 + offset) as *mut u32, value) }
+    }
+}
+
+unsafe fn map_mmio(addr: u64) -> Mmio {
+    let offset = (addr % PAGE_SIZE as u64) as usize;
+
+    let virt = kvirt::map_physical(RawPhys(addr - offset as u64), 1,
+            PageFlags::PRESENT | PageFlags::WRITE | PageFlags::CACHE_DISABLED)
+        .expect("kvirt::map_physical in apic");
+
+    Mmio(virt.as_ptr() as usize + offset)
+}
+
+#[derive(Debug)]
+struct IoApic {
+    regs: Mmio,
+    gsi_base: u32,
+    gsi_count: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        self.regs.write(IOREGSEL, reg);
+        self.regs.read(IOWIN)
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        self.regs.write(IOREGSEL, reg);
+        self.regs.write(IOWIN, value);
+    }
+
+    fn set_redirection(&self, index: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION + index * 2;
+
+        // mask the entry while it's half written:
+        self.write(reg, REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Override {
+    irq: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+#[derive(Debug)]
+struct Routing {
+    io_apics: ArrayVec<[IoApic; 4]>,
+    overrides: ArrayVec<[Override; 16]>,
+    // local APIC of the CPU that receives every IRQ
+    apic_id: u8,
+}
+
+// virtual address of the local APIC registers, zero while interrupts still
+// go through the 8259s
+static LOCAL_APIC: AtomicUsize = AtomicUsize::new(0);
+
+static ROUTING: Mutex<Option<Routing>> = Mutex::new(None);
+
+// rate the timers count down at, assumed the same on every CPU
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Moves the 8259s out of the way of the IRQ vectors and masks every line
+unsafe fn disable_pic() {
+    let mut pic1_command = Port::<u8>::new(PIC1_COMMAND);
+    let mut pic1_data = Port::<u8>::new(PIC1_DATA);
+    let mut pic2_command = Port::<u8>::new(PIC2_COMMAND);
+    let mut pic2_data = Port::<u8>::new(PIC2_DATA);
+
+    // same initialisation sequence as pic_init in isrs.asm:
+    pic1_command.write(0x11);
+    pic2_command.write(0x11);
+    pic1_data.write(PIC1_VECTOR_BASE);
+    pic2_data.write(PIC2_VECTOR_BASE);
+    pic1_data.write(0x04);
+    pic2_data.write(0x02);
+    pic1_data.write(0x01);
+    pic2_data.write(0x01);
+
+    pic1_data.write(0xff);
+    pic2_data.write(0xff);
+
+    pic_active.store(0, Ordering::SeqCst);
+}
+
+/// Counts local APIC timer ticks per second, at divide by 16
+fn calibrate_timer(local: Mmio) -> u64 {
+    local.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    local.write(LAPIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    local.write(LAPIC_TIMER_INITIAL, u32::max_value());
+
+    // the timer counts down, calibrate wants a counter that goes up:
+    let hz = pit::calibrate(|| (u32::max_value() - local.read(LAPIC_TIMER_CURRENT)) as u64);
+
+    local.write(LAPIC_TIMER_INITIAL, 0);
+
+    hz
+}
+
+unsafe fn enable_local(local: Mmio) {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    let base = apic_base.read();
+    apic_base.write(base | APIC_BASE_ENABLE);
+
+    local.write(LAPIC_TPR, 0);
+    local.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+// Safety: must not be called more than once, and only with interrupts
+// disabled, after acpi::init, interrupt::init and pit::init, and before any
+// IRQ handlers are registered
+pub unsafe fn init() {
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => {
+            crate::info!("no MADT, staying on the 8259 PICs");
+            return;
+        }
+    };
+
+    let mut io_apics = ArrayVec::<[IoApic; 4]>::new();
+    let mut overrides = ArrayVec::<[Override; 16]>::new();
+
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic { addr, gsi_base, .. } => {
+                let mut io_apic = IoApic { regs: map_mmio(addr as u64), gsi_base, gsi_count: 0 };
+
+                // the version register holds the index of the last entry:
+                io_apic.gsi_count = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+
+                if io_apics.try_push(io_apic).is_err() {
+                    crate::warn!("too many IO-APICs, ignoring the one at {:#x}", addr);
+                }
+            }
+            MadtEntry::InterruptOverride { irq, gsi, flags } => {
+                if overrides.try_push(Override { irq, gsi, flags }).is_err() {
+                    crate::warn!("too many interrupt overrides, ignoring irq {}", irq);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if io_apics.is_empty() {
+        crate::info!("no IO-APIC, staying on the 8259 PICs");
+        return;
+    }
+
+    for io_apic in io_apics.iter() {
+        for index in 0..io_apic.gsi_count {
+            io_apic.set_redirection(index, REDIRECTION_MASKED);
+        }
+    }
+
+    if madt.has_8259 {
+        disable_pic();
+    }
+
+    let local = map_mmio(madt.local_apic_addr);
+
+    enable_local(local);
+
+    let apic_id = (local.read(LAPIC_ID) >> 24) as u8;
+
+    let timer_hz = calibrate_timer(local);
+    TIMER_HZ.store(timer_hz, Ordering::SeqCst);
+
+    crate::info!("local APIC {} at {:#x}, timer at {} kHz, {} IO-APIC(s) with {} lines",
+        apic_id, madt.local_apic_addr, timer_hz / 1000, io_apics.len(),
+        io_apics.iter().map(|io_apic| io_apic.gsi_count).sum::<u32>());
+
+    *ROUTING.lock() = Some(Routing { io_apics, overrides, apic_id });
+
+    LOCAL_APIC.store(local.0, Ordering::SeqCst);
+}
+
+// Safety: must be called once on each application processor as it starts,
+// with interrupts disabled, after init
+pub unsafe fn init_ap() {
+    let local = Mmio(LOCAL_APIC.load(Ordering::SeqCst));
+
+    enable_local(local);
+    local.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+}
+
+/// Whether interrupts are delivered through the APIC rather than the 8259s
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::SeqCst) != 0
+}
+
+/// Raises TIMER_VECTOR on this CPU once after `delay`, or stops its timer if
+/// `None`. Delays longer than the timer can count fire early.
+pub fn set_timer(delay: Option<Duration>) {
+    let local = Mmio(LOCAL_APIC.load(Ordering::SeqCst));
+
+    let count = match delay {
+        Some(delay) => {
+            let count = delay.as_nanos() * TIMER_HZ.load(Ordering::SeqCst) as u128 / 1_000_000_000;
+
+            // a count of zero would stop the timer instead:
+            cmp::max(cmp::min(count, u32::max_value() as u128), 1) as u32
+        }
+        None => 0,
+    };
+
+    // one shot mode, which is the LVT's default:
+    local.write(LAPIC_LVT_TIMER, TIMER_VECTOR as u32);
+    local.write(LAPIC_TIMER_INITIAL, count);
+}
+
+/// Local APIC ID of the CPU we're running on, zero without an APIC
+pub fn id() -> u8 {
+    match LOCAL_APIC.load(Ordering::SeqCst) {
+        0 => 0,
+        base => (Mmio(base).read(LAPIC_ID) >> 24) as u8,
+    }
+}
+
+fn send_icr(apic_id: u8, command: u32) {
+    let local = Mmio(LOCAL_APIC.load(Ordering::SeqCst));
+
+    critical::section(|| {
+        local.write(LAPIC_ICR_HIGH, (apic_id as u32) << ICR_DEST_SHIFT);
+        // writing the low half sends it:
+        local.write(LAPIC_ICR_LOW, command);
+
+        while (local.read(LAPIC_ICR_LOW) & ICR_PENDING) != 0 {}
+    });
+}
+
+/// Resets an application processor, ready for a startup IPI
+pub fn send_init(apic_id: u8) {
+    send_icr(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Starts an application processor in real mode at `page` * 4 KiB
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_icr(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send_icr(apic_id, ICR_ASSERT | vector as u32);
+}
+
+/// Sends an IPI to every CPU but this one
+pub fn broadcast_ipi(vector: u8) {
+    send_icr(0, ICR_ALL_EXCLUDING_SELF | ICR_ASSERT | vector as u32);
+}
+
+/// Acknowledges the interrupt being handled on this CPU
+pub fn eoi() {
+    let base = LOCAL_APIC.load(Ordering::SeqCst);
+
+    if base != 0 {
+        Mmio(base).write(LAPIC_EOI, 0);
+    }
+}
+
+/// Points the IO-APIC line wired to `irq` at vector IRQ_BASE + irq and
+/// unmasks it. ISA IRQs follow the MADT's overrides.
+pub fn route_irq(irq: u8) -> Result<(), RegisterIrqError> {
+    let routing = ROUTING.lock();
+
+    let routing = routing.as_ref()
+        .ok_or(RegisterIrqError::BadIrq)?;
+
+    let (gsi, flags) = match routing.overrides.iter().find(|o| o.irq == irq) {
+        Some(o) => (o.gsi, o.flags),
+        None => {
+            // lines taken over by another IRQ aren't wired to this one:
+            if routing.overrides.iter().any(|o| o.gsi == irq as u32) {
+                return Err(RegisterIrqError::BadIrq);
+            }
+
+            let flags = if irq < ISA_IRQ_COUNT {
+                0
+            } else {
+                OVERRIDE_ACTIVE_LOW | OVERRIDE_LEVEL
+            };
+
+            (irq as u32, flags)
+        }
+    };
+
+    let io_apic = routing.io_apics.iter()
+        .find(|io_apic| gsi >= io_apic.gsi_base && gsi < io_apic.gsi_base + io_apic.gsi_count)
+        .ok_or(RegisterIrqError::BadIrq)?;
+
+    let mut entry = (IRQ_BASE + irq) as u64
+        | (routing.apic_id as u64) << REDIRECTION_DEST_SHIFT;
+
+    if (flags & OVERRIDE_POLARITY) == OVERRIDE_ACTIVE_LOW {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+
+    if (flags & OVERRIDE_TRIGGER) == OVERRIDE_LEVEL {
+        entry |= REDIRECTION_LEVEL;
+    }
+
+    io_apic.set_redirection(gsi - io_apic.gsi_base, entry);
+
+    Ok(())
+}
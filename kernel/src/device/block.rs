@@ -0,0 +1,95 @@
+use arrayvec::ArrayVec;
+
+use crate::device::ahci::{AhciDrive, AhciError};
+use crate::device::ide::{IdeDrive, IdeError};
+use crate::device::virtio_blk::{VirtioBlk, VirtioBlkError};
+use crate::sync::Mutex;
+
+pub const SECTOR_SIZE: usize = 512;
+
+pub type Sector = [u8; SECTOR_SIZE];
+
+#[derive(Debug)]
+pub enum BlockError {
+    Ide(IdeError),
+    Virtio(VirtioBlkError),
+    Ahci(AhciError),
+}
+
+impl From<IdeError> for BlockError {
+    fn from(e: IdeError) -> Self {
+        BlockError::Ide(e)
+    }
+}
+
+impl From<VirtioBlkError> for BlockError {
+    fn from(e: VirtioBlkError) -> Self {
+        BlockError::Virtio(e)
+    }
+}
+
+impl From<AhciError> for BlockError {
+    fn from(e: AhciError) -> Self {
+        BlockError::Ahci(e)
+    }
+}
+
+/// A disk addressed in 512 byte sectors. This is what partition tables and
+/// filesystems sit on top of.
+#[derive(Debug)]
+pub enum BlockDevice {
+    Ide(IdeDrive),
+    Virtio(VirtioBlk),
+    Ahci(AhciDrive),
+}
+
+impl BlockDevice {
+    pub async fn read_sectors(&self, lba: usize, buffs: &mut [&mut Sector]) -> Result<(), BlockError> {
+        match self {
+            BlockDevice::Ide(drive) => Ok(drive.read_sectors(lba, buffs).await?),
+            BlockDevice::Virtio(drive) => Ok(drive.read_sectors(lba, buffs).await?),
+            BlockDevice::Ahci(drive) => Ok(drive.read_sectors(lba, buffs).await?),
+        }
+    }
+
+    pub async fn write_sectors(&self, lba: usize, buffs: &[&Sector]) -> Result<(), BlockError> {
+        match self {
+            BlockDevice::Ide(drive) => Ok(drive.write_sectors(lba, buffs).await?),
+            BlockDevice::Virtio(drive) => Ok(drive.write_sectors(lba, buffs).await?),
+            BlockDevice::Ahci(drive) => Ok(drive.write_sectors(lba, buffs).await?),
+        }
+    }
+
+    pub async fn flush(&self) -> Result<(), BlockError> {
+        match self {
+            BlockDevice::Ide(drive) => Ok(drive.flush().await?),
+            BlockDevice::Virtio(drive) => Ok(drive.flush().await?),
+            BlockDevice::Ahci(drive) => Ok(drive.flush().await?),
+        }
+    }
+}
+
+// block devices discovered by drivers during boot, waiting to be claimed:
+static DISCOVERED: Mutex<Option<ArrayVec<[BlockDevice; 8]>>> = Mutex::new(None);
+
+pub fn register(device: BlockDevice) {
+    let mut discovered = DISCOVERED.lock();
+
+    let discovered = discovered.get_or_insert_with(ArrayVec::new);
+
+    if let Err(e) = discovered.try_push(device) {
+        crate::warn!("too many devices, ignoring {:?}", e.element());
+    }
+}
+
+/// Takes the first discovered block device, if any
+pub fn take() -> Option<BlockDevice> {
+    let mut discovered = DISCOVERED.lock();
+    let discovered = discovered.as_mut()?;
+
+    if discovered.is_empty() {
+        None
+    } else {
+        Some(discovered.remove(0))
+    }
+}
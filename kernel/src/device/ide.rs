@@ -1,10 +1,55 @@
+use core::ptr;
 use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
 
 use arrayvec::ArrayString;
 use x86_64::instructions::port::Port;
 
-use crate::sync::{Mutex, MutexGuard};
-use crate::util;
+use crate::device::block::{Sector, SECTOR_SIZE};
+use crate::device::pci::{self, Command, Match, PciDevice, ProbeError};
+use crate::interrupt::{self, IrqHandler};
+use crate::mem::MemoryExhausted;
+use crate::mem::dma::DmaRegion;
+use crate::mem::page::PAGE_SIZE;
+use crate::sync::{Arc, AsyncMutex, Mutex, MutexGuard};
+use crate::util::{self, IrqWakers};
+
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_IDE: u8 = 0x01;
+
+// programming interface bits of the IDE controller class code
+const PROG_IF_PRIMARY_NATIVE: u8 = 0x01;
+const PROG_IF_BUS_MASTER: u8 = 0x80;
+
+const BMIDE_BAR_INDEX: usize = 4;
+const PRIMARY_COMPAT_IRQ: u8 = 14;
+
+// bus master register offsets, relative to the channel's BMIDE base
+const BM_COMMAND: u16 = 0;
+const BM_STATUS: u16 = 2;
+const BM_PRDT: u16 = 4;
+
+const BM_COMMAND_START: u8 = 0x01;
+const BM_COMMAND_READ: u8 = 0x08;
+
+// PRD table on the first page, followed by the bounce buffer. PRD entries
+// are kept to a page each so that none of them cross a 64 KiB boundary
+const PRDT_OFFSET: usize = 0;
+const BOUNCE_OFFSET: usize = PAGE_SIZE;
+const BOUNCE_PAGES: usize = 16;
+const BOUNCE_SECTORS: usize = BOUNCE_PAGES * PAGE_SIZE / SECTOR_SIZE;
+
+const PRD_END_OF_TABLE: u16 = 0x8000;
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "ide",
+    matches: &[Match::Class { class: CLASS_STORAGE, subclass: SUBCLASS_IDE }],
+    probe,
+};
+
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Drive {
@@ -21,6 +66,10 @@ pub struct IdeChannel {
     a: AtomicBool,
     b: AtomicBool,
     io: Mutex<IdeIo>,
+    bus_master: Mutex<Option<Arc<BusMaster>>>,
+    // held for the duration of each command, including while waiting on DMA
+    // completion:
+    commands: AsyncMutex<()>,
 }
 
 impl IdeChannel {
@@ -29,9 +78,15 @@ impl IdeChannel {
             a: AtomicBool::new(false),
             b: AtomicBool::new(false),
             io: Mutex::new(io),
+            bus_master: Mutex::new(None),
+            commands: AsyncMutex::new(()),
         }
     }
 
+    fn bus_master(&self) -> Option<Arc<BusMaster>> {
+        self.bus_master.lock().clone()
+    }
+
     fn busyness(&self, drive: Drive) -> &AtomicBool {
         match drive {
             Drive::A => &self.a,
@@ -53,6 +108,11 @@ pub static PRIMARY: IdeChannel = IdeChannel::new(IdeIo {
     control_base: 0x3f6,
 });
 
+pub static SECONDARY: IdeChannel = IdeChannel::new(IdeIo {
+    base: 0x170,
+    control_base: 0x376,
+});
+
 #[derive(Debug)]
 pub struct IdeDrive {
     channel: &'static IdeChannel,
@@ -92,12 +152,58 @@ bitflags::bitflags! {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+bitflags::bitflags! {
+    pub struct BusMasterStatus: u8 {
+        const SIMPLEX_ONLY          = 0x80;
+        const DRIVE_1_DMA_CAPABLE   = 0x40;
+        const DRIVE_0_DMA_CAPABLE   = 0x20;
+        const INTERRUPT             = 0x04;
+        const ERROR                 = 0x02;
+        const ACTIVE                = 0x01;
+    }
+}
+
+#[derive(Debug)]
+pub enum IdeError {
+    Ata(AtaError),
+    BusMaster(BusMasterStatus),
+    MemoryExhausted,
+}
+
+impl From<AtaError> for IdeError {
+    fn from(e: AtaError) -> Self {
+        IdeError::Ata(e)
+    }
+}
+
+impl From<MemoryExhausted> for IdeError {
+    fn from(_: MemoryExhausted) -> Self {
+        IdeError::MemoryExhausted
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AtaCommand {
     ReadPio = 0x20,
+    WritePio = 0x30,
+    ReadDma = 0xc8,
+    WriteDma = 0xca,
+    Packet = 0xa0,
+    IdentifyPacket = 0xa1,
+    FlushCache = 0xe7,
     Identify = 0xec,
 }
 
+// lba1 and lba2 contents left behind by a packet device after reset or an
+// aborted IDENTIFY
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xeb);
+
+const SCSI_READ_10: u8 = 0x28;
+
+pub const ATAPI_SECTOR_SIZE: usize = 2048;
+
+pub type AtapiSector = [u8; ATAPI_SECTOR_SIZE];
+
 #[derive(Debug)]
 struct IdeIo {
     base: u16,
@@ -184,20 +290,177 @@ impl IdeIo {
         })
     }
 
-    fn read_pio_data(&self, buff: &mut Sector) {
-        for i in 0..256 {
+    fn read_pio_data(&self, buff: &mut [u8]) {
+        for i in 0..(buff.len() / 2) {
             let w = unsafe { self.data().read() };
             buff[i * 2 + 0] = ((w >> 0) & 0xff) as u8;
             buff[i * 2 + 1] = ((w >> 8) & 0xff) as u8;
         }
     }
+
+    fn write_pio_data(&self, buff: &[u8]) {
+        for i in 0..(buff.len() / 2) {
+            let w = (buff[i * 2 + 0] as u16) | ((buff[i * 2 + 1] as u16) << 8);
+            unsafe { self.data().write(w); }
+        }
+    }
+
+    fn byte_count(&self) -> usize {
+        unsafe {
+            let lo = self.lba1().read() as usize;
+            let hi = self.lba2().read() as usize;
+            lo | (hi << 8)
+        }
+    }
+}
+
+#[repr(C)]
+struct PrdEntry {
+    phys: u32,
+    byte_count: u16,
+    flags: u16,
+}
+
+/// Bus master DMA engine of a PCI IDE controller channel
+struct BusMaster {
+    base: u16,
+    region: DmaRegion,
+    wakers: IrqWakers,
+}
+
+impl BusMaster {
+    fn command(&self) -> Port<u8> {
+        Port::new(self.base + BM_COMMAND)
+    }
+
+    fn status_port(&self) -> Port<u8> {
+        Port::new(self.base + BM_STATUS)
+    }
+
+    fn prdt(&self) -> Port<u32> {
+        Port::new(self.base + BM_PRDT)
+    }
+
+    fn status(&self) -> BusMasterStatus {
+        BusMasterStatus::from_bits_truncate(unsafe { self.status_port().read() })
+    }
+
+    /// Builds the PRD table covering `byte_len` bytes of the bounce buffer
+    /// and readies the engine, without starting it
+    fn prepare(&self, byte_len: usize, read: bool) {
+        let prd_count = (byte_len + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        for index in 0..prd_count {
+            let offset = index * PAGE_SIZE;
+            let len = core::cmp::min(PAGE_SIZE, byte_len - offset);
+
+            let flags = if index + 1 == prd_count { PRD_END_OF_TABLE } else { 0 };
+
+            unsafe {
+                ptr::write_volatile(self.region.ptr::<PrdEntry>(PRDT_OFFSET).add(index), PrdEntry {
+                    phys: self.region.phys(BOUNCE_OFFSET + offset) as u32,
+                    byte_count: len as u16,
+                    flags,
+                });
+            }
+        }
+
+        unsafe {
+            self.prdt().write(self.region.phys(PRDT_OFFSET) as u32);
+            self.command().write(if read { BM_COMMAND_READ } else { 0 });
+
+            // interrupt and error bits are write one to clear:
+            self.status_port().write((BusMasterStatus::INTERRUPT | BusMasterStatus::ERROR).bits());
+        }
+    }
+
+    fn start(&self, read: bool) {
+        let direction = if read { BM_COMMAND_READ } else { 0 };
+        unsafe { self.command().write(direction | BM_COMMAND_START); }
+    }
+
+    fn stop(&self) {
+        unsafe {
+            let command = self.command().read();
+            self.command().write(command & !BM_COMMAND_START);
+        }
+    }
+
+    async fn wait(&self) -> Result<(), IdeError> {
+        self.wakers.wait(|| {
+            if self.status().contains(BusMasterStatus::INTERRUPT) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }).await?;
+
+        Ok(())
+    }
+}
+
+impl IrqHandler for BusMaster {
+    fn handle_irq(&self) {
+        // the interrupt bit is left for the waiting task to inspect and
+        // clear, it's reset by prepare before every transfer
+        if self.status().contains(BusMasterStatus::INTERRUPT) {
+            self.wakers.wake_all();
+        }
+    }
+}
+
+fn probe(dev: &PciDevice) -> Result<(), ProbeError> {
+    if (dev.prog_if & PROG_IF_BUS_MASTER) == 0 {
+        // no DMA support, the primary channel stays in PIO mode
+        return Err(ProbeError::Unsupported);
+    }
+
+    let base = dev.bars[BMIDE_BAR_INDEX]
+        .and_then(|bar| bar.io_port())
+        .ok_or(ProbeError::Unsupported)?;
+
+    let irq = if (dev.prog_if & PROG_IF_PRIMARY_NATIVE) != 0 {
+        dev.irq().ok_or(ProbeError::Unsupported)?
+    } else {
+        PRIMARY_COMPAT_IRQ
+    };
+
+    dev.enable(Command::IO_SPACE | Command::BUS_MASTER);
+
+    let bus_master = Arc::new(BusMaster {
+        base,
+        // the PRDT and its entries only take 32 bit addresses:
+        region: DmaRegion::alloc_32bit(1 + BOUNCE_PAGES)?,
+        wakers: IrqWakers::new(),
+    })?;
+
+    interrupt::register_irq(irq, bus_master.clone())?;
+
+    crate::info!("primary channel bus master at {:#x}, irq {}", base, irq);
+
+    *PRIMARY.bus_master.lock() = Some(bus_master);
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveKind {
+    Ata,
+    Atapi,
 }
 
 #[derive(Debug)]
 pub struct Detect {
+    kind: DriveKind,
     model: ArrayString<[u8; 40]>,
 }
 
+impl Detect {
+    pub fn kind(&self) -> DriveKind {
+        self.kind
+    }
+}
+
 #[derive(Debug)]
 pub enum DetectError {
     NoDevice,
@@ -205,8 +468,6 @@ pub enum DetectError {
     Ata(AtaError),
 }
 
-pub type Sector = [u8; 512];
-
 impl IdeDrive {
     fn select(&self) -> MutexGuard<IdeIo> {
         let ports = self.channel.io.lock();
@@ -238,18 +499,35 @@ impl IdeDrive {
             // identify
             io.command_status().write(AtaCommand::Identify as u8);
 
-            let status = io.wait_command(AtaStatus::DRIVE_READY)
-                .map_err(DetectError::Ata)?;
-
-            if status.is_empty() {
+            if io.status().is_empty() {
                 // drive does not exist
                 return Err(DetectError::NoDevice);
             }
 
-            // check lba1 and lba2 to make sure this is an ATA device
-            if io.lba1().read() != 0 || io.lba2().read() != 0 {
-                return Err(DetectError::NotAta);
-            }
+            let kind = match io.wait_command(AtaStatus::DRIVE_READY) {
+                Ok(_) => {
+                    // check lba1 and lba2 to make sure this is an ATA device
+                    if io.lba1().read() != 0 || io.lba2().read() != 0 {
+                        return Err(DetectError::NotAta);
+                    }
+
+                    DriveKind::Ata
+                }
+                Err(e) => {
+                    // packet devices abort IDENTIFY, leaving their signature
+                    // behind. they answer IDENTIFY PACKET DEVICE instead:
+                    if (io.lba1().read(), io.lba2().read()) != ATAPI_SIGNATURE {
+                        return Err(DetectError::Ata(e));
+                    }
+
+                    io.command_status().write(AtaCommand::IdentifyPacket as u8);
+
+                    io.wait_command(AtaStatus::DATA_REQUEST_READY)
+                        .map_err(DetectError::Ata)?;
+
+                    DriveKind::Atapi
+                }
+            };
 
             let mut identify_data = [0u8; 512];
             io.read_pio_data(&mut identify_data);
@@ -276,12 +554,13 @@ impl IdeDrive {
             };
 
             Ok(Detect {
+                kind,
                 model,
             })
         }
     }
 
-    pub async fn read_sectors(&self, lba: usize, buffs: &mut [&mut Sector]) -> Result<(), AtaError> {
+    pub async fn read_sectors(&self, lba: usize, buffs: &mut [&mut Sector]) -> Result<(), IdeError> {
         if lba > 0x00fffffe {
             panic!("cannot read lba > 0x00ffffff currently");
         }
@@ -290,6 +569,105 @@ impl IdeDrive {
             panic!("cannot read more than 255 sectors currently");
         }
 
+        let _commands = self.channel.commands.lock().await?;
+
+        let bus_master = match self.channel.bus_master() {
+            Some(bus_master) => bus_master,
+            None => { return Ok(self.read_pio(lba, buffs)?); }
+        };
+
+        let mut lba = lba;
+
+        for chunk in buffs.chunks_mut(BOUNCE_SECTORS) {
+            self.transfer_dma(&bus_master, AtaCommand::ReadDma, lba, chunk.len()).await?;
+
+            for (index, buff) in chunk.iter_mut().enumerate() {
+                let bounce = bus_master.region.ptr::<u8>(BOUNCE_OFFSET + index * SECTOR_SIZE);
+                unsafe { ptr::copy(bounce, buff.as_mut_ptr(), SECTOR_SIZE); }
+            }
+
+            lba += chunk.len();
+        }
+
+        Ok(())
+    }
+
+    pub async fn write_sectors(&self, lba: usize, buffs: &[&Sector]) -> Result<(), IdeError> {
+        if lba > 0x00fffffe {
+            panic!("cannot write lba > 0x00ffffff currently");
+        }
+
+        if buffs.len() > 255 {
+            panic!("cannot write more than 255 sectors currently");
+        }
+
+        let _commands = self.channel.commands.lock().await?;
+
+        let bus_master = match self.channel.bus_master() {
+            Some(bus_master) => bus_master,
+            None => { return Ok(self.write_pio(lba, buffs)?); }
+        };
+
+        let mut lba = lba;
+
+        for chunk in buffs.chunks(BOUNCE_SECTORS) {
+            for (index, buff) in chunk.iter().enumerate() {
+                let bounce = bus_master.region.ptr::<u8>(BOUNCE_OFFSET + index * SECTOR_SIZE);
+                unsafe { ptr::copy(buff.as_ptr(), bounce, SECTOR_SIZE); }
+            }
+
+            self.transfer_dma(&bus_master, AtaCommand::WriteDma, lba, chunk.len()).await?;
+
+            lba += chunk.len();
+        }
+
+        Ok(())
+    }
+
+    /// Transfers `count` sectors between the drive and the start of the bus
+    /// master's bounce buffer
+    async fn transfer_dma(&self, bus_master: &BusMaster, command: AtaCommand, lba: usize, count: usize)
+        -> Result<(), IdeError>
+    {
+        let read = command == AtaCommand::ReadDma;
+        let lba = lba.to_le_bytes();
+
+        bus_master.prepare(count * SECTOR_SIZE, read);
+
+        {
+            let io = self.select();
+            io.wait_command(AtaStatus::empty())?;
+
+            unsafe {
+                io.error_features().write(0);
+                io.seccount0().write(count as u8);
+                io.lba0().write(lba[0]);
+                io.lba1().write(lba[1]);
+                io.lba2().write(lba[2]);
+                io.wait_command(AtaStatus::DRIVE_READY)?;
+                io.command_status().write(command as u8);
+            }
+        }
+
+        bus_master.start(read);
+        let result = bus_master.wait().await;
+        bus_master.stop();
+
+        result?;
+
+        let status = bus_master.status();
+
+        // reading the drive status also acknowledges its interrupt:
+        self.select().wait_command(AtaStatus::empty())?;
+
+        if status.contains(BusMasterStatus::ERROR) {
+            return Err(IdeError::BusMaster(status));
+        }
+
+        Ok(())
+    }
+
+    fn read_pio(&self, lba: usize, buffs: &mut [&mut Sector]) -> Result<(), AtaError> {
         let lba = lba.to_le_bytes();
 
         let io = self.select();
@@ -307,9 +685,114 @@ impl IdeDrive {
 
         for buff in buffs {
             io.wait_command(AtaStatus::DATA_REQUEST_READY)?;
-            io.read_pio_data(buff);
+            io.read_pio_data(&mut buff[..]);
         }
 
         Ok(())
     }
+
+    fn write_pio(&self, lba: usize, buffs: &[&Sector]) -> Result<(), AtaError> {
+        let lba = lba.to_le_bytes();
+
+        let io = self.select();
+        io.wait_command(AtaStatus::empty())?;
+
+        unsafe {
+            io.error_features().write(0);
+            io.seccount0().write(buffs.len() as u8);
+            io.lba0().write(lba[0]);
+            io.lba1().write(lba[1]);
+            io.lba2().write(lba[2]);
+            io.wait_command(AtaStatus::DRIVE_READY)?;
+            io.command_status().write(AtaCommand::WritePio as u8);
+        }
+
+        for buff in buffs {
+            io.wait_command(AtaStatus::DATA_REQUEST_READY)?;
+            io.write_pio_data(&buff[..]);
+        }
+
+        io.wait_command(AtaStatus::empty())?;
+
+        Ok(())
+    }
+
+    pub fn into_atapi(self) -> AtapiDrive {
+        AtapiDrive { drive: self }
+    }
+
+    pub async fn flush(&self) -> Result<(), IdeError> {
+        let _commands = self.channel.commands.lock().await?;
+
+        let io = self.select();
+        io.wait_command(AtaStatus::empty())?;
+
+        unsafe { io.command_status().write(AtaCommand::FlushCache as u8); }
+
+        io.wait_command(AtaStatus::empty())?;
+
+        Ok(())
+    }
+}
+
+/// A packet device, such as a CD-ROM drive, on an IDE channel. Transfers use
+/// PIO and are addressed in 2048 byte sectors.
+#[derive(Debug)]
+pub struct AtapiDrive {
+    drive: IdeDrive,
+}
+
+impl AtapiDrive {
+    pub async fn read_sectors(&self, lba: u32, buffs: &mut [&mut AtapiSector]) -> Result<(), IdeError> {
+        if buffs.len() > 0xffff {
+            panic!("cannot read more than 65535 sectors currently");
+        }
+
+        let _commands = self.drive.channel.commands.lock().await?;
+
+        let lba = lba.to_be_bytes();
+        let count = (buffs.len() as u16).to_be_bytes();
+
+        let packet = [
+            SCSI_READ_10, 0,
+            lba[0], lba[1], lba[2], lba[3],
+            0,
+            count[0], count[1],
+            0, 0, 0,
+        ];
+
+        let byte_limit = (ATAPI_SECTOR_SIZE as u16).to_le_bytes();
+
+        let io = self.drive.select();
+        io.wait_command(AtaStatus::empty())?;
+
+        unsafe {
+            // features = 0 selects PIO, lba1/lba2 hold the maximum number of
+            // bytes the drive may transfer per data request
+            io.error_features().write(0);
+            io.lba1().write(byte_limit[0]);
+            io.lba2().write(byte_limit[1]);
+            io.command_status().write(AtaCommand::Packet as u8);
+        }
+
+        io.wait_command(AtaStatus::DATA_REQUEST_READY)?;
+        io.write_pio_data(&packet);
+
+        for buff in buffs {
+            io.wait_command(AtaStatus::DATA_REQUEST_READY)?;
+
+            let byte_count = io.byte_count();
+
+            if byte_count != ATAPI_SECTOR_SIZE {
+                // we asked for exactly one sector per data request
+                return Err(IdeError::Ata(io.error()));
+            }
+
+            io.read_pio_data(&mut buff[..]);
+        }
+
+        io.wait_command(AtaStatus::empty())?;
+
+        Ok(())
+    }
 }
@@ -1,48 +1,345 @@
 use core::task::Poll;
 
 use arraydeque::{ArrayDeque, Saturating};
-use futures::future;
+use arrayvec::ArrayVec;
+use interface::{KeyCode, KeyEvent, Keymap};
+use interface::{MOD_ALT, MOD_ALT_GR, MOD_CAPS_LOCK, MOD_CTRL, MOD_NUM_LOCK, MOD_SCROLL_LOCK, MOD_SHIFT};
+use interface::{KEY_CAPS_LOCK, KEY_LEFT_ALT, KEY_LEFT_CTRL, KEY_LEFT_SHIFT, KEY_NUM_LOCK,
+    KEY_PAUSE, KEY_RIGHT_ALT, KEY_RIGHT_CTRL, KEY_RIGHT_SHIFT, KEY_SCROLL_LOCK};
 use x86_64::instructions::port::Port;
 
+use crate::device::keymap::{self, Text};
+use crate::interrupt;
+use crate::mem::MemoryExhausted;
 use crate::sync::Mutex;
+use crate::util::IrqWakers;
 
 pub type Scancode = u8;
 
-static BUFF: Mutex<Option<ArrayDeque<[u8; 32], Saturating>>> = Mutex::new(None);
+pub const IRQ: u8 = 1;
 
-// Safety: must not be called more than once
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+const STATUS_INPUT_FULL: u8 = 0x02;
+
+const COMMAND_SET_LEDS: u8 = 0xed;
+
+const LED_SCROLL_LOCK: u8 = 0x01;
+const LED_NUM_LOCK: u8 = 0x02;
+const LED_CAPS_LOCK: u8 = 0x04;
+
+const SCANCODE_EXTENDED: Scancode = 0xe0;
+const SCANCODE_PAUSE: Scancode = 0xe1;
+const SCANCODE_RELEASE: Scancode = 0x80;
+const SCANCODE_ACK: Scancode = 0xfa;
+const SCANCODE_RESEND: Scancode = 0xfe;
+const SCANCODE_ERROR_0: Scancode = 0x00;
+const SCANCODE_ERROR_1: Scancode = 0xff;
+
+// the pause key sends E1 1D 45 E1 9D C5, with no release
+const PAUSE_SEQUENCE_LEN: u8 = 6;
+
+#[derive(Debug)]
+enum Prefix {
+    None,
+    Extended,
+    Pause(u8),
+}
+
+#[derive(Debug)]
+struct Decoder {
+    prefix: Prefix,
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    alt: bool,
+    alt_gr: bool,
+    locks: u8,
+    // LED state waiting on the controller to acknowledge COMMAND_SET_LEDS
+    pending_leds: Option<u8>,
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Decoder {
+            prefix: Prefix::None,
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            alt: false,
+            alt_gr: false,
+            locks: 0,
+            pending_leds: None,
+        }
+    }
+
+    fn modifiers(&self) -> u8 {
+        let mut modifiers = self.locks;
+
+        if self.left_shift || self.right_shift {
+            modifiers |= MOD_SHIFT;
+        }
+
+        if self.left_ctrl || self.right_ctrl {
+            modifiers |= MOD_CTRL;
+        }
+
+        if self.alt {
+            modifiers |= MOD_ALT;
+        }
+
+        if self.alt_gr {
+            modifiers |= MOD_ALT_GR;
+        }
+
+        modifiers
+    }
+
+    fn toggle_lock(&mut self, lock: u8) {
+        self.locks ^= lock;
+
+        let mut leds = 0;
+
+        if (self.locks & MOD_SCROLL_LOCK) != 0 { leds |= LED_SCROLL_LOCK; }
+        if (self.locks & MOD_NUM_LOCK) != 0 { leds |= LED_NUM_LOCK; }
+        if (self.locks & MOD_CAPS_LOCK) != 0 { leds |= LED_CAPS_LOCK; }
+
+        // the LED state itself is sent once the command is acknowledged:
+        send(COMMAND_SET_LEDS);
+        self.pending_leds = Some(leds);
+    }
+
+    fn key(&mut self, keycode: KeyCode, pressed: bool) -> KeyEvent {
+        match keycode {
+            KEY_LEFT_SHIFT => self.left_shift = pressed,
+            KEY_RIGHT_SHIFT => self.right_shift = pressed,
+            KEY_LEFT_CTRL => self.left_ctrl = pressed,
+            KEY_RIGHT_CTRL => self.right_ctrl = pressed,
+            KEY_LEFT_ALT => self.alt = pressed,
+            KEY_RIGHT_ALT => self.alt_gr = pressed,
+            KEY_CAPS_LOCK if pressed => self.toggle_lock(MOD_CAPS_LOCK),
+            KEY_NUM_LOCK if pressed => self.toggle_lock(MOD_NUM_LOCK),
+            KEY_SCROLL_LOCK if pressed => self.toggle_lock(MOD_SCROLL_LOCK),
+            _ => {}
+        }
+
+        KeyEvent {
+            keycode,
+            pressed: pressed as u8,
+            modifiers: self.modifiers(),
+        }
+    }
+
+    /// Feeds one byte from the controller through the decoder, returning a
+    /// key event if it completed one
+    fn decode(&mut self, scancode: Scancode) -> Option<KeyEvent> {
+        match self.prefix {
+            Prefix::Pause(remaining) => {
+                self.prefix = if remaining > 1 { Prefix::Pause(remaining - 1) } else { Prefix::None };
+
+                return if remaining == 1 {
+                    Some(self.key(KEY_PAUSE, true))
+                } else {
+                    None
+                };
+            }
+            Prefix::Extended => {
+                self.prefix = Prefix::None;
+
+                let code = scancode & !SCANCODE_RELEASE;
+
+                // E0 2A and E0 AA are fake shifts sent around some keys:
+                if code == (KEY_LEFT_SHIFT as u8) {
+                    return None;
+                }
+
+                let keycode = ((SCANCODE_EXTENDED as KeyCode) << 8) | code as KeyCode;
+                return Some(self.key(keycode, (scancode & SCANCODE_RELEASE) == 0));
+            }
+            Prefix::None => {}
+        }
+
+        match scancode {
+            SCANCODE_EXTENDED => {
+                self.prefix = Prefix::Extended;
+                None
+            }
+            SCANCODE_PAUSE => {
+                self.prefix = Prefix::Pause(PAUSE_SEQUENCE_LEN - 1);
+                None
+            }
+            SCANCODE_ACK => {
+                if let Some(leds) = self.pending_leds.take() {
+                    send(leds);
+                }
+                None
+            }
+            SCANCODE_RESEND | SCANCODE_ERROR_0 | SCANCODE_ERROR_1 => None,
+            _ => {
+                let code = scancode & !SCANCODE_RELEASE;
+                Some(self.key(code as KeyCode, (scancode & SCANCODE_RELEASE) == 0))
+            }
+        }
+    }
+}
+
+fn send(byte: u8) {
+    unsafe {
+        let mut status = Port::<u8>::new(STATUS_PORT);
+
+        // TODO time out rather than spinning on a wedged controller
+        while (status.read() & STATUS_INPUT_FULL) != 0 {}
+
+        Port::<u8>::new(DATA_PORT).write(byte);
+    }
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+static EVENTS: Mutex<Option<ArrayDeque<[KeyEvent; 64], Saturating>>> = Mutex::new(None);
+static KEYMAP: Mutex<Keymap> = Mutex::new(Keymap::Us);
+static WAKERS: IrqWakers = IrqWakers::new();
+
+// UTF-8 left over from a console read into a buffer that was too short
+static PENDING_TEXT: Mutex<Option<ArrayVec<[u8; 8]>>> = Mutex::new(None);
+
+// Safety: must not be called more than once, and only after interrupt::init
 pub unsafe fn init() {
-    *BUFF.lock() = Some(ArrayDeque::new());
+    *EVENTS.lock() = Some(ArrayDeque::new());
+    *PENDING_TEXT.lock() = Some(ArrayVec::new());
+
+    // the BIOS leaves this line open on the 8259, but not on the IO-APIC.
+    // interrupt() calls straight into this module rather than through a
+    // registered handler, so open it here:
+    interrupt::unmask_irq(IRQ)
+        .expect("interrupt::unmask_irq in keyboard::init");
+}
+
+pub fn set_keymap(keymap: Keymap) {
+    *KEYMAP.lock() = keymap;
+}
+
+fn poll_event() -> Poll<KeyEvent> {
+    let mut events = EVENTS.lock();
+
+    let events = events.as_mut()
+        .expect("keyboard to be initialized");
+
+    match events.pop_front() {
+        None => Poll::Pending,
+        Some(event) => Poll::Ready(event),
+    }
 }
 
-pub async fn read_scancode() -> Scancode {
-    // TODO - send task to sleep while waiting for interrupt
-    future::poll_fn(|_ctx| {
-        let mut buff = BUFF.lock();
+pub async fn read_event() -> Result<KeyEvent, MemoryExhausted> {
+    WAKERS.wait(poll_event).await
+}
+
+/// Reads raw key events into `buf`, waiting for at least one
+pub async fn read_events(buf: &mut [KeyEvent]) -> Result<usize, MemoryExhausted> {
+    if buf.len() == 0 {
+        return Ok(0);
+    }
 
-        let buff = buff.as_mut()
-            .expect("keyboard to be initialized");
+    buf[0] = read_event().await?;
 
-        match buff.pop_front() {
-            None => Poll::Pending,
-            Some(s) => Poll::Ready(s),
+    let mut count = 1;
+
+    while count < buf.len() {
+        match poll_event() {
+            Poll::Ready(event) => { buf[count] = event; count += 1; }
+            Poll::Pending => break,
         }
-    }).await
+    }
+
+    Ok(count)
+}
+
+fn push_text(text: Text, out: &mut ArrayVec<[u8; 8]>) {
+    match text {
+        Text::Char(c) => {
+            let mut utf8 = [0u8; 4];
+            out.extend(c.encode_utf8(&mut utf8).bytes());
+        }
+        Text::Seq(seq) => {
+            out.extend(seq.bytes());
+        }
+    }
+}
+
+/// Reads UTF-8 text typed at the keyboard under the current keymap,
+/// waiting for at least one byte
+pub async fn read_text(buf: &mut [u8]) -> Result<usize, MemoryExhausted> {
+    if buf.len() == 0 {
+        return Ok(0);
+    }
+
+    loop {
+        {
+            let mut pending = PENDING_TEXT.lock();
+            let pending = pending.as_mut().expect("keyboard to be initialized");
+
+            // translate everything available without waiting, leaving room
+            // for the longest text a single key produces:
+            while pending.len() < buf.len() && pending.remaining_capacity() >= 4 {
+                let event = match poll_event() {
+                    Poll::Ready(event) => event,
+                    Poll::Pending => break,
+                };
+
+                if event.pressed == 0 {
+                    continue;
+                }
+
+                let keymap = *KEYMAP.lock();
+
+                if let Some(text) = keymap::translate(keymap, event.keycode, event.modifiers) {
+                    push_text(text, pending);
+                }
+            }
+
+            if pending.len() > 0 {
+                let count = core::cmp::min(pending.len(), buf.len());
+                buf[..count].copy_from_slice(&pending[..count]);
+                pending.drain(..count);
+                return Ok(count);
+            }
+        }
+
+        // nothing translated to text yet, wait for more keys:
+        WAKERS.wait(|| {
+            let events = EVENTS.lock();
+
+            match events.as_ref().map(|events| events.is_empty()) {
+                Some(false) => Poll::Ready(()),
+                _ => Poll::Pending,
+            }
+        }).await?;
+    }
 }
 
 pub unsafe fn interrupt() {
-    let mut keyboard = Port::<u8>::new(0x60);
+    let mut keyboard = Port::<u8>::new(DATA_PORT);
     let raw_scancode = keyboard.read();
 
+    let event = match DECODER.lock().decode(raw_scancode) {
+        Some(event) => event,
+        None => return,
+    };
+
     // TODO - can we do this locklessly?
-    let mut buff = BUFF.lock();
+    let mut events = EVENTS.lock();
 
-    if let Some(ref mut buff) = &mut *buff {
-        match buff.push_back(raw_scancode) {
+    if let Some(ref mut events) = &mut *events {
+        match events.push_back(event) {
             Ok(()) => {}
             Err(_) => {
-                crate::println!("keyboard buffer overflow!");
+                crate::warn!("buffer overflow!");
             }
         }
     }
+
+    WAKERS.wake_all();
 }
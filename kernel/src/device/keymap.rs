@@ -0,0 +1,217 @@
+use interface::{KeyCode, Keymap, MOD_ALT_GR, MOD_CAPS_LOCK, MOD_CTRL, MOD_NUM_LOCK, MOD_SHIFT};
+use interface::{KEY_DELETE, KEY_DOWN, KEY_END, KEY_HOME, KEY_INSERT, KEY_KEYPAD_ENTER,
+    KEY_KEYPAD_SLASH, KEY_LEFT, KEY_PAGE_DOWN, KEY_PAGE_UP, KEY_RIGHT, KEY_UP};
+
+/// Text produced by a single key press
+#[derive(Debug, Clone, Copy)]
+pub enum Text {
+    Char(char),
+    // escape sequences for cursor and editing keys, as a VT100 would send
+    Seq(&'static str),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Key {
+    normal: char,
+    shift: char,
+    alt_gr: Option<char>,
+}
+
+const fn key(normal: char, shift: char) -> Key {
+    Key { normal, shift, alt_gr: None }
+}
+
+const fn key3(normal: char, shift: char, alt_gr: char) -> Key {
+    Key { normal, shift, alt_gr: Some(alt_gr) }
+}
+
+fn us(code: u8) -> Option<Key> {
+    Some(match code {
+        0x01 => key('\x1b', '\x1b'),
+        0x02 => key('1', '!'),
+        0x03 => key('2', '@'),
+        0x04 => key('3', '#'),
+        0x05 => key('4', '$'),
+        0x06 => key('5', '%'),
+        0x07 => key('6', '^'),
+        0x08 => key('7', '&'),
+        0x09 => key('8', '*'),
+        0x0a => key('9', '('),
+        0x0b => key('0', ')'),
+        0x0c => key('-', '_'),
+        0x0d => key('=', '+'),
+        0x0e => key('\x08', '\x08'),
+        0x0f => key('\t', '\t'),
+        0x10 => key('q', 'Q'),
+        0x11 => key('w', 'W'),
+        0x12 => key('e', 'E'),
+        0x13 => key('r', 'R'),
+        0x14 => key('t', 'T'),
+        0x15 => key('y', 'Y'),
+        0x16 => key('u', 'U'),
+        0x17 => key('i', 'I'),
+        0x18 => key('o', 'O'),
+        0x19 => key('p', 'P'),
+        0x1a => key('[', '{'),
+        0x1b => key(']', '}'),
+        0x1c => key('\n', '\n'),
+        0x1e => key('a', 'A'),
+        0x1f => key('s', 'S'),
+        0x20 => key('d', 'D'),
+        0x21 => key('f', 'F'),
+        0x22 => key('g', 'G'),
+        0x23 => key('h', 'H'),
+        0x24 => key('j', 'J'),
+        0x25 => key('k', 'K'),
+        0x26 => key('l', 'L'),
+        0x27 => key(';', ':'),
+        0x28 => key('\'', '"'),
+        0x29 => key('`', '~'),
+        0x2b => key('\\', '|'),
+        0x2c => key('z', 'Z'),
+        0x2d => key('x', 'X'),
+        0x2e => key('c', 'C'),
+        0x2f => key('v', 'V'),
+        0x30 => key('b', 'B'),
+        0x31 => key('n', 'N'),
+        0x32 => key('m', 'M'),
+        0x33 => key(',', '<'),
+        0x34 => key('.', '>'),
+        0x35 => key('/', '?'),
+        0x37 => key('*', '*'),
+        0x39 => key(' ', ' '),
+        0x4a => key('-', '-'),
+        0x4e => key('+', '+'),
+        // the extra key next to left shift on ISO keyboards
+        0x56 => key('\\', '|'),
+        _ => return None,
+    })
+}
+
+fn uk(code: u8) -> Option<Key> {
+    match code {
+        0x03 => Some(key('2', '"')),
+        0x04 => Some(key('3', '£')),
+        0x05 => Some(key3('4', '$', '€')),
+        0x28 => Some(key('\'', '@')),
+        0x29 => Some(key('`', '¬')),
+        0x2b => Some(key('#', '~')),
+        0x56 => Some(key('\\', '|')),
+        _ => us(code),
+    }
+}
+
+fn de(code: u8) -> Option<Key> {
+    match code {
+        0x03 => Some(key3('2', '"', '²')),
+        0x04 => Some(key3('3', '§', '³')),
+        0x07 => Some(key('6', '&')),
+        0x08 => Some(key3('7', '/', '{')),
+        0x09 => Some(key3('8', '(', '[')),
+        0x0a => Some(key3('9', ')', ']')),
+        0x0b => Some(key3('0', '=', '}')),
+        0x0c => Some(key3('ß', '?', '\\')),
+        // dead keys on a real layout, we just produce the accent itself
+        0x0d => Some(key('´', '`')),
+        0x10 => Some(key3('q', 'Q', '@')),
+        0x12 => Some(key3('e', 'E', '€')),
+        0x15 => Some(key('z', 'Z')),
+        0x1a => Some(key('ü', 'Ü')),
+        0x1b => Some(key3('+', '*', '~')),
+        0x27 => Some(key('ö', 'Ö')),
+        0x28 => Some(key('ä', 'Ä')),
+        0x29 => Some(key('^', '°')),
+        0x2b => Some(key('#', '\'')),
+        0x2c => Some(key('y', 'Y')),
+        0x32 => Some(key3('m', 'M', 'µ')),
+        0x33 => Some(key(',', ';')),
+        0x34 => Some(key('.', ':')),
+        0x35 => Some(key('-', '_')),
+        0x56 => Some(key3('<', '>', '|')),
+        _ => us(code),
+    }
+}
+
+/// Keys that don't depend on the layout
+fn fixed(keycode: KeyCode, modifiers: u8) -> Option<Text> {
+    let num_lock = (modifiers & MOD_NUM_LOCK) != 0;
+
+    let keypad = match keycode {
+        0x47 => Some(('7', "\x1b[H")),
+        0x48 => Some(('8', "\x1b[A")),
+        0x49 => Some(('9', "\x1b[5~")),
+        0x4b => Some(('4', "\x1b[D")),
+        0x4c => Some(('5', "")),
+        0x4d => Some(('6', "\x1b[C")),
+        0x4f => Some(('1', "\x1b[F")),
+        0x50 => Some(('2', "\x1b[B")),
+        0x51 => Some(('3', "\x1b[6~")),
+        0x52 => Some(('0', "\x1b[2~")),
+        0x53 => Some(('.', "\x1b[3~")),
+        _ => None,
+    };
+
+    if let Some((digit, seq)) = keypad {
+        return if num_lock {
+            Some(Text::Char(digit))
+        } else if seq.is_empty() {
+            None
+        } else {
+            Some(Text::Seq(seq))
+        };
+    }
+
+    Some(match keycode {
+        KEY_KEYPAD_ENTER => Text::Char('\n'),
+        KEY_KEYPAD_SLASH => Text::Char('/'),
+        KEY_UP => Text::Seq("\x1b[A"),
+        KEY_DOWN => Text::Seq("\x1b[B"),
+        KEY_RIGHT => Text::Seq("\x1b[C"),
+        KEY_LEFT => Text::Seq("\x1b[D"),
+        KEY_HOME => Text::Seq("\x1b[H"),
+        KEY_END => Text::Seq("\x1b[F"),
+        KEY_INSERT => Text::Seq("\x1b[2~"),
+        KEY_DELETE => Text::Seq("\x1b[3~"),
+        KEY_PAGE_UP => Text::Seq("\x1b[5~"),
+        KEY_PAGE_DOWN => Text::Seq("\x1b[6~"),
+        _ => return None,
+    })
+}
+
+/// Translates a key press into text under `keymap`, or None if the key
+/// doesn't produce any (eg. modifiers and function keys)
+pub fn translate(keymap: Keymap, keycode: KeyCode, modifiers: u8) -> Option<Text> {
+    if let Some(text) = fixed(keycode, modifiers) {
+        return Some(text);
+    }
+
+    if keycode > 0xff {
+        return None;
+    }
+
+    let key = match keymap {
+        Keymap::Us => us(keycode as u8),
+        Keymap::Uk => uk(keycode as u8),
+        Keymap::De => de(keycode as u8),
+    }?;
+
+    if (modifiers & MOD_ALT_GR) != 0 {
+        return key.alt_gr.map(Text::Char);
+    }
+
+    let mut shift = (modifiers & MOD_SHIFT) != 0;
+
+    // caps lock only affects letters:
+    if key.normal.is_alphabetic() && (modifiers & MOD_CAPS_LOCK) != 0 {
+        shift = !shift;
+    }
+
+    let c = if shift { key.shift } else { key.normal };
+
+    if (modifiers & MOD_CTRL) != 0 && c.is_ascii_alphabetic() {
+        // ctrl+letter produces the corresponding C0 control character
+        return Some(Text::Char(((c as u8) & 0x1f) as char));
+    }
+
+    Some(Text::Char(c))
+}
@@ -2,20 +2,20 @@ use core::mem;
 
 use arrayvec::ArrayVec;
 
-use crate::device::ide::{IdeDrive, Sector, AtaError};
+use crate::device::block::{BlockDevice, BlockError, Sector};
 use crate::mem::MemoryExhausted;
 use crate::sync::Arc;
 
 pub struct Mbr {
-    drive: Arc<IdeDrive>,
+    drive: Arc<BlockDevice>,
 }
 
 impl Mbr {
-    pub fn open(drive: IdeDrive) -> Result<Self, MemoryExhausted> {
+    pub fn open(drive: BlockDevice) -> Result<Self, MemoryExhausted> {
         Ok(Mbr { drive: Arc::new(drive)? })
     }
 
-    pub async fn partitions(&self) -> Result<ArrayVec<[Option<Partition>; 4]>, AtaError> {
+    pub async fn partitions(&self) -> Result<ArrayVec<[Option<Partition>; 4]>, BlockError> {
         #[repr(packed)]
         struct RawMbr {
             pad: [u8; 0x1be],
@@ -27,12 +27,12 @@ impl Mbr {
 
         let mbr = unsafe { mem::transmute::<&[u8; 512], &RawMbr>(&boot_sector) };
 
-        crate::println!("{:x?}", &mbr.pad[432..440]);
+        crate::debug!("disk signature {:x?}", &mbr.pad[432..440]);
 
         let mut parts = [None, None, None, None];
 
         for (idx, part) in mbr.entries.iter().enumerate() {
-            crate::println!("{:?}", part);
+            crate::debug!("{:?}", part);
             if (part.status & 0x80) != 0 {
                 parts[idx] = Some(Partition {
                     drive: self.drive.clone(),
@@ -60,7 +60,7 @@ struct MbrEntry {
 
 #[derive(Debug)]
 pub struct Partition {
-    drive: Arc<IdeDrive>,
+    drive: Arc<BlockDevice>,
     pub number: usize,
     pub lba: usize,
     pub sectors: usize,
@@ -68,7 +68,7 @@ pub struct Partition {
 
 impl Partition {
     pub async fn read_sectors(&self, lba: usize, buffs: &mut [&mut Sector])
-        -> Result<(), AtaError>
+        -> Result<(), BlockError>
     {
         if lba + buffs.len() > self.sectors {
             panic!("would read beyond partition");
@@ -76,4 +76,18 @@ impl Partition {
 
         self.drive.read_sectors(lba + self.lba, buffs).await
     }
+
+    pub async fn write_sectors(&self, lba: usize, buffs: &[&Sector])
+        -> Result<(), BlockError>
+    {
+        if lba + buffs.len() > self.sectors {
+            panic!("would write beyond partition");
+        }
+
+        self.drive.write_sectors(lba + self.lba, buffs).await
+    }
+
+    pub async fn flush(&self) -> Result<(), BlockError> {
+        self.drive.flush().await
+    }
 }
@@ -1,4 +1,14 @@
+pub mod ahci;
+pub mod apic;
+pub mod block;
 pub mod ide;
 pub mod keyboard;
+pub mod keymap;
 pub mod mbr;
+pub mod mouse;
+pub mod pci;
 pub mod pit;
+pub mod rtc;
+pub mod serial;
+pub mod virtio;
+pub mod virtio_blk;
@@ -0,0 +1,292 @@
+use core::task::Poll;
+
+use arraydeque::{ArrayDeque, Saturating};
+use interface::MouseEvent;
+use x86_64::instructions::port::Port;
+
+use crate::critical;
+use crate::interrupt::{self, IrqHandler};
+use crate::mem::MemoryExhausted;
+use crate::sync::{Arc, Mutex};
+use crate::util::IrqWakers;
+
+const IRQ: u8 = 12;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_INPUT_FULL: u8 = 0x02;
+// the byte waiting in the output buffer came from the auxiliary port
+const STATUS_AUX_DATA: u8 = 0x20;
+
+// 8042 controller commands
+const CONTROLLER_READ_CONFIG: u8 = 0x20;
+const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+const CONTROLLER_ENABLE_AUX: u8 = 0xa8;
+const CONTROLLER_WRITE_AUX: u8 = 0xd4;
+
+const CONFIG_AUX_INTERRUPT: u8 = 0x02;
+const CONFIG_AUX_CLOCK_DISABLE: u8 = 0x20;
+
+// mouse commands
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xf3;
+const MOUSE_GET_ID: u8 = 0xf2;
+
+const MOUSE_ACK: u8 = 0xfa;
+
+const MOUSE_ID_INTELLIMOUSE: u8 = 0x03;
+
+// bits in the first byte of each packet
+const PACKET_BUTTONS: u8 = 0x07;
+const PACKET_ALWAYS_SET: u8 = 0x08;
+const PACKET_X_SIGN: u8 = 0x10;
+const PACKET_Y_SIGN: u8 = 0x20;
+const PACKET_X_OVERFLOW: u8 = 0x40;
+const PACKET_Y_OVERFLOW: u8 = 0x80;
+
+// bounds waits on the controller so a missing mouse can't hang boot
+const SPIN_LIMIT: usize = 100_000;
+
+#[derive(Debug)]
+pub struct NoDevice;
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS_PORT).read() }
+}
+
+fn wait_write() -> Result<(), NoDevice> {
+    for _ in 0..SPIN_LIMIT {
+        if (status() & STATUS_INPUT_FULL) == 0 {
+            return Ok(());
+        }
+    }
+
+    Err(NoDevice)
+}
+
+fn wait_read() -> Result<u8, NoDevice> {
+    for _ in 0..SPIN_LIMIT {
+        if (status() & STATUS_OUTPUT_FULL) != 0 {
+            return Ok(unsafe { Port::<u8>::new(DATA_PORT).read() });
+        }
+    }
+
+    Err(NoDevice)
+}
+
+fn controller_command(command: u8) -> Result<(), NoDevice> {
+    wait_write()?;
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(command); }
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), NoDevice> {
+    wait_write()?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte); }
+    Ok(())
+}
+
+/// Sends a byte to the mouse through the auxiliary port and waits for it to
+/// be acknowledged
+fn mouse_write(byte: u8) -> Result<(), NoDevice> {
+    controller_command(CONTROLLER_WRITE_AUX)?;
+    write_data(byte)?;
+
+    match wait_read()? {
+        MOUSE_ACK => Ok(()),
+        _ => Err(NoDevice),
+    }
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), NoDevice> {
+    mouse_write(MOUSE_SET_SAMPLE_RATE)?;
+    mouse_write(rate)
+}
+
+/// Brings up the auxiliary port and mouse, returning whether it sends
+/// IntelliMouse wheel packets
+fn enable() -> Result<bool, NoDevice> {
+    controller_command(CONTROLLER_ENABLE_AUX)?;
+
+    controller_command(CONTROLLER_READ_CONFIG)?;
+    let config = wait_read()?;
+
+    mouse_write(MOUSE_SET_DEFAULTS)?;
+
+    // this magic sequence of sample rates switches IntelliMouse compatible
+    // devices into 4 byte packets with a wheel:
+    set_sample_rate(200)?;
+    set_sample_rate(100)?;
+    set_sample_rate(80)?;
+
+    mouse_write(MOUSE_GET_ID)?;
+    let wheel = wait_read()? == MOUSE_ID_INTELLIMOUSE;
+
+    mouse_write(MOUSE_ENABLE_REPORTING)?;
+
+    // only now let the mouse raise interrupts, the replies above were read
+    // by polling:
+    controller_command(CONTROLLER_WRITE_CONFIG)?;
+    write_data((config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLE)?;
+
+    Ok(wheel)
+}
+
+#[derive(Debug)]
+struct Decoder {
+    packet: [u8; 4],
+    len: usize,
+    packet_len: usize,
+}
+
+impl Decoder {
+    fn decode(&mut self, byte: u8) -> Option<MouseEvent> {
+        // the first byte always has this bit set, which lets us find our
+        // way back to a packet boundary after losing a byte:
+        if self.len == 0 && (byte & PACKET_ALWAYS_SET) == 0 {
+            return None;
+        }
+
+        self.packet[self.len] = byte;
+        self.len += 1;
+
+        if self.len < self.packet_len {
+            return None;
+        }
+
+        self.len = 0;
+
+        let flags = self.packet[0];
+
+        if (flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW)) != 0 {
+            // motion is meaningless, but button changes still count:
+            return Some(MouseEvent {
+                buttons: buttons(flags),
+                ..MouseEvent::default()
+            });
+        }
+
+        // 9 bit two's complement, with the sign bit in the flags byte:
+        let dx = self.packet[1] as i16 - if (flags & PACKET_X_SIGN) != 0 { 0x100 } else { 0 };
+        let dy = self.packet[2] as i16 - if (flags & PACKET_Y_SIGN) != 0 { 0x100 } else { 0 };
+
+        let wheel = if self.packet_len == 4 {
+            // 4 bit two's complement:
+            ((self.packet[3] << 4) as i8) >> 4
+        } else {
+            0
+        };
+
+        Some(MouseEvent {
+            dx,
+            // the mouse reports y up, we report screen coordinates
+            dy: -dy,
+            wheel,
+            buttons: buttons(flags),
+            _reserved: 0,
+        })
+    }
+}
+
+fn buttons(flags: u8) -> u8 {
+    // the packet's button bits are laid out as MOUSE_LEFT, RIGHT and MIDDLE
+    flags & PACKET_BUTTONS
+}
+
+static DECODER: Mutex<Option<Decoder>> = Mutex::new(None);
+static EVENTS: Mutex<Option<ArrayDeque<[MouseEvent; 64], Saturating>>> = Mutex::new(None);
+static WAKERS: IrqWakers = IrqWakers::new();
+
+struct MouseIrq;
+
+impl IrqHandler for MouseIrq {
+    fn handle_irq(&self) {
+        let status = status();
+
+        if (status & STATUS_OUTPUT_FULL) == 0 || (status & STATUS_AUX_DATA) == 0 {
+            return;
+        }
+
+        let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+
+        let event = match DECODER.lock().as_mut().and_then(|decoder| decoder.decode(byte)) {
+            Some(event) => event,
+            None => return,
+        };
+
+        if let Some(events) = EVENTS.lock().as_mut() {
+            if events.push_back(event).is_err() {
+                crate::warn!("buffer overflow!");
+            }
+        }
+
+        WAKERS.wake_all();
+    }
+}
+
+// Safety: must not be called more than once, and only after interrupt::init
+pub unsafe fn init() {
+    let wheel = match critical::section(enable) {
+        Ok(wheel) => wheel,
+        Err(NoDevice) => {
+            crate::info!("no PS/2 mouse found");
+            return;
+        }
+    };
+
+    crate::info!("PS/2 mouse enabled{}", if wheel { " with wheel" } else { "" });
+
+    *DECODER.lock() = Some(Decoder {
+        packet: [0; 4],
+        len: 0,
+        packet_len: if wheel { 4 } else { 3 },
+    });
+
+    *EVENTS.lock() = Some(ArrayDeque::new());
+
+    let handler = Arc::new(MouseIrq)
+        .expect("Arc::new in mouse::init");
+
+    interrupt::register_irq(IRQ, handler)
+        .expect("interrupt::register_irq in mouse::init");
+}
+
+pub fn is_present() -> bool {
+    EVENTS.lock().is_some()
+}
+
+fn poll_event() -> Poll<MouseEvent> {
+    let mut events = EVENTS.lock();
+
+    let events = events.as_mut()
+        .expect("mouse to be initialized");
+
+    match events.pop_front() {
+        None => Poll::Pending,
+        Some(event) => Poll::Ready(event),
+    }
+}
+
+/// Reads mouse events into `buf`, waiting for at least one
+pub async fn read_events(buf: &mut [MouseEvent]) -> Result<usize, MemoryExhausted> {
+    if buf.len() == 0 {
+        return Ok(0);
+    }
+
+    buf[0] = WAKERS.wait(poll_event).await?;
+
+    let mut count = 1;
+
+    while count < buf.len() {
+        match poll_event() {
+            Poll::Ready(event) => { buf[count] = event; count += 1; }
+            Poll::Pending => break,
+        }
+    }
+
+    Ok(count)
+}
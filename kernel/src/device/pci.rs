@@ -0,0 +1,599 @@
+use core::fmt::{self, Display};
+use core::ops::Bound;
+use core::ptr;
+
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::ArrayVec;
+use interface::PciDeviceInfo;
+use x86_64::instructions::port::Port;
+
+use crate::acpi;
+use crate::interrupt::{self, RegisterIrqError};
+use crate::mem::MemoryExhausted;
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::kvirt;
+use crate::mem::page::{PageFlags, PAGE_SIZE};
+use crate::mem::phys::RawPhys;
+use crate::sync::Mutex;
+use crate::util::EarlyInit;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const REG_VENDOR_ID: u16 = 0x00;
+const REG_COMMAND: u16 = 0x04;
+const REG_STATUS: u16 = 0x06;
+const REG_CLASS: u16 = 0x08;
+const REG_HEADER_TYPE: u16 = 0x0e;
+const REG_BAR0: u16 = 0x10;
+const REG_SECONDARY_BUS: u16 = 0x19;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT_LINE: u16 = 0x3c;
+const REG_INTERRUPT_PIN: u16 = 0x3d;
+
+const STATUS_CAPABILITIES: u16 = 0x0010;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+const NO_DEVICE: u16 = 0xffff;
+const MAX_CAPABILITIES: usize = 16;
+
+bitflags::bitflags! {
+    pub struct Command: u16 {
+        const IO_SPACE          = 0x0001;
+        const MEMORY_SPACE      = 0x0002;
+        const BUS_MASTER        = 0x0004;
+        const INTERRUPT_DISABLE = 0x0400;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+struct Ecam {
+    base: u64,
+    start_bus: u8,
+    end_bus: u8,
+    // lazily mapped 1 MiB windows, one per bus:
+    buses: Mutex<[Option<usize>; 256]>,
+}
+
+impl Ecam {
+    fn config_ptr(&self, addr: PciAddress, offset: u16) -> Option<*mut u32> {
+        if addr.bus < self.start_bus || addr.bus > self.end_bus {
+            return None;
+        }
+
+        const BUS_SIZE: usize = 1 << 20;
+
+        let mut buses = self.buses.lock();
+
+        let bus_base = match buses[addr.bus as usize] {
+            Some(base) => base,
+            None => {
+                let phys = self.base + ((addr.bus - self.start_bus) as u64) * BUS_SIZE as u64;
+
+                let virt = unsafe {
+                    kvirt::map_physical(RawPhys(phys), BUS_SIZE / PAGE_SIZE,
+                        PageFlags::PRESENT | PageFlags::WRITE | PageFlags::CACHE_DISABLED)
+                }.expect("kvirt::map_physical in pci::Ecam");
+
+                let base = virt.as_ptr() as usize;
+                buses[addr.bus as usize] = Some(base);
+                base
+            }
+        };
+
+        let offset = ((addr.device as usize) << 15)
+            | ((addr.function as usize) << 12)
+            | (offset as usize & 0xffc);
+
+        Some((bus_base + offset) as *mut u32)
+    }
+}
+
+enum ConfigSpace {
+    Legacy,
+    Ecam(Ecam),
+}
+
+static CONFIG_SPACE: EarlyInit<ConfigSpace> = EarlyInit::new();
+static LEGACY_PORTS: Mutex<()> = Mutex::new(());
+
+fn legacy_address(addr: PciAddress, offset: u16) -> u32 {
+    0x8000_0000
+        | ((addr.bus as u32) << 16)
+        | ((addr.device as u32) << 11)
+        | ((addr.function as u32) << 8)
+        | (offset as u32 & 0xfc)
+}
+
+pub fn read_u32(addr: PciAddress, offset: u16) -> u32 {
+    if let ConfigSpace::Ecam(ecam) = &*CONFIG_SPACE {
+        if let Some(ptr) = ecam.config_ptr(addr, offset) {
+            return unsafe { ptr::read_volatile(ptr) };
+        }
+    }
+
+    let _lock = LEGACY_PORTS.lock();
+
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(addr, offset));
+        Port::<u32>::new(CONFIG_DATA).read()
+    }
+}
+
+pub fn write_u32(addr: PciAddress, offset: u16, value: u32) {
+    if let ConfigSpace::Ecam(ecam) = &*CONFIG_SPACE {
+        if let Some(ptr) = ecam.config_ptr(addr, offset) {
+            unsafe { ptr::write_volatile(ptr, value); }
+            return;
+        }
+    }
+
+    let _lock = LEGACY_PORTS.lock();
+
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(addr, offset));
+        Port::<u32>::new(CONFIG_DATA).write(value);
+    }
+}
+
+pub fn read_u16(addr: PciAddress, offset: u16) -> u16 {
+    let shift = (offset & 2) * 8;
+    ((read_u32(addr, offset) >> shift) & 0xffff) as u16
+}
+
+pub fn read_u8(addr: PciAddress, offset: u16) -> u8 {
+    let shift = (offset & 3) * 8;
+    ((read_u32(addr, offset) >> shift) & 0xff) as u8
+}
+
+pub fn write_u16(addr: PciAddress, offset: u16, value: u16) {
+    let shift = (offset & 2) * 8;
+    let dword = read_u32(addr, offset) & !(0xffff << shift);
+    write_u32(addr, offset, dword | ((value as u32) << shift));
+}
+
+pub fn command(addr: PciAddress) -> Command {
+    Command::from_bits_truncate(read_u16(addr, REG_COMMAND))
+}
+
+pub fn set_command(addr: PciAddress, command: Command) {
+    // preserve any command bits we don't know about:
+    let raw = read_u16(addr, REG_COMMAND) & !Command::all().bits();
+    write_u16(addr, REG_COMMAND, raw | command.bits());
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Bar {
+    Io {
+        port: u16,
+        size: u32,
+    },
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+}
+
+impl Bar {
+    pub fn io_port(&self) -> Option<u16> {
+        match self {
+            Bar::Io { port, .. } => Some(*port),
+            Bar::Memory { .. } => None,
+        }
+    }
+
+    pub fn memory_address(&self) -> Option<u64> {
+        match self {
+            Bar::Io { .. } => None,
+            Bar::Memory { address, .. } => Some(*address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub addr: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: ArrayVec<[Capability; MAX_CAPABILITIES]>,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub driver: Option<&'static str>,
+}
+
+impl PciDevice {
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().find(|cap| cap.id == id).cloned()
+    }
+
+    /// Returns the legacy interrupt line routed to this device, if any
+    pub fn irq(&self) -> Option<u8> {
+        if self.interrupt_pin == 0 || self.interrupt_line as usize >= interrupt::IRQ_COUNT {
+            None
+        } else {
+            Some(self.interrupt_line)
+        }
+    }
+
+    pub fn enable(&self, flags: Command) {
+        set_command(self.addr, command(self.addr) | flags);
+    }
+
+    fn info(&self) -> PciDeviceInfo {
+        PciDeviceInfo {
+            vendor_id: self.vendor_id,
+            device_id: self.device_id,
+            bus: self.addr.bus,
+            device: self.addr.device,
+            function: self.addr.function,
+            class: self.class,
+            subclass: self.subclass,
+            prog_if: self.prog_if,
+            revision: self.revision,
+            interrupt_line: self.interrupt_line,
+            interrupt_pin: self.interrupt_pin,
+            _reserved: 0,
+        }
+    }
+}
+
+fn decode_bars(addr: PciAddress, header_type: u8) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+
+    let bar_count = match header_type & HEADER_TYPE_MASK {
+        0 => 6,
+        HEADER_TYPE_BRIDGE => 2,
+        _ => 0,
+    };
+
+    // disable decoding while we size the BARs:
+    let command = read_u16(addr, REG_COMMAND);
+    write_u16(addr, REG_COMMAND, command & !(Command::IO_SPACE | Command::MEMORY_SPACE).bits());
+
+    let mut index = 0;
+
+    while index < bar_count {
+        let offset = REG_BAR0 + index as u16 * 4;
+        let raw = read_u32(addr, offset);
+
+        write_u32(addr, offset, 0xffff_ffff);
+        let mask = read_u32(addr, offset);
+        write_u32(addr, offset, raw);
+
+        if mask == 0 {
+            // unimplemented BAR
+            index += 1;
+            continue;
+        }
+
+        if (raw & 0x1) != 0 {
+            let size = (!(mask & !0x3)).wrapping_add(1) & 0xffff;
+
+            bars[index] = Some(Bar::Io {
+                port: (raw & !0x3) as u16,
+                size,
+            });
+
+            index += 1;
+            continue;
+        }
+
+        let prefetchable = (raw & 0x8) != 0;
+
+        match (raw >> 1) & 0x3 {
+            0x2 if index + 1 < bar_count => {
+                let offset_hi = offset + 4;
+                let raw_hi = read_u32(addr, offset_hi);
+
+                write_u32(addr, offset_hi, 0xffff_ffff);
+                let mask_hi = read_u32(addr, offset_hi);
+                write_u32(addr, offset_hi, raw_hi);
+
+                let mask = ((mask_hi as u64) << 32) | (mask & !0xf) as u64;
+
+                bars[index] = Some(Bar::Memory {
+                    address: ((raw_hi as u64) << 32) | (raw & !0xf) as u64,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable,
+                    is_64bit: true,
+                });
+
+                // 64 bit BARs consume the following BAR slot:
+                index += 2;
+            }
+            _ => {
+                bars[index] = Some(Bar::Memory {
+                    address: (raw & !0xf) as u64,
+                    size: (!(mask & !0xf)).wrapping_add(1) as u64,
+                    prefetchable,
+                    is_64bit: false,
+                });
+
+                index += 1;
+            }
+        }
+    }
+
+    write_u16(addr, REG_COMMAND, command);
+
+    bars
+}
+
+fn read_capabilities(addr: PciAddress) -> ArrayVec<[Capability; MAX_CAPABILITIES]> {
+    let mut caps = ArrayVec::new();
+
+    if (read_u16(addr, REG_STATUS) & STATUS_CAPABILITIES) == 0 {
+        return caps;
+    }
+
+    let mut offset = read_u8(addr, REG_CAPABILITIES) & 0xfc;
+
+    // bound the walk so a malformed list can't loop forever:
+    while offset != 0 && !caps.is_full() {
+        let id = read_u8(addr, offset as u16);
+        let next = read_u8(addr, offset as u16 + 1) & 0xfc;
+
+        caps.push(Capability { id, offset });
+        offset = next;
+    }
+
+    caps
+}
+
+fn read_device(addr: PciAddress) -> Option<PciDevice> {
+    let vendor_id = read_u16(addr, REG_VENDOR_ID);
+
+    if vendor_id == NO_DEVICE {
+        return None;
+    }
+
+    let class_reg = read_u32(addr, REG_CLASS);
+    let header_type = read_u8(addr, REG_HEADER_TYPE);
+
+    Some(PciDevice {
+        addr,
+        vendor_id,
+        device_id: read_u16(addr, REG_VENDOR_ID + 2),
+        class: (class_reg >> 24) as u8,
+        subclass: (class_reg >> 16) as u8,
+        prog_if: (class_reg >> 8) as u8,
+        revision: class_reg as u8,
+        header_type,
+        bars: decode_bars(addr, header_type),
+        capabilities: read_capabilities(addr),
+        interrupt_line: read_u8(addr, REG_INTERRUPT_LINE),
+        interrupt_pin: read_u8(addr, REG_INTERRUPT_PIN),
+        driver: None,
+    })
+}
+
+type DeviceMap = BTreeMap<PciAddress, PciDevice, GlobalAlloc>;
+
+static DEVICES: EarlyInit<Mutex<DeviceMap>> = EarlyInit::new();
+
+fn scan_bus(bus: u8, devices: &mut DeviceMap) {
+    for device in 0..32 {
+        let addr = PciAddress { bus, device, function: 0 };
+
+        if read_u16(addr, REG_VENDOR_ID) == NO_DEVICE {
+            continue;
+        }
+
+        let function_count = if (read_u8(addr, REG_HEADER_TYPE) & HEADER_MULTIFUNCTION) != 0 {
+            8
+        } else {
+            1
+        };
+
+        for function in 0..function_count {
+            let addr = PciAddress { bus, device, function };
+
+            let dev = match read_device(addr) {
+                Some(dev) => dev,
+                None => continue,
+            };
+
+            let is_bridge = dev.class == CLASS_BRIDGE && dev.subclass == SUBCLASS_PCI_BRIDGE;
+
+            crate::info!("{} {:04x}:{:04x} class {:02x}:{:02x}:{:02x} irq {}",
+                addr, dev.vendor_id, dev.device_id, dev.class, dev.subclass, dev.prog_if,
+                dev.interrupt_line);
+
+            devices.insert(addr, dev)
+                .expect("devices.insert in pci::scan_bus");
+
+            if is_bridge {
+                let secondary = read_u8(addr, REG_SECONDARY_BUS);
+
+                // never rescan the same or an earlier bus:
+                if secondary > bus {
+                    scan_bus(secondary, devices);
+                }
+            }
+        }
+    }
+}
+
+fn find_ecam() -> Option<Ecam> {
+    let mcfg = acpi::find_table(b"MCFG")?;
+
+    // MCFG has 8 reserved bytes before the allocation entries, each of which
+    // is 16 bytes long:
+    for entry in mcfg.data().get(8..)?.chunks_exact(16) {
+        let base = u64::from_le_bytes([
+            entry[0], entry[1], entry[2], entry[3],
+            entry[4], entry[5], entry[6], entry[7],
+        ]);
+
+        let segment = u16::from_le_bytes([entry[8], entry[9]]);
+
+        // we only support PCI segment group 0:
+        if segment != 0 {
+            continue;
+        }
+
+        return Some(Ecam {
+            base,
+            start_bus: entry[10],
+            end_bus: entry[11],
+            buses: Mutex::new([None; 256]),
+        });
+    }
+
+    None
+}
+
+// Safety: must not be called more than once, and only after acpi::init
+pub unsafe fn init() {
+    let config_space = match find_ecam() {
+        Some(ecam) => {
+            crate::info!("using ECAM at {:#x}, buses {}..={}",
+                ecam.base, ecam.start_bus, ecam.end_bus);
+            ConfigSpace::Ecam(ecam)
+        }
+        None => {
+            crate::info!("using legacy configuration mechanism");
+            ConfigSpace::Legacy
+        }
+    };
+
+    EarlyInit::set(&CONFIG_SPACE, config_space);
+
+    let mut devices = BTreeMap::new();
+    scan_bus(0, &mut devices);
+
+    EarlyInit::set(&DEVICES, Mutex::new(devices));
+}
+
+#[derive(Debug)]
+pub enum Match {
+    Id { vendor: u16, device: u16 },
+    Class { class: u8, subclass: u8 },
+}
+
+impl Match {
+    fn matches(&self, dev: &PciDevice) -> bool {
+        match *self {
+            Match::Id { vendor, device } => {
+                dev.vendor_id == vendor && dev.device_id == device
+            }
+            Match::Class { class, subclass } => {
+                dev.class == class && dev.subclass == subclass
+            }
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    pub probe: fn(&PciDevice) -> Result<(), ProbeError>,
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    Unsupported,
+    MemoryExhausted,
+    Irq(RegisterIrqError),
+}
+
+impl From<MemoryExhausted> for ProbeError {
+    fn from(_: MemoryExhausted) -> Self {
+        ProbeError::MemoryExhausted
+    }
+}
+
+impl From<RegisterIrqError> for ProbeError {
+    fn from(e: RegisterIrqError) -> Self {
+        ProbeError::Irq(e)
+    }
+}
+
+/// Binds `driver` to every matching device not already claimed by another
+/// driver
+pub fn register_driver(driver: &'static Driver) {
+    let mut last = None;
+
+    // probes run without the lock held, so take one device at a time in
+    // address order, picking up after the last one:
+    loop {
+        let dev = {
+            let devices = DEVICES.lock();
+
+            let after = match last {
+                Some(addr) => (Bound::Excluded(addr), Bound::Unbounded),
+                None => (Bound::Unbounded, Bound::Unbounded),
+            };
+
+            let next = devices.range(after)
+                .map(|(_, dev)| dev)
+                .filter(|dev| dev.driver.is_none())
+                .find(|dev| driver.matches.iter().any(|m| m.matches(dev)));
+
+            match next {
+                Some(dev) => dev.clone(),
+                None => break,
+            }
+        };
+
+        let addr = dev.addr;
+        last = Some(addr);
+
+        match (driver.probe)(&dev) {
+            Ok(()) => {
+                crate::info!("{}: bound to {}", addr, driver.name);
+
+                if let Some(dev) = DEVICES.lock().get_mut(&addr) {
+                    dev.driver = Some(driver.name);
+                }
+            }
+            Err(e) => {
+                crate::warn!("{}: {} probe failed: {:?}", addr, driver.name, e);
+            }
+        }
+    }
+}
+
+/// Copies device information into `buf`, returning the total number of
+/// devices present on the bus
+pub fn list(buf: &mut [PciDeviceInfo]) -> usize {
+    let devices = DEVICES.lock();
+
+    for (slot, dev) in buf.iter_mut().zip(devices.values()) {
+        *slot = dev.info();
+    }
+
+    devices.len()
+}
@@ -1,26 +1,109 @@
 use core::cmp;
+use core::time::Duration;
+
 use x86_64::instructions::port::Port;
 
 use crate::critical;
 
 const PIT_FREQ: usize = 1193182;
 
-unsafe fn set_frequency(hz: usize) {
-    let divisor = cmp::min(PIT_FREQ / hz, 65535);
+// the longest channel 0 can count down, about 55 ms
+const MAX_COUNT: u128 = 0xffff;
 
-    critical::section(|| {
-        let mut port = Port::<u8>::new(0x40);
-        port.write(((divisor >> 0) & 0xff) as u8);
-        port.write(((divisor >> 8) & 0xff) as u8);
-    });
+// channel 2 is gated and read back through the PC speaker port
+const SPEAKER_PORT: u16 = 0x61;
+const SPEAKER_GATE: u8 = 0x01;
+const SPEAKER_ENABLE: u8 = 0x02;
+const SPEAKER_OUT2: u8 = 0x20;
+
+const CALIBRATE_MS: usize = 10;
+
+// channel 0, lobyte/hibyte, mode 0 (interrupt on terminal count). writing
+// this stops the count until the next one is loaded
+fn stop_channel0() {
+    unsafe { Port::<u8>::new(0x43).write(0b00110000); }
 }
 
 pub unsafe fn init() {
+    critical::section(stop_channel0);
+}
+
+/// Raises IRQ 0 once after `delay`, or a little sooner if that's longer
+/// than channel 0 can count. Stops it if `None`. Drives the timer interrupt
+/// when there is no local APIC.
+pub fn set_timer(delay: Option<Duration>) {
     critical::section(|| {
-        // initialize pit channel 0
-        let mut port = Port::<u8>::new(0x43);
-        port.write(0b00110100);
+        stop_channel0();
+
+        if let Some(delay) = delay {
+            let cycles = delay.as_nanos() * PIT_FREQ as u128 / 1_000_000_000;
 
-        set_frequency(20);
+            // a count of zero would mean the full 65536:
+            let count = cmp::max(cmp::min(cycles, MAX_COUNT), 1);
+
+            let mut port = Port::<u8>::new(0x40);
+            unsafe {
+                port.write(((count >> 0) & 0xff) as u8);
+                port.write(((count >> 8) & 0xff) as u8);
+            }
+        }
     });
 }
+
+/// Measures the TSC frequency in Hz
+pub fn calibrate_tsc() -> u64 {
+    calibrate(|| unsafe { core::arch::x86_64::_rdtsc() })
+}
+
+// Counts `count` PIT cycles down on channel 2 and spins until it's done.
+// `start` is called as the count begins.
+unsafe fn one_shot<T>(count: usize, start: impl FnOnce() -> T) -> T {
+    let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+
+    // gate channel 2 on, with the speaker itself kept off:
+    let gate = speaker.read();
+    speaker.write((gate & !SPEAKER_ENABLE) | SPEAKER_GATE);
+
+    // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count):
+    Port::<u8>::new(0x43).write(0b10110000);
+
+    let mut data = Port::<u8>::new(0x42);
+    data.write(((count >> 0) & 0xff) as u8);
+    data.write(((count >> 8) & 0xff) as u8);
+
+    let value = start();
+
+    // OUT2 goes high when the count reaches zero
+    while (speaker.read() & SPEAKER_OUT2) == 0 {}
+
+    speaker.write(gate);
+
+    value
+}
+
+/// Measures the rate in Hz of an increasing counter against a one shot count
+/// on channel 2. Doesn't need interrupts, so can run before they're enabled.
+pub fn calibrate(mut counter: impl FnMut() -> u64) -> u64 {
+    let count = PIT_FREQ * CALIBRATE_MS / 1000;
+
+    critical::section(|| unsafe {
+        let start = one_shot(count, || counter());
+        let end = counter();
+
+        end.wrapping_sub(start) * 1000 / CALIBRATE_MS as u64
+    })
+}
+
+/// Spins for at least `duration`. Only for use before the scheduler runs,
+/// everything else should sleep through time::sleep.
+pub fn delay(duration: Duration) {
+    let mut cycles = duration.as_nanos() * PIT_FREQ as u128 / 1_000_000_000 + 1;
+
+    while cycles > 0 {
+        let count = cmp::min(cycles, MAX_COUNT);
+
+        critical::section(|| unsafe { one_shot(count as usize, || ()) });
+
+        cycles -= count;
+    }
+}
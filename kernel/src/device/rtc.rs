@@ -0,0 +1,127 @@
+use x86_64::instructions::port::Port;
+
+use crate::critical;
+use crate::time::DateTime;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+
+// set in the hours register for PM times in 12 hour mode
+const HOURS_PM: u8 = 0x80;
+
+// bounds how long we wait for the RTC to give two identical readings
+const MAX_READS: usize = 16;
+
+// an update takes under 2ms, this is far longer
+const SPIN_LIMIT: usize = 100_000;
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(INDEX_PORT).write(reg);
+        Port::<u8>::new(DATA_PORT).read()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+fn read_raw() -> Raw {
+    // registers are garbage while the RTC is updating them, which it does
+    // once a second:
+    for _ in 0..SPIN_LIMIT {
+        if (read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS) == 0 {
+            break;
+        }
+    }
+
+    Raw {
+        seconds: read_register(REG_SECONDS),
+        minutes: read_register(REG_MINUTES),
+        hours: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads the current date and time from the CMOS RTC, which we assume
+/// keeps UTC
+pub fn read() -> DateTime {
+    let (raw, status_b) = critical::section(|| {
+        let mut raw = read_raw();
+
+        // an update may still begin between the flag check and the reads,
+        // so read until two passes agree:
+        for _ in 0..MAX_READS {
+            let again = read_raw();
+
+            if again == raw {
+                break;
+            }
+
+            raw = again;
+        }
+
+        (raw, read_register(REG_STATUS_B))
+    });
+
+    let pm = (raw.hours & HOURS_PM) != 0;
+    let mut raw = Raw { hours: raw.hours & !HOURS_PM, ..raw };
+
+    if (status_b & STATUS_B_BINARY) == 0 {
+        raw = Raw {
+            seconds: from_bcd(raw.seconds),
+            minutes: from_bcd(raw.minutes),
+            hours: from_bcd(raw.hours),
+            day: from_bcd(raw.day),
+            month: from_bcd(raw.month),
+            year: from_bcd(raw.year),
+        };
+    }
+
+    if (status_b & STATUS_B_24_HOUR) == 0 {
+        // 12 hour mode runs 12, 1, .. 11
+        raw.hours %= 12;
+
+        if pm {
+            raw.hours += 12;
+        }
+    }
+
+    // the century register isn't at a standard location, so go by the year:
+    let year = if raw.year < 70 { 2000 } else { 1900 } + raw.year as u16;
+
+    DateTime {
+        year,
+        month: raw.month,
+        day: raw.day,
+        hour: raw.hours,
+        minute: raw.minutes,
+        second: raw.seconds,
+    }
+}
@@ -0,0 +1,402 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+
+use arraydeque::{ArrayDeque, Saturating};
+use x86_64::instructions::port::Port;
+
+use crate::interrupt::{self, IrqHandler};
+use crate::mem::MemoryExhausted;
+use crate::sync::{Arc, Mutex};
+use crate::util::IrqWakers;
+
+const UART_CLOCK: u32 = 115200;
+pub const DEFAULT_BAUD: u32 = 115200;
+
+// register offsets from the port base
+const REG_DATA: u16 = 0;
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_INTERRUPT_ID: u16 = 2;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+const REG_MODEM_STATUS: u16 = 6;
+
+// with DLAB set in the line control register:
+const REG_DIVISOR_LO: u16 = 0;
+const REG_DIVISOR_HI: u16 = 1;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+
+// enable and clear both FIFOs, 14 byte receive trigger level
+const FCR_ENABLE: u8 = 0xc7;
+const FIFO_DEPTH: usize = 16;
+
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+// OUT2 gates the UART's interrupt line on PC hardware
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOPBACK: u8 = 0x10;
+
+const IIR_NONE_PENDING: u8 = 0x01;
+const IIR_ID_MASK: u8 = 0x0e;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_RX_TIMEOUT: u8 = 0x0c;
+
+bitflags::bitflags! {
+    struct InterruptEnable: u8 {
+        const RX_AVAILABLE  = 0x01;
+        const TX_EMPTY      = 0x02;
+        const LINE_STATUS   = 0x04;
+    }
+}
+
+bitflags::bitflags! {
+    struct LineStatus: u8 {
+        const DATA_READY    = 0x01;
+        const OVERRUN       = 0x02;
+        const TX_EMPTY      = 0x20;
+    }
+}
+
+type Ring = ArrayDeque<[u8; 256], Saturating>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub fn from_index(index: usize) -> Option<ComPort> {
+        match index {
+            0 => Some(ComPort::Com1),
+            1 => Some(ComPort::Com2),
+            2 => Some(ComPort::Com3),
+            3 => Some(ComPort::Com4),
+            _ => None,
+        }
+    }
+
+    fn serial(self) -> &'static SerialPort {
+        &PORTS[self as usize]
+    }
+
+    fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+}
+
+pub struct SerialPort {
+    base: u16,
+    present: AtomicBool,
+    rx: Mutex<Option<Ring>>,
+    tx: Mutex<Option<Ring>>,
+    rx_wakers: IrqWakers,
+    tx_wakers: IrqWakers,
+}
+
+impl fmt::Debug for SerialPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SerialPort({:#x})", self.base)
+    }
+}
+
+static PORTS: [SerialPort; 4] = [
+    SerialPort::new(0x3f8),
+    SerialPort::new(0x2f8),
+    SerialPort::new(0x3e8),
+    SerialPort::new(0x2e8),
+];
+
+#[derive(Debug)]
+pub struct BadBaud;
+
+#[derive(Debug)]
+pub struct NoDevice;
+
+impl SerialPort {
+    const fn new(base: u16) -> Self {
+        SerialPort {
+            base,
+            present: AtomicBool::new(false),
+            rx: Mutex::new(None),
+            tx: Mutex::new(None),
+            rx_wakers: IrqWakers::new(),
+            tx_wakers: IrqWakers::new(),
+        }
+    }
+
+    fn reg(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    fn line_status(&self) -> LineStatus {
+        LineStatus::from_bits_truncate(unsafe { self.reg(REG_LINE_STATUS).read() })
+    }
+
+    fn set_interrupts(&self, enable: InterruptEnable) {
+        unsafe { self.reg(REG_INTERRUPT_ENABLE).write(enable.bits()); }
+    }
+
+    fn interrupts(&self) -> InterruptEnable {
+        InterruptEnable::from_bits_truncate(unsafe { self.reg(REG_INTERRUPT_ENABLE).read() })
+    }
+
+    /// Checks for a UART by sending a byte to ourselves in loopback mode
+    fn probe(&self) -> bool {
+        unsafe {
+            self.reg(REG_MODEM_CONTROL).write(MCR_LOOPBACK | MCR_RTS | MCR_OUT2);
+            self.reg(REG_DATA).write(0xae);
+
+            let ok = self.reg(REG_DATA).read() == 0xae;
+
+            self.reg(REG_MODEM_CONTROL).write(MCR_DTR | MCR_RTS | MCR_OUT2);
+            ok
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present.load(Ordering::SeqCst)
+    }
+
+    pub fn set_baud(&self, baud: u32) -> Result<(), BadBaud> {
+        if baud == 0 || UART_CLOCK % baud != 0 {
+            return Err(BadBaud);
+        }
+
+        let divisor = (UART_CLOCK / baud).to_le_bytes();
+
+        if divisor[2] != 0 || divisor[3] != 0 {
+            return Err(BadBaud);
+        }
+
+        // the divisor latch shares registers with data and interrupt
+        // enable, so nothing else may touch the port in the meantime:
+        let _tx = self.tx.lock();
+
+        unsafe {
+            self.reg(REG_LINE_CONTROL).write(LCR_8N1 | LCR_DLAB);
+            self.reg(REG_DIVISOR_LO).write(divisor[0]);
+            self.reg(REG_DIVISOR_HI).write(divisor[1]);
+            self.reg(REG_LINE_CONTROL).write(LCR_8N1);
+        }
+
+        Ok(())
+    }
+
+    /// Writes directly to the transmitter, bypassing the TX ring. Used for
+    /// kernel console output, which must work with interrupts disabled.
+    fn write_polled(&self, bytes: &[u8]) {
+        for &b in bytes {
+            while !self.line_status().contains(LineStatus::TX_EMPTY) {}
+            unsafe { self.reg(REG_DATA).write(b); }
+        }
+    }
+
+    fn drain_rx(&self) {
+        let mut rx = self.rx.lock();
+
+        while self.line_status().contains(LineStatus::DATA_READY) {
+            let b = unsafe { self.reg(REG_DATA).read() };
+
+            if let Some(rx) = rx.as_mut() {
+                if rx.push_back(b).is_err() {
+                    crate::warn!("rx buffer overflow!");
+                }
+            }
+        }
+
+        self.rx_wakers.wake_all();
+    }
+
+    fn fill_tx(&self) {
+        let mut tx = self.tx.lock();
+        let tx = match tx.as_mut() {
+            Some(tx) => tx,
+            None => return,
+        };
+
+        if !self.line_status().contains(LineStatus::TX_EMPTY) {
+            return;
+        }
+
+        let mut sent = 0;
+
+        while sent < FIFO_DEPTH {
+            match tx.pop_front() {
+                Some(b) => unsafe { self.reg(REG_DATA).write(b); }
+                None => break,
+            }
+
+            sent += 1;
+        }
+
+        let mut interrupts = self.interrupts();
+        interrupts.set(InterruptEnable::TX_EMPTY, !tx.is_empty());
+        self.set_interrupts(interrupts);
+
+        // write() kicks us too, so only wake writers if there's new room.
+        // waking them regardless would have them spin until there is:
+        if sent > 0 {
+            self.tx_wakers.wake_all();
+        }
+    }
+
+    fn service(&self) {
+        loop {
+            let id = unsafe { self.reg(REG_INTERRUPT_ID).read() };
+
+            if (id & IIR_NONE_PENDING) != 0 {
+                return;
+            }
+
+            match id & IIR_ID_MASK {
+                IIR_RX_AVAILABLE | IIR_RX_TIMEOUT => self.drain_rx(),
+                IIR_TX_EMPTY => self.fill_tx(),
+                IIR_LINE_STATUS => {
+                    if self.line_status().contains(LineStatus::OVERRUN) {
+                        crate::warn!("{:#x} overrun!", self.base);
+                    }
+                }
+                IIR_MODEM_STATUS => unsafe { self.reg(REG_MODEM_STATUS).read(); }
+                _ => return,
+            }
+        }
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, MemoryExhausted> {
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+
+        self.rx_wakers.wait(|| {
+            let mut rx = self.rx.lock();
+            let rx = rx.as_mut().expect("serial port to be initialized");
+
+            let mut count = 0;
+
+            while count < buf.len() {
+                match rx.pop_front() {
+                    Some(b) => { buf[count] = b; count += 1; }
+                    None => break,
+                }
+            }
+
+            if count == 0 {
+                Poll::Pending
+            } else {
+                Poll::Ready(count)
+            }
+        }).await
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> Result<usize, MemoryExhausted> {
+        let mut written = 0;
+
+        self.tx_wakers.wait(|| {
+            {
+                let mut tx = self.tx.lock();
+                let tx = tx.as_mut().expect("serial port to be initialized");
+
+                while written < buf.len() && !tx.is_full() {
+                    let _ = tx.push_back(buf[written]);
+                    written += 1;
+                }
+            }
+
+            // kick the transmitter, it raises TX_EMPTY interrupts from here
+            // until the ring is drained:
+            self.fill_tx();
+
+            if written == buf.len() {
+                Poll::Ready(written)
+            } else {
+                Poll::Pending
+            }
+        }).await
+    }
+}
+
+struct SerialIrq {
+    irq: u8,
+}
+
+impl IrqHandler for SerialIrq {
+    fn handle_irq(&self) {
+        for index in 0..PORTS.len() {
+            let port = ComPort::from_index(index).expect("ComPort::from_index");
+
+            if port.irq() == self.irq && port.serial().is_present() {
+                port.serial().service();
+            }
+        }
+    }
+}
+
+// Safety: must not be called more than once, and only after interrupt::init
+pub unsafe fn init() {
+    for index in 0..PORTS.len() {
+        let port = ComPort::from_index(index).expect("ComPort::from_index");
+        let serial = port.serial();
+
+        // interrupts off while we configure the port
+        serial.set_interrupts(InterruptEnable::empty());
+
+        if !serial.probe() {
+            continue;
+        }
+
+        serial.set_baud(DEFAULT_BAUD).expect("serial.set_baud");
+        serial.reg(REG_FIFO_CONTROL).write(FCR_ENABLE);
+
+        *serial.rx.lock() = Some(Ring::new());
+        *serial.tx.lock() = Some(Ring::new());
+
+        serial.present.store(true, Ordering::SeqCst);
+        serial.set_interrupts(InterruptEnable::RX_AVAILABLE | InterruptEnable::LINE_STATUS);
+    }
+
+    for &irq in &[4, 3] {
+        let handler = Arc::new(SerialIrq { irq })
+            .expect("Arc::new in serial::init");
+
+        interrupt::register_irq(irq, handler)
+            .expect("interrupt::register_irq in serial::init");
+    }
+}
+
+pub fn get(port: ComPort) -> Result<&'static SerialPort, NoDevice> {
+    let serial = port.serial();
+
+    if serial.is_present() {
+        Ok(serial)
+    } else {
+        Err(NoDevice)
+    }
+}
+
+/// Kernel console sink on COM1. Output is dropped until the port is found.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let serial = ComPort::Com1.serial();
+
+        if serial.is_present() {
+            serial.write_polled(s.as_bytes());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{self, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::mem::MemoryExhausted;
+use crate::mem::dma::DmaRegion;
+use crate::mem::page::PAGE_SIZE;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+bitflags::bitflags! {
+    pub struct DeviceStatus: u8 {
+        const ACKNOWLEDGE   = 0x01;
+        const DRIVER        = 0x02;
+        const DRIVER_OK     = 0x04;
+        const FEATURES_OK   = 0x08;
+        const FAILED        = 0x80;
+    }
+}
+
+bitflags::bitflags! {
+    pub struct DescFlags: u16 {
+        const NEXT  = 0x1;
+        const WRITE = 0x2;
+    }
+}
+
+const ISR_QUEUE: u8 = 0x1;
+
+/// Register block of a legacy (virtio 0.9.5) PCI device, which lives in I/O
+/// space behind BAR0
+#[derive(Debug)]
+pub struct LegacyIo {
+    base: u16,
+}
+
+impl LegacyIo {
+    pub fn new(base: u16) -> Self {
+        LegacyIo { base }
+    }
+
+    fn device_features(&self) -> Port<u32> {
+        Port::new(self.base + 0x00)
+    }
+
+    fn guest_features(&self) -> Port<u32> {
+        Port::new(self.base + 0x04)
+    }
+
+    fn queue_pfn(&self) -> Port<u32> {
+        Port::new(self.base + 0x08)
+    }
+
+    fn queue_size(&self) -> Port<u16> {
+        Port::new(self.base + 0x0c)
+    }
+
+    fn queue_select(&self) -> Port<u16> {
+        Port::new(self.base + 0x0e)
+    }
+
+    fn queue_notify(&self) -> Port<u16> {
+        Port::new(self.base + 0x10)
+    }
+
+    fn device_status(&self) -> Port<u8> {
+        Port::new(self.base + 0x12)
+    }
+
+    fn isr_status(&self) -> Port<u8> {
+        Port::new(self.base + 0x13)
+    }
+
+    /// Device specific configuration follows the common registers when MSI-X
+    /// is disabled, which it always is for us
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.base + 0x14 + offset).read() }
+    }
+
+    pub fn reset(&self) {
+        unsafe { self.device_status().write(0); }
+    }
+
+    pub fn add_status(&self, status: DeviceStatus) {
+        unsafe {
+            let current = self.device_status().read();
+            self.device_status().write(current | status.bits());
+        }
+    }
+
+    /// Acknowledges features offered by the device that are also in `wanted`,
+    /// returning the negotiated set
+    pub fn negotiate_features(&self, wanted: u32) -> u32 {
+        unsafe {
+            let offered = self.device_features().read();
+            let features = offered & wanted;
+            self.guest_features().write(features);
+            features
+        }
+    }
+
+    /// Reads and clears the interrupt status register, returning whether a
+    /// used buffer notification was pending
+    pub fn ack_interrupt(&self) -> bool {
+        let isr = unsafe { self.isr_status().read() };
+        (isr & ISR_QUEUE) != 0
+    }
+
+    pub fn notify(&self, queue: u16) {
+        unsafe { self.queue_notify().write(queue); }
+    }
+
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, SetupQueueError> {
+        let size = unsafe {
+            self.queue_select().write(index);
+            self.queue_size().read()
+        };
+
+        if size == 0 {
+            return Err(SetupQueueError::NoQueue);
+        }
+
+        let queue = Virtqueue::new(size)?;
+
+        unsafe {
+            self.queue_pfn().write((queue.region.phys(0) / PAGE_SIZE as u64) as u32);
+        }
+
+        Ok(queue)
+    }
+}
+
+#[derive(Debug)]
+pub enum SetupQueueError {
+    NoQueue,
+    MemoryExhausted,
+}
+
+impl From<MemoryExhausted> for SetupQueueError {
+    fn from(_: MemoryExhausted) -> Self {
+        SetupQueueError::MemoryExhausted
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    pub device_writable: bool,
+}
+
+/// A split virtqueue in the legacy memory layout: the descriptor table and
+/// available ring, then the used ring on the next page boundary.
+#[derive(Debug)]
+pub struct Virtqueue {
+    region: DmaRegion,
+    size: u16,
+    used_offset: usize,
+    last_used: u16,
+}
+
+fn align_page(n: usize) -> usize {
+    (n + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+impl Virtqueue {
+    fn new(size: u16) -> Result<Self, MemoryExhausted> {
+        let size_ = size as usize;
+
+        let desc_len = mem::size_of::<Descriptor>() * size_;
+        let avail_len = mem::size_of::<u16>() * (3 + size_);
+        let used_len = mem::size_of::<u16>() * 3 + mem::size_of::<UsedElem>() * size_;
+
+        let used_offset = align_page(desc_len + avail_len);
+        let total = used_offset + align_page(used_len);
+
+        let region = DmaRegion::alloc(total / PAGE_SIZE)?;
+
+        Ok(Virtqueue { region, size, used_offset, last_used: 0 })
+    }
+
+    fn desc(&self, index: u16) -> *mut Descriptor {
+        self.region.ptr::<Descriptor>(0).wrapping_add(index as usize)
+    }
+
+    fn avail_idx(&self) -> *mut u16 {
+        self.region.ptr::<u16>(self.size as usize * mem::size_of::<Descriptor>() + 2)
+    }
+
+    fn avail_ring(&self, slot: u16) -> *mut u16 {
+        self.avail_idx().wrapping_add(1 + slot as usize)
+    }
+
+    fn used_idx(&self) -> *mut u16 {
+        self.region.ptr::<u16>(self.used_offset + 2)
+    }
+
+    /// Places a chain of buffers starting at descriptor 0 on the available
+    /// ring. Only one chain is ever outstanding at a time.
+    pub fn submit(&mut self, buffers: &[Buffer]) {
+        assert!(buffers.len() <= self.size as usize, "chain longer than virtqueue");
+
+        for (index, buffer) in buffers.iter().enumerate() {
+            let mut flags = DescFlags::empty();
+
+            if buffer.device_writable {
+                flags.insert(DescFlags::WRITE);
+            }
+
+            if index + 1 < buffers.len() {
+                flags.insert(DescFlags::NEXT);
+            }
+
+            unsafe {
+                ptr::write_volatile(self.desc(index as u16), Descriptor {
+                    addr: buffer.addr,
+                    len: buffer.len,
+                    flags: flags.bits(),
+                    next: index as u16 + 1,
+                });
+            }
+        }
+
+        unsafe {
+            let avail_idx = ptr::read_volatile(self.avail_idx());
+            ptr::write_volatile(self.avail_ring(avail_idx % self.size), 0);
+
+            // descriptors must be visible before the index update:
+            atomic::fence(Ordering::SeqCst);
+            ptr::write_volatile(self.avail_idx(), avail_idx.wrapping_add(1));
+            atomic::fence(Ordering::SeqCst);
+        }
+    }
+
+    /// Returns true and consumes the used element if the device has
+    /// completed the outstanding chain
+    pub fn poll_used(&mut self) -> bool {
+        let used_idx = unsafe { ptr::read_volatile(self.used_idx()) };
+
+        if used_idx == self.last_used {
+            return false;
+        }
+
+        self.last_used = self.last_used.wrapping_add(1);
+        true
+    }
+}
@@ -0,0 +1,273 @@
+use core::fmt::{self, Debug};
+use core::ptr;
+use core::task::Poll;
+
+use crate::device::block::{self, BlockDevice, Sector, SECTOR_SIZE};
+use crate::device::pci::{self, Command, Match, PciDevice, ProbeError};
+use crate::device::virtio::{self, Buffer, DeviceStatus, LegacyIo, Virtqueue};
+use crate::interrupt::{self, IrqHandler};
+use crate::mem::MemoryExhausted;
+use crate::mem::dma::DmaRegion;
+use crate::mem::page::PAGE_SIZE;
+use crate::sync::{Arc, AsyncMutex};
+use crate::util::IrqWakers;
+
+// transitional device id, which exposes the legacy register layout
+const DEVICE_ID_LEGACY_BLOCK: u16 = 0x1001;
+
+const FEATURE_RO: u32 = 1 << 5;
+const FEATURE_FLUSH: u32 = 1 << 9;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+const HEADER_OFFSET: usize = 0;
+const STATUS_OFFSET: usize = 16;
+const BOUNCE_OFFSET: usize = PAGE_SIZE;
+const BOUNCE_PAGES: usize = 16;
+const BOUNCE_SECTORS: usize = BOUNCE_PAGES * PAGE_SIZE / SECTOR_SIZE;
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-blk",
+    matches: &[Match::Id { vendor: virtio::VENDOR_ID, device: DEVICE_ID_LEGACY_BLOCK }],
+    probe,
+};
+
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+#[derive(Debug)]
+pub enum VirtioBlkError {
+    MemoryExhausted,
+    OutOfRange,
+    ReadOnly,
+    Unsupported,
+    Io,
+}
+
+impl From<MemoryExhausted> for VirtioBlkError {
+    fn from(_: MemoryExhausted) -> Self {
+        VirtioBlkError::MemoryExhausted
+    }
+}
+
+#[repr(C)]
+struct RequestHeader {
+    type_: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+struct Request {
+    queue: Virtqueue,
+    dma: DmaRegion,
+}
+
+struct Shared {
+    io: LegacyIo,
+    request: AsyncMutex<Request>,
+    wakers: IrqWakers,
+    capacity: u64,
+    features: u32,
+}
+
+impl IrqHandler for Shared {
+    fn handle_irq(&self) {
+        if self.io.ack_interrupt() {
+            self.wakers.wake_all();
+        }
+    }
+}
+
+pub struct VirtioBlk {
+    shared: Arc<Shared>,
+}
+
+impl Debug for VirtioBlk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VirtioBlk {{ capacity: {} }}", self.shared.capacity)
+    }
+}
+
+fn probe(dev: &PciDevice) -> Result<(), ProbeError> {
+    let port = dev.bars[0]
+        .and_then(|bar| bar.io_port())
+        .ok_or(ProbeError::Unsupported)?;
+
+    let irq = dev.irq().ok_or(ProbeError::Unsupported)?;
+
+    dev.enable(Command::IO_SPACE | Command::BUS_MASTER);
+
+    let io = LegacyIo::new(port);
+    io.reset();
+    io.add_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+
+    let features = io.negotiate_features(FEATURE_RO | FEATURE_FLUSH);
+
+    let queue = match io.setup_queue(0) {
+        Ok(queue) => queue,
+        Err(e) => {
+            crate::error!("could not set up request queue: {:?}", e);
+            io.add_status(DeviceStatus::FAILED);
+            return Err(ProbeError::Unsupported);
+        }
+    };
+
+    // capacity is a little endian u64 at the start of the device config:
+    let capacity = (io.config_u32(0) as u64) | ((io.config_u32(4) as u64) << 32);
+
+    let dma = DmaRegion::alloc(1 + BOUNCE_PAGES)?;
+
+    let shared = Arc::new(Shared {
+        io,
+        request: AsyncMutex::new(Request { queue, dma }),
+        wakers: IrqWakers::new(),
+        capacity,
+        features,
+    })?;
+
+    interrupt::register_irq(irq, shared.clone())?;
+
+    shared.io.add_status(DeviceStatus::DRIVER_OK);
+
+    crate::info!("{} sectors, irq {}{}", capacity, irq,
+        if (features & FEATURE_RO) != 0 { ", read only" } else { "" });
+
+    block::register(BlockDevice::Virtio(VirtioBlk { shared }));
+
+    Ok(())
+}
+
+impl VirtioBlk {
+    pub fn sector_count(&self) -> u64 {
+        self.shared.capacity
+    }
+
+    fn check_range(&self, lba: usize, count: usize) -> Result<(), VirtioBlkError> {
+        match (lba as u64).checked_add(count as u64) {
+            Some(end) if end <= self.shared.capacity => Ok(()),
+            _ => Err(VirtioBlkError::OutOfRange),
+        }
+    }
+
+    /// Submits a single request and waits for the device to complete it.
+    /// `data_len` bytes of the bounce buffer are transferred.
+    async fn request(&self, request: &mut Request, type_: u32, sector: u64, data_len: usize)
+        -> Result<(), VirtioBlkError>
+    {
+        let dma = &request.dma;
+
+        unsafe {
+            ptr::write_volatile(dma.ptr::<RequestHeader>(HEADER_OFFSET), RequestHeader {
+                type_,
+                _reserved: 0,
+                sector,
+            });
+
+            ptr::write_volatile(dma.ptr::<u8>(STATUS_OFFSET), 0xff);
+        }
+
+        let header = Buffer {
+            addr: dma.phys(HEADER_OFFSET),
+            len: 16,
+            device_writable: false,
+        };
+
+        let status = Buffer {
+            addr: dma.phys(STATUS_OFFSET),
+            len: 1,
+            device_writable: true,
+        };
+
+        if data_len == 0 {
+            request.queue.submit(&[header, status]);
+        } else {
+            let data = Buffer {
+                addr: dma.phys(BOUNCE_OFFSET),
+                len: data_len as u32,
+                device_writable: type_ == REQUEST_IN,
+            };
+
+            request.queue.submit(&[header, data, status]);
+        }
+
+        self.shared.io.notify(0);
+
+        let shared = &self.shared;
+        let queue = &mut request.queue;
+
+        shared.wakers.wait(|| {
+            if queue.poll_used() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }).await?;
+
+        match unsafe { ptr::read_volatile(request.dma.ptr::<u8>(STATUS_OFFSET)) } {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(VirtioBlkError::Unsupported),
+            _ => Err(VirtioBlkError::Io),
+        }
+    }
+
+    pub async fn read_sectors(&self, lba: usize, buffs: &mut [&mut Sector]) -> Result<(), VirtioBlkError> {
+        self.check_range(lba, buffs.len())?;
+
+        let mut request = self.shared.request.lock().await?;
+        let mut lba = lba;
+
+        for chunk in buffs.chunks_mut(BOUNCE_SECTORS) {
+            self.request(&mut request, REQUEST_IN, lba as u64, chunk.len() * SECTOR_SIZE).await?;
+
+            for (index, buff) in chunk.iter_mut().enumerate() {
+                let bounce = request.dma.ptr::<u8>(BOUNCE_OFFSET + index * SECTOR_SIZE);
+                unsafe { ptr::copy(bounce, buff.as_mut_ptr(), SECTOR_SIZE); }
+            }
+
+            lba += chunk.len();
+        }
+
+        Ok(())
+    }
+
+    pub async fn write_sectors(&self, lba: usize, buffs: &[&Sector]) -> Result<(), VirtioBlkError> {
+        if (self.shared.features & FEATURE_RO) != 0 {
+            return Err(VirtioBlkError::ReadOnly);
+        }
+
+        self.check_range(lba, buffs.len())?;
+
+        let mut request = self.shared.request.lock().await?;
+        let mut lba = lba;
+
+        for chunk in buffs.chunks(BOUNCE_SECTORS) {
+            for (index, buff) in chunk.iter().enumerate() {
+                let bounce = request.dma.ptr::<u8>(BOUNCE_OFFSET + index * SECTOR_SIZE);
+                unsafe { ptr::copy(buff.as_ptr(), bounce, SECTOR_SIZE); }
+            }
+
+            self.request(&mut request, REQUEST_OUT, lba as u64, chunk.len() * SECTOR_SIZE).await?;
+
+            lba += chunk.len();
+        }
+
+        Ok(())
+    }
+
+    pub async fn flush(&self) -> Result<(), VirtioBlkError> {
+        if (self.shared.features & FEATURE_FLUSH) == 0 {
+            // without the flush feature the device is write through
+            return Ok(());
+        }
+
+        let mut request = self.shared.request.lock().await?;
+
+        self.request(&mut request, REQUEST_FLUSH, 0, 0).await
+    }
+}
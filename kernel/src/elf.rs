@@ -0,0 +1,305 @@
+use core::mem;
+use core::ptr;
+use core::slice;
+
+use arrayvec::ArrayVec;
+use interface::SysError;
+
+use crate::critical;
+use crate::fs::File;
+use crate::mem::page::{self, MapError, PageFlags};
+use crate::mem::phys;
+use crate::mem::user::{self, PageRange};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+
+// more than any executable our linker script produces
+const MAX_PROGRAM_HEADERS: usize = 16;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct FileHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(SysError),
+    /// Not an x86-64 executable we can run
+    BadHeader,
+    /// A segment doesn't fit in user memory or overlaps something mapped
+    BadSegment,
+    MemoryExhausted,
+}
+
+impl From<LoadError> for SysError {
+    fn from(e: LoadError) -> Self {
+        match e {
+            LoadError::Io(e) => e,
+            LoadError::BadHeader => SysError::BadExecutable,
+            LoadError::BadSegment => SysError::BadExecutable,
+            LoadError::MemoryExhausted => SysError::MemoryExhausted,
+        }
+    }
+}
+
+impl From<SysError> for LoadError {
+    fn from(e: SysError) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+/// Where a loaded executable starts running
+#[derive(Debug)]
+pub struct Image {
+    pub entry: u64,
+    /// Where the program headers are mapped, if a segment covers them
+    pub program_headers: Option<u64>,
+    pub program_header_count: u64,
+}
+
+impl Image {
+    pub fn program_header_size(&self) -> u64 {
+        mem::size_of::<ProgramHeader>() as u64
+    }
+}
+
+async fn read_exact(file: &File, position: u64, buf: &mut [u8]) -> Result<(), LoadError> {
+    file.seek(position as usize).await?;
+
+    let mut done = 0;
+
+    while done < buf.len() {
+        match file.read(&mut buf[done..]).await? {
+            0 => return Err(LoadError::BadHeader),
+            read => done += read,
+        }
+    }
+
+    Ok(())
+}
+
+async fn read_struct<T: Copy>(file: &File, position: u64) -> Result<T, LoadError> {
+    let mut buf = [0u8; 64];
+    let buf = &mut buf[..mem::size_of::<T>()];
+
+    read_exact(file, position, buf).await?;
+
+    Ok(unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) })
+}
+
+fn validate(header: &FileHeader) -> Result<(), LoadError> {
+    let valid = header.ident[0..4] == ELF_MAGIC
+        && header.ident[4] == ELFCLASS64
+        && header.ident[5] == ELFDATA2LSB
+        && header.ident[6] == EV_CURRENT
+        && header.kind == ET_EXEC
+        && header.machine == EM_X86_64
+        && header.phentsize as usize == mem::size_of::<ProgramHeader>()
+        && header.phnum as usize <= MAX_PROGRAM_HEADERS;
+
+    if valid {
+        Ok(())
+    } else {
+        Err(LoadError::BadHeader)
+    }
+}
+
+fn segment_pages(segment: &ProgramHeader) -> Result<PageRange, LoadError> {
+    match segment.vaddr.checked_add(segment.memsz) {
+        Some(end) if end <= user::MAX_USER_ADDR => {}
+        _ => return Err(LoadError::BadSegment),
+    }
+
+    PageRange::containing(segment.vaddr, segment.memsz)
+        .map_err(|_| LoadError::BadSegment)
+}
+
+// Flags for a page, which may be shared between the end of one segment and
+// the start of the next
+fn page_flags(segments: &[ProgramHeader], page: u64) -> PageFlags {
+    let mut flags = PageFlags::PRESENT | PageFlags::USER | PageFlags::NO_EXECUTE;
+
+    for segment in segments {
+        let contains = segment_pages(segment)
+            .map(|range| range.contains(page))
+            .unwrap_or(false);
+
+        if contains {
+            if (segment.flags & PF_W) != 0 {
+                flags.insert(PageFlags::WRITE);
+            }
+
+            if (segment.flags & PF_X) != 0 {
+                flags.remove(PageFlags::NO_EXECUTE);
+            }
+        }
+    }
+
+    flags
+}
+
+// Finds the address the program headers are loaded at, for the auxiliary
+// vector
+fn program_headers_addr(header: &FileHeader, segments: &[ProgramHeader]) -> Option<u64> {
+    let size = header.phnum as u64 * mem::size_of::<ProgramHeader>() as u64;
+    let end = header.phoff.checked_add(size)?;
+
+    segments.iter()
+        .find(|segment| {
+            let segment_end = match segment.offset.checked_add(segment.filesz) {
+                Some(segment_end) => segment_end,
+                None => return false,
+            };
+
+            header.phoff >= segment.offset && end <= segment_end
+        })
+        .and_then(|segment| segment.vaddr.checked_add(header.phoff - segment.offset))
+}
+
+fn map_zeroed(range: &PageRange) -> Result<(), LoadError> {
+    for addr in range.pages() {
+        let addr = addr as *mut u8;
+
+        // the previous segment may end in this page:
+        if page::is_mapped(addr) {
+            continue;
+        }
+
+        let phys = phys::alloc()
+            .map_err(|_| LoadError::MemoryExhausted)?;
+
+        // writable while we fill it in, page_flags applies the segment's
+        // own permissions once it's loaded
+        unsafe {
+            page::map(phys, addr, PageFlags::PRESENT | PageFlags::USER | PageFlags::WRITE)
+                .map_err(|e| match e {
+                    MapError::AlreadyMapped => panic!("MapError::AlreadyMapped in elf::map_zeroed"),
+                    MapError::CannotAllocatePageTable => LoadError::MemoryExhausted,
+                })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps the executable in `file` into the current page context. Segments
+/// are zero filled past their file contents, which covers the BSS.
+pub async fn load(file: &File) -> Result<Image, LoadError> {
+    let header = read_struct::<FileHeader>(file, 0).await?;
+
+    validate(&header)?;
+
+    let mut segments = ArrayVec::<[ProgramHeader; MAX_PROGRAM_HEADERS]>::new();
+
+    for index in 0..header.phnum as u64 {
+        let position = header.phoff.checked_add(index * mem::size_of::<ProgramHeader>() as u64)
+            .ok_or(LoadError::BadHeader)?;
+
+        let program_header = read_struct::<ProgramHeader>(file, position).await?;
+
+        if program_header.kind == PT_LOAD {
+            segments.push(program_header);
+        }
+    }
+
+    // the entry point has to be somewhere we'll let it execute:
+    let entry_valid = segments.iter().any(|segment| {
+        (segment.flags & PF_X) != 0
+            && header.entry >= segment.vaddr
+            && header.entry - segment.vaddr < segment.memsz
+    });
+
+    if !entry_valid {
+        return Err(LoadError::BadHeader);
+    }
+
+    // check every segment before mapping any of them:
+    for segment in segments.iter() {
+        if segment.filesz > segment.memsz {
+            return Err(LoadError::BadSegment);
+        }
+
+        let range = segment_pages(segment)?;
+
+        for addr in range.pages() {
+            let shared = segments.iter()
+                .filter(|other| other.vaddr < segment.vaddr)
+                .filter_map(|other| segment_pages(other).ok())
+                .any(|other| other.contains(addr));
+
+            if !shared {
+                let page = PageRange::new(addr, 1)
+                    .map_err(|_| LoadError::BadSegment)?;
+
+                let crit = critical::begin();
+                user::validate_available(&page, &crit)
+                    .map_err(|_| LoadError::BadSegment)?;
+            }
+        }
+    }
+
+    for segment in segments.iter() {
+        map_zeroed(&segment_pages(segment)?)?;
+
+        if segment.filesz > 0 {
+            let dest = unsafe {
+                slice::from_raw_parts_mut(segment.vaddr as *mut u8, segment.filesz as usize)
+            };
+
+            read_exact(file, segment.offset, dest).await?;
+        }
+    }
+
+    for segment in segments.iter() {
+        for addr in segment_pages(segment)?.pages() {
+            unsafe {
+                page::modify(addr as *mut u8, page_flags(&segments, addr))
+                    .expect("page::modify in elf::load");
+            }
+        }
+    }
+
+    Ok(Image {
+        entry: header.entry,
+        program_headers: program_headers_addr(&header, &segments),
+        program_header_count: header.phnum as u64,
+    })
+}
@@ -7,10 +7,11 @@ use futures::pin_mut;
 use futures::stream::{self, Stream, StreamExt, TryStream, TryStreamExt};
 use interface::{SysError, SysResult};
 
-use crate::device::ide::{AtaError, Sector};
+use crate::device::block::{BlockError, Sector};
 use crate::device::mbr::Partition;
 use crate::mem::MemoryExhausted;
 use crate::sync::{Arc, AsyncMutex};
+use crate::time::DateTime;
 
 const DIR_ENTRY_SIZE: usize = 32;
 const SECTOR_SIZE: usize = 512;
@@ -29,27 +30,27 @@ struct Filesystem {
 #[derive(Debug)]
 pub enum OpenError {
     MemoryExhausted,
-    Ata(AtaError),
+    Block(BlockError),
 }
 
 #[derive(Debug)]
 pub enum FatError {
     MemoryExhausted,
-    Ata(AtaError),
+    Block(BlockError),
 }
 
 impl From<FatError> for SysError {
     fn from(e: FatError) -> Self {
         match e {
             FatError::MemoryExhausted => SysError::MemoryExhausted,
-            FatError::Ata(_) => SysError::IoError,
+            FatError::Block(_) => SysError::IoError,
         }
     }
 }
 
-impl From<AtaError> for FatError {
-    fn from(e: AtaError) -> FatError {
-        FatError::Ata(e)
+impl From<BlockError> for FatError {
+    fn from(e: BlockError) -> FatError {
+        FatError::Block(e)
     }
 }
 
@@ -65,7 +66,7 @@ struct ClusterNumber(usize);
 impl Fat16 {
     pub async fn open(part: Partition) -> Result<Self, FatError> {
         let bpb = BiosParameterBlock::read(&part).await
-            .map_err(FatError::Ata)?;
+            .map_err(FatError::Block)?;
 
         let fs = Arc::new(Filesystem { part, bpb })
             .map_err(|_| FatError::MemoryExhausted)?;
@@ -82,7 +83,7 @@ impl Fat16 {
 }
 
 impl Filesystem {
-    async fn next_cluster(&self, cluster: ClusterNumber) -> Result<Option<ClusterNumber>, AtaError> {
+    async fn next_cluster(&self, cluster: ClusterNumber) -> Result<Option<ClusterNumber>, BlockError> {
         const FAT_ENTRY_SIZE: usize = mem::size_of::<u16>();
 
         let max_cluster = self.bpb.fat_sector_count() * SECTOR_SIZE / FAT_ENTRY_SIZE;
@@ -114,7 +115,7 @@ impl Filesystem {
         }
     }
 
-    fn cluster_chain(&self, start: ClusterNumber) -> impl Stream<Item = Result<ClusterNumber, AtaError>> + '_ {
+    fn cluster_chain(&self, start: ClusterNumber) -> impl Stream<Item = Result<ClusterNumber, BlockError>> + '_ {
         stream::unfold(Some(start), move |cluster| async move {
             match cluster {
                 Some(cluster) => {
@@ -128,7 +129,7 @@ impl Filesystem {
         })
     }
 
-    fn sector_chain(&self, start: ClusterNumber) -> impl Stream<Item = Result<usize, AtaError>> + '_ {
+    fn sector_chain(&self, start: ClusterNumber) -> impl Stream<Item = Result<usize, BlockError>> + '_ {
         self.cluster_chain(start)
             .map(move |cluster| {
                 cluster.map(|cluster| stream::iter(self.bpb.cluster_sectors(cluster).map(Ok)))
@@ -150,7 +151,15 @@ pub struct Directory {
 }
 
 impl Directory {
-    fn directory_sectors(&self) -> impl TryStream<Ok = usize, Error = AtaError> + '_ {
+    /// The directory's own entry in its parent, None for the root
+    pub fn dirent(&self) -> Option<&DirEntry> {
+        match &self.kind {
+            DirectoryKind::Root => None,
+            DirectoryKind::Sub(dirent) => Some(dirent),
+        }
+    }
+
+    fn directory_sectors(&self) -> impl TryStream<Ok = usize, Error = BlockError> + '_ {
         match &self.kind {
             DirectoryKind::Root => {
                 let first_sector = self.fs.bpb.first_root_dir_sector();
@@ -183,7 +192,7 @@ impl Directory {
         let fs = &self.fs;
 
         self.directory_sectors()
-            .map_err(FatError::Ata)
+            .map_err(FatError::Block)
             .and_then(move |sector| async move {
                 let raw_entries = read_raw_entries_from_sector(fs, sector).await?;
                 Ok(stream::iter(raw_entries.into_iter().map(Ok)))
@@ -212,9 +221,8 @@ impl Directory {
             .entries()
             .try_filter(|entry| {
                 let entry_name = entry.name();
-                crate::println!("--- {:?}", core::str::from_utf8(&entry_name).expect("utf8"));
-                crate::println!("    {:?}", core::str::from_utf8(&name).expect("utf8"));
-                crate::println!();
+                crate::trace!("comparing {:?} with {:?}",
+                    core::str::from_utf8(&entry_name), core::str::from_utf8(&name));
                 future::ready(&entry_name == name)
             });
         pin_mut!(entries);
@@ -265,6 +273,14 @@ impl DirEntry {
         self.dirent().attributes().contains(Attributes::DIRECTORY)
     }
 
+    pub fn created(&self) -> u64 {
+        self.dirent().created()
+    }
+
+    pub fn modified(&self) -> u64 {
+        self.dirent().modified()
+    }
+
     pub fn open(&self) -> Result<Open, FatError> {
         let fs = self.shared.fs.clone();
 
@@ -310,6 +326,10 @@ pub struct File {
 }
 
 impl File {
+    pub fn dirent(&self) -> &DirEntry {
+        &self.dirent
+    }
+
     pub async fn read(&self, mut buf: &mut [u8]) -> Result<usize, FatError> {
         let mut seek = self.seek.lock().await?;
         let mut total_read = 0;
@@ -343,7 +363,7 @@ impl File {
             // TODO make this read multiple sectors at a time:
             self.fs.part.read_sectors(sector, &mut [&mut sector_buff])
                 .await
-                .map_err(FatError::Ata)?;
+                .map_err(FatError::Block)?;
 
             let byte_count = cmp::min(SECTOR_SIZE - seek.offset, buf.len());
 
@@ -358,6 +378,30 @@ impl File {
 
         Ok(total_read)
     }
+
+    /// Moves the read position to `position` bytes from the start
+    pub async fn seek(&self, position: usize) -> Result<(), FatError> {
+        let mut seek = self.seek.lock().await?;
+
+        let cluster_size = self.fs.bpb.sectors_per_cluster() * SECTOR_SIZE;
+
+        let mut cluster = Some(self.dirent.first_cluster());
+
+        for _ in 0..(position / cluster_size) {
+            cluster = match cluster {
+                None => break,
+                Some(cluster) => self.fs.next_cluster(cluster).await?,
+            };
+        }
+
+        *seek = Seek {
+            cluster,
+            sector: (position % cluster_size) / SECTOR_SIZE,
+            offset: position % SECTOR_SIZE,
+        };
+
+        Ok(())
+    }
 }
 
 #[repr(packed)]
@@ -373,7 +417,7 @@ pub struct RawDirEntry {
     access_date: PackedDate,
     cluster_hi: u16,
     modify_time: PackedTime,
-    modify_date: PackedTime,
+    modify_date: PackedDate,
     cluster_lo: u16,
     size: u32,
 }
@@ -420,6 +464,23 @@ impl RawDirEntry {
         filename
     }
 
+    /// Creation time in seconds since the Unix epoch, zero if unset
+    pub fn created(&self) -> u64 {
+        // the tenths field counts 10ms units up to 1.99s, on top of the two
+        // second resolution of the time itself:
+        let extra_seconds = (self.create_tenths / 100) as u64;
+
+        unix_time(self.create_date, self.create_time)
+            .map(|created| created + extra_seconds)
+            .unwrap_or(0)
+    }
+
+    /// Last modification time in seconds since the Unix epoch, zero if unset
+    pub fn modified(&self) -> u64 {
+        unix_time(self.modify_date, self.modify_time)
+            .unwrap_or(0)
+    }
+
     fn first_cluster(&self) -> ClusterNumber {
         let cluster_lo = self.cluster_lo as usize;
         let cluster_hi = self.cluster_hi as usize;
@@ -439,6 +500,24 @@ struct PackedDate {
     ymd: u16,
 }
 
+/// FAT timestamps are local time with two second resolution, which we take
+/// to be UTC
+// Seconds since the Unix epoch, or None for a time that was never set. FAT
+// leaves the creation date all zero when it isn't kept.
+fn unix_time(date: PackedDate, time: PackedTime) -> Option<u64> {
+    let ymd = date.ymd;
+    let hms = time.hms;
+
+    DateTime {
+        year: 1980 + (ymd >> 9),
+        month: ((ymd >> 5) & 0x0f) as u8,
+        day: (ymd & 0x1f) as u8,
+        hour: (hms >> 11) as u8,
+        minute: ((hms >> 5) & 0x3f) as u8,
+        second: ((hms & 0x1f) * 2) as u8,
+    }.to_unix()
+}
+
 #[repr(packed)]
 #[derive(Clone, Copy, Debug)]
 struct BiosParameterBlock {
@@ -503,7 +582,7 @@ impl BiosParameterBlock {
 }
 
 impl BiosParameterBlock {
-    pub async fn read(part: &Partition) -> Result<BiosParameterBlock, AtaError> {
+    pub async fn read(part: &Partition) -> Result<BiosParameterBlock, BlockError> {
         let mut buff: Sector = [0; 512];
         part.read_sectors(0, &mut [&mut buff]).await?;
 
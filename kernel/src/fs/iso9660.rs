@@ -0,0 +1,423 @@
+use core::cmp;
+
+use arrayvec::ArrayVec;
+use interface::SysError;
+
+use crate::device::ide::{AtapiDrive, AtapiSector, IdeError, ATAPI_SECTOR_SIZE};
+use crate::mem::MemoryExhausted;
+use crate::sync::{Arc, AsyncMutex};
+
+const SECTOR_SIZE: usize = ATAPI_SECTOR_SIZE;
+
+// volume descriptors start after the 32 KiB system area:
+const FIRST_VOLUME_DESCRIPTOR: u32 = 16;
+
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+const STANDARD_IDENTIFIER: &[u8] = b"CD001";
+
+const ROOT_RECORD_OFFSET: usize = 156;
+const JOLIET_ESCAPE_OFFSET: usize = 88;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+
+// bounds the volume descriptor walk on malformed discs
+const MAX_VOLUME_DESCRIPTORS: u32 = 32;
+
+pub type Name = ArrayVec<[u8; 256]>;
+
+#[derive(Debug)]
+pub enum IsoError {
+    MemoryExhausted,
+    Ide(IdeError),
+    NotIso9660,
+}
+
+impl From<IsoError> for SysError {
+    fn from(e: IsoError) -> Self {
+        match e {
+            IsoError::MemoryExhausted => SysError::MemoryExhausted,
+            IsoError::Ide(_) => SysError::IoError,
+            IsoError::NotIso9660 => SysError::IoError,
+        }
+    }
+}
+
+impl From<IdeError> for IsoError {
+    fn from(e: IdeError) -> Self {
+        IsoError::Ide(e)
+    }
+}
+
+impl From<MemoryExhausted> for IsoError {
+    fn from(_: MemoryExhausted) -> Self {
+        IsoError::MemoryExhausted
+    }
+}
+
+/// How file names are recorded in the directory tree we read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Names {
+    // plain ISO9660 names, upper case with a version suffix
+    Iso,
+    // UCS-2 names from a Joliet supplementary volume descriptor
+    Joliet,
+    // POSIX names from Rock Ridge NM entries in the primary tree
+    RockRidge,
+}
+
+#[derive(Debug)]
+struct Filesystem {
+    drive: AtapiDrive,
+    names: Names,
+}
+
+impl Filesystem {
+    async fn read_sector(&self, lba: u32) -> Result<AtapiSector, IsoError> {
+        let mut buff: AtapiSector = [0; SECTOR_SIZE];
+        self.drive.read_sectors(lba, &mut [&mut buff]).await?;
+        Ok(buff)
+    }
+}
+
+#[derive(Debug)]
+pub struct Iso9660 {
+    fs: Arc<Filesystem>,
+    root: Extent,
+}
+
+impl Iso9660 {
+    pub async fn open(drive: AtapiDrive) -> Result<Self, IsoError> {
+        let mut fs = Filesystem { drive, names: Names::Iso };
+
+        let mut primary = None;
+        let mut joliet = None;
+
+        for lba in FIRST_VOLUME_DESCRIPTOR..(FIRST_VOLUME_DESCRIPTOR + MAX_VOLUME_DESCRIPTORS) {
+            let sector = fs.read_sector(lba).await?;
+
+            if &sector[1..6] != STANDARD_IDENTIFIER {
+                return Err(IsoError::NotIso9660);
+            }
+
+            let root = || Record::parse(&sector[ROOT_RECORD_OFFSET..]).map(|record| record.extent);
+
+            match sector[0] {
+                DESCRIPTOR_PRIMARY => {
+                    primary = root();
+                }
+                DESCRIPTOR_SUPPLEMENTARY if is_joliet(&sector) => {
+                    joliet = root();
+                }
+                DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+
+        let primary = primary.ok_or(IsoError::NotIso9660)?;
+
+        // prefer Rock Ridge, then Joliet, then plain ISO9660 names
+        let root = if has_rock_ridge(&fs, primary).await? {
+            fs.names = Names::RockRidge;
+            primary
+        } else if let Some(joliet) = joliet {
+            fs.names = Names::Joliet;
+            joliet
+        } else {
+            primary
+        };
+
+        crate::info!("mounted with {:?} names", fs.names);
+
+        Ok(Iso9660 { fs: Arc::new(fs)?, root })
+    }
+
+    pub fn root(&self) -> Directory {
+        Directory {
+            fs: self.fs.clone(),
+            extent: self.root,
+        }
+    }
+}
+
+fn is_joliet(descriptor: &AtapiSector) -> bool {
+    match &descriptor[JOLIET_ESCAPE_OFFSET..(JOLIET_ESCAPE_OFFSET + 3)] {
+        b"%/@" | b"%/C" | b"%/E" => true,
+        _ => false,
+    }
+}
+
+/// Rock Ridge volumes carry a SUSP "SP" entry in the system use area of the
+/// root directory's "." record
+async fn has_rock_ridge(fs: &Filesystem, root: Extent) -> Result<bool, IsoError> {
+    let sector = fs.read_sector(root.lba).await?;
+
+    Ok(Record::parse(&sector)
+        .map(|dot| system_use_entries(dot.system_use).any(|(sig, _)| sig == *b"SP"))
+        .unwrap_or(false))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    lba: u32,
+    len: u32,
+}
+
+impl Extent {
+    fn sector_count(&self) -> u32 {
+        ((self.len as usize + SECTOR_SIZE - 1) / SECTOR_SIZE) as u32
+    }
+}
+
+/// A directory record, borrowed from the sector it was read from
+struct Record<'a> {
+    len: usize,
+    extent: Extent,
+    flags: u8,
+    identifier: &'a [u8],
+    system_use: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    fn parse(buf: &'a [u8]) -> Option<Record<'a>> {
+        let len = *buf.get(0)? as usize;
+
+        if len < 34 || len > buf.len() {
+            return None;
+        }
+
+        let buf = &buf[..len];
+
+        // both endian fields, we take the little endian half:
+        let lba = u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]);
+        let data_len = u32::from_le_bytes([buf[10], buf[11], buf[12], buf[13]]);
+
+        let name_len = buf[32] as usize;
+        let identifier = buf.get(33..(33 + name_len))?;
+
+        // the identifier is padded to an even length:
+        let system_use_start = 33 + name_len + (1 - name_len % 2);
+        let system_use = buf.get(system_use_start..).unwrap_or(&[]);
+
+        Some(Record {
+            len,
+            extent: Extent { lba, len: data_len },
+            flags: buf[25],
+            identifier,
+            system_use,
+        })
+    }
+
+    fn is_dir(&self) -> bool {
+        (self.flags & FLAG_DIRECTORY) != 0
+    }
+
+    /// The "." and ".." records have single byte identifiers 0 and 1
+    fn is_special(&self) -> bool {
+        self.identifier == [0] || self.identifier == [1]
+    }
+
+    fn name(&self, names: Names) -> Name {
+        match names {
+            Names::RockRidge => {
+                rock_ridge_name(self.system_use)
+                    .unwrap_or_else(|| iso_name(self.identifier))
+            }
+            Names::Joliet => joliet_name(self.identifier),
+            Names::Iso => iso_name(self.identifier),
+        }
+    }
+}
+
+/// Iterates SUSP entries as (signature, entry) pairs
+fn system_use_entries(mut area: &[u8]) -> impl Iterator<Item = ([u8; 2], &[u8])> {
+    core::iter::from_fn(move || {
+        if area.len() < 4 {
+            return None;
+        }
+
+        let len = area[2] as usize;
+
+        if len < 4 || len > area.len() {
+            return None;
+        }
+
+        let (entry, rest) = area.split_at(len);
+        area = rest;
+
+        Some(([entry[0], entry[1]], entry))
+    })
+}
+
+/// Concatenates Rock Ridge NM entries, up to as much as fits in a Name.
+/// Names continued into a separate continuation area (CE) are not followed.
+fn rock_ridge_name(system_use: &[u8]) -> Option<Name> {
+    const NM_CONTINUE: u8 = 0x01;
+
+    let mut name = Name::new();
+    let mut found = false;
+
+    for (sig, entry) in system_use_entries(system_use) {
+        if sig != *b"NM" || entry.len() < 5 {
+            continue;
+        }
+
+        found = true;
+
+        let part = &entry[5..];
+        let fits = core::cmp::min(part.len(), name.remaining_capacity());
+
+        name.try_extend_from_slice(&part[..fits])
+            .expect("Name::try_extend_from_slice within capacity");
+
+        if (entry[4] & NM_CONTINUE) == 0 || fits < part.len() {
+            break;
+        }
+    }
+
+    if found { Some(name) } else { None }
+}
+
+/// Joliet names are big endian UCS-2, which we transcode to UTF-8. That can
+/// take more room than a Name has, so long names lose their last characters.
+fn joliet_name(identifier: &[u8]) -> Name {
+    let mut name = Name::new();
+
+    for unit in identifier.chunks_exact(2) {
+        let c = core::char::from_u32(u16::from_be_bytes([unit[0], unit[1]]) as u32)
+            .unwrap_or('?');
+
+        if c == ';' {
+            // version suffix
+            break;
+        }
+
+        let mut utf8 = [0u8; 4];
+
+        // whole characters only:
+        if name.try_extend_from_slice(c.encode_utf8(&mut utf8).as_bytes()).is_err() {
+            break;
+        }
+    }
+
+    name
+}
+
+fn iso_name(identifier: &[u8]) -> Name {
+    let mut name = identifier.iter()
+        .take_while(|b| **b != b';')
+        .map(u8::to_ascii_lowercase)
+        .collect::<Name>();
+
+    // names without an extension are still recorded with a trailing dot:
+    if name.last() == Some(&b'.') {
+        name.pop();
+    }
+
+    name
+}
+
+#[derive(Debug)]
+pub struct Directory {
+    fs: Arc<Filesystem>,
+    extent: Extent,
+}
+
+impl Directory {
+    pub async fn entry(&self, name: &[u8]) -> Result<Option<DirEntry>, IsoError> {
+        for index in 0..self.extent.sector_count() {
+            let sector = self.fs.read_sector(self.extent.lba + index).await?;
+            let mut offset = 0;
+
+            // records never span sectors, a zero length marks the end of the
+            // records in this one
+            while let Some(record) = Record::parse(&sector[offset..]) {
+                offset += record.len;
+
+                if record.is_special() {
+                    continue;
+                }
+
+                if &record.name(self.fs.names)[..] == name {
+                    return Ok(Some(DirEntry {
+                        fs: self.fs.clone(),
+                        extent: record.extent,
+                        is_dir: record.is_dir(),
+                    }));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    fs: Arc<Filesystem>,
+    extent: Extent,
+    is_dir: bool,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    pub fn open(&self) -> Open {
+        let fs = self.fs.clone();
+
+        if self.is_dir {
+            Open::Dir(Directory { fs, extent: self.extent })
+        } else {
+            Open::File(File { fs, extent: self.extent, offset: AsyncMutex::new(0) })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Open {
+    File(File),
+    Dir(Directory),
+}
+
+#[derive(Debug)]
+pub struct File {
+    fs: Arc<Filesystem>,
+    extent: Extent,
+    offset: AsyncMutex<usize>,
+}
+
+impl File {
+    pub async fn read(&self, mut buf: &mut [u8]) -> Result<usize, IsoError> {
+        let mut offset = self.offset.lock().await?;
+        let mut total_read = 0;
+
+        while buf.len() > 0 && *offset < self.extent.len as usize {
+            let sector_index = *offset / SECTOR_SIZE;
+            let sector_offset = *offset % SECTOR_SIZE;
+
+            // TODO make this read multiple sectors at a time:
+            let sector = self.fs.read_sector(self.extent.lba + sector_index as u32).await?;
+
+            let remaining = self.extent.len as usize - *offset;
+            let byte_count = cmp::min(cmp::min(SECTOR_SIZE - sector_offset, buf.len()), remaining);
+
+            buf[0..byte_count].copy_from_slice(&sector[sector_offset..(sector_offset + byte_count)]);
+
+            buf = &mut buf[byte_count..];
+            total_read += byte_count;
+            *offset += byte_count;
+        }
+
+        Ok(total_read)
+    }
+
+    /// Moves the read position to `position` bytes from the start
+    pub async fn seek(&self, position: usize) -> Result<(), IsoError> {
+        *self.offset.lock().await? = position;
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 pub mod fat16;
+pub mod iso9660;
 pub mod vfs;
 
 pub use vfs::File;
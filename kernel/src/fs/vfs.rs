@@ -1,22 +1,47 @@
+use core::cmp;
 use core::fmt::Write;
+use core::mem;
+use core::ptr;
 
-use interface::{SysError, SysResult};
+use arrayvec::ArrayVec;
+use interface::{FileTimes, KeyEvent, MouseEvent, SysError, SysResult};
 use itertools::Itertools;
 
+use crate::device::{keyboard, mouse};
+use crate::device::serial::{self, ComPort, SerialPort};
 use crate::fs::fat16::{self, Fat16, DirEntry, FatError};
+use crate::fs::iso9660::{self, Iso9660, IsoError};
+use crate::tty;
 use crate::util;
 
 pub use fat16::Open;
 
+// device files live under here, independent of any mounted volume
+const DEV_PREFIX: &[u8] = b"/dev/";
+
+#[derive(Debug)]
+pub enum Volume {
+    Fat(Fat16),
+    Iso9660(Iso9660),
+}
+
+#[derive(Debug)]
+struct Mount {
+    // absolute path with no trailing slash, empty for the root mount
+    path: &'static [u8],
+    volume: Volume,
+}
+
 #[derive(Debug)]
 pub struct Filesystem {
-    root: Fat16,
+    mounts: ArrayVec<[Mount; 4]>,
 }
 
 #[derive(Debug)]
 pub enum OpenError {
     NotFound,
     Fat(FatError),
+    Iso(IsoError),
 }
 
 impl From<OpenError> for SysError {
@@ -24,62 +49,163 @@ impl From<OpenError> for SysError {
         match e {
             OpenError::NotFound => SysError::NoFile,
             OpenError::Fat(e) => e.into(),
+            OpenError::Iso(e) => e.into(),
         }
     }
 }
 
+#[derive(Debug)]
+pub struct MountTableFull;
+
 impl Filesystem {
-    pub fn new(root: Fat16) -> Self {
-        Filesystem { root }
+    pub fn new(root: Volume) -> Self {
+        let mut mounts = ArrayVec::new();
+        mounts.push(Mount { path: b"", volume: root });
+        Filesystem { mounts }
     }
 
-    pub async fn open(&self, path: &[u8]) -> Result<File, OpenError> {
-        let mut container = self.root.root();
-        let mut segments = path.split(|b| *b == b'/');
+    /// Mounts `volume` at `path`, which must be absolute and must not end in
+    /// a slash. The mount point need not exist on the parent volume.
+    pub fn mount(&mut self, path: &'static [u8], volume: Volume) -> Result<(), MountTableFull> {
+        self.mounts.try_push(Mount { path, volume })
+            .map_err(|_| MountTableFull)
+    }
 
+    pub async fn open(&self, path: &[u8]) -> Result<File, OpenError> {
         // ensure path starts with /:
-        if segments.next() != Some(b"") {
+        if path.first() != Some(&b'/') {
             // TODO support relative paths
             return Err(OpenError::NotFound);
         }
 
-        loop {
-            let segment = match segments.next() {
-                None => {
-                    return Ok(File::Fat(Open::Dir(container)));
+        if path.starts_with(DEV_PREFIX) {
+            return open_device(&path[DEV_PREFIX.len()..]);
+        }
+
+        // find the longest mount path that is a prefix of this path:
+        let (mount, rest) = self.mounts.iter()
+            .filter_map(|mount| {
+                let rest = path.get(mount.path.len()..)?;
+
+                if &path[..mount.path.len()] != mount.path {
+                    return None;
                 }
-                Some(b"") => {
-                    // ignore empty path segments
-                    continue;
+
+                if rest.len() > 0 && rest[0] != b'/' {
+                    return None;
                 }
-                Some(segment) => segment,
-            };
 
-            let entry = container.entry(segment)
-                .await
-                .map_err(OpenError::Fat)?
+                Some((mount, rest))
+            })
+            .max_by_key(|(mount, _)| mount.path.len())
+            .ok_or(OpenError::NotFound)?;
+
+        match &mount.volume {
+            Volume::Fat(fat) => open_fat(fat, rest).await,
+            Volume::Iso9660(iso) => open_iso9660(iso, rest).await,
+        }
+    }
+}
+
+fn open_device(name: &[u8]) -> Result<File, OpenError> {
+    match name {
+        b"keyboard" => Ok(File::Keyboard),
+        b"mouse" if mouse::is_present() => Ok(File::Mouse),
+        b"ttyS0" | b"ttyS1" | b"ttyS2" | b"ttyS3" => {
+            let port = ComPort::from_index((name[4] - b'0') as usize)
                 .ok_or(OpenError::NotFound)?;
 
-            match entry.open() {
-                Ok(Open::Dir(dir)) => {
-                    container = dir;
-                }
-                Ok(Open::File(file)) => {
-                    match segments.next() {
-                        None => {
-                            // this was the last path segment, return file
-                            return Ok(File::Fat(Open::File(file)));
-                        }
-                        Some(_) => {
-                            // there are more path segments to go, and a file
-                            // cannot possibly contain directory entries
-                            return Err(OpenError::NotFound);
-                        }
+            serial::get(port)
+                .map(File::Serial)
+                .map_err(|_| OpenError::NotFound)
+        }
+        _ => Err(OpenError::NotFound),
+    }
+}
+
+async fn open_fat(fat: &Fat16, path: &[u8]) -> Result<File, OpenError> {
+    let mut container = fat.root();
+    let mut segments = path.split(|b| *b == b'/');
+
+    // skip the leading slash:
+    segments.next();
+
+    loop {
+        let segment = match segments.next() {
+            None => {
+                return Ok(File::Fat(Open::Dir(container)));
+            }
+            Some(b"") => {
+                // ignore empty path segments
+                continue;
+            }
+            Some(segment) => segment,
+        };
+
+        let entry = container.entry(segment)
+            .await
+            .map_err(OpenError::Fat)?
+            .ok_or(OpenError::NotFound)?;
+
+        match entry.open() {
+            Ok(Open::Dir(dir)) => {
+                container = dir;
+            }
+            Ok(Open::File(file)) => {
+                match segments.next() {
+                    None => {
+                        // this was the last path segment, return file
+                        return Ok(File::Fat(Open::File(file)));
+                    }
+                    Some(_) => {
+                        // there are more path segments to go, and a file
+                        // cannot possibly contain directory entries
+                        return Err(OpenError::NotFound);
                     }
                 }
-                Err(e) => {
-                    return Err(OpenError::Fat(e));
+            }
+            Err(e) => {
+                return Err(OpenError::Fat(e));
+            }
+        }
+    }
+}
+
+async fn open_iso9660(iso: &Iso9660, path: &[u8]) -> Result<File, OpenError> {
+    let mut container = iso.root();
+    let mut segments = path.split(|b| *b == b'/');
+
+    // skip the leading slash:
+    segments.next();
+
+    loop {
+        let segment = match segments.next() {
+            None => {
+                return Ok(File::Iso9660(iso9660::Open::Dir(container)));
+            }
+            Some(b"") => {
+                // ignore empty path segments
+                continue;
+            }
+            Some(segment) => segment,
+        };
+
+        let entry = container.entry(segment)
+            .await
+            .map_err(OpenError::Iso)?
+            .ok_or(OpenError::NotFound)?;
+
+        match entry.open() {
+            iso9660::Open::Dir(dir) => {
+                container = dir;
+            }
+            iso9660::Open::File(file) => {
+                if segments.next().is_some() {
+                    // a file cannot possibly contain directory entries
+                    return Err(OpenError::NotFound);
                 }
+
+                return Ok(File::Iso9660(iso9660::Open::File(file)));
             }
         }
     }
@@ -88,22 +214,57 @@ impl Filesystem {
 #[derive(Debug)]
 pub enum File {
     Console,
+    Keyboard,
+    Mouse,
     Fat(Open),
+    Iso9660(iso9660::Open),
+    Serial(&'static SerialPort),
 }
 
 impl File {
     pub async fn read(&self, buf: &mut [u8]) -> SysResult<usize> {
         match self {
             File::Console => {
-                use crate::device::keyboard;
+                tty::read(buf).await
+            }
+            File::Keyboard => {
+                // an empty read would look like end of file:
+                if buf.len() < mem::size_of::<KeyEvent>() {
+                    return Err(SysError::IllegalValue);
+                }
+
+                let mut events = [KeyEvent::default(); 16];
+                let max = cmp::min(events.len(), buf.len() / mem::size_of::<KeyEvent>());
+
+                let count = keyboard::read_events(&mut events[..max]).await?;
+
+                let records = buf.chunks_exact_mut(mem::size_of::<KeyEvent>());
 
-                if buf.len() == 0 {
-                    return Ok(0);
+                for (event, out) in events[..count].iter().zip(records) {
+                    // user buffers need not be aligned:
+                    unsafe { ptr::write_unaligned(out.as_mut_ptr() as *mut KeyEvent, *event); }
                 }
 
-                let scancode = keyboard::read_scancode().await;
-                buf[0] = scancode;
-                Ok(1)
+                Ok(count * mem::size_of::<KeyEvent>())
+            }
+            File::Mouse => {
+                if buf.len() < mem::size_of::<MouseEvent>() {
+                    return Err(SysError::IllegalValue);
+                }
+
+                let mut events = [MouseEvent::default(); 16];
+                let max = cmp::min(events.len(), buf.len() / mem::size_of::<MouseEvent>());
+
+                let count = mouse::read_events(&mut events[..max]).await?;
+
+                let records = buf.chunks_exact_mut(mem::size_of::<MouseEvent>());
+
+                for (event, out) in events[..count].iter().zip(records) {
+                    // user buffers need not be aligned:
+                    unsafe { ptr::write_unaligned(out.as_mut_ptr() as *mut MouseEvent, *event); }
+                }
+
+                Ok(count * mem::size_of::<MouseEvent>())
             }
             File::Fat(Open::File(file)) => {
                 Ok(file.read(buf).await?)
@@ -111,6 +272,15 @@ impl File {
             File::Fat(Open::Dir(_)) => {
                 Err(SysError::InvalidOperation)
             }
+            File::Iso9660(iso9660::Open::File(file)) => {
+                Ok(file.read(buf).await?)
+            }
+            File::Iso9660(iso9660::Open::Dir(_)) => {
+                Err(SysError::InvalidOperation)
+            }
+            File::Serial(port) => {
+                Ok(port.read(buf).await?)
+            }
         }
     }
 
@@ -129,7 +299,47 @@ impl File {
 
                 Ok(buf.len())
             }
+            File::Keyboard | File::Mouse => {
+                Err(SysError::InvalidOperation)
+            }
             File::Fat(_) => { panic!() }
+            File::Iso9660(_) => {
+                // read only filesystem
+                Err(SysError::InvalidOperation)
+            }
+            File::Serial(port) => {
+                Ok(port.write(buf).await?)
+            }
+        }
+    }
+
+    /// Moves the read position of a file on disk to `position` bytes from
+    /// the start
+    pub async fn seek(&self, position: usize) -> SysResult<()> {
+        match self {
+            File::Fat(Open::File(file)) => {
+                Ok(file.seek(position).await?)
+            }
+            File::Iso9660(iso9660::Open::File(file)) => {
+                Ok(file.seek(position).await?)
+            }
+            _ => {
+                Err(SysError::InvalidOperation)
+            }
         }
     }
+
+    pub fn times(&self) -> SysResult<FileTimes> {
+        let dirent = match self {
+            File::Fat(Open::File(file)) => Some(file.dirent()),
+            File::Fat(Open::Dir(dir)) => dir.dirent(),
+            _ => return Err(SysError::InvalidOperation),
+        };
+
+        // the FAT root directory has no entry of its own to keep times in
+        Ok(dirent.map(|dirent| FileTimes {
+            created: dirent.created(),
+            modified: dirent.modified(),
+        }).unwrap_or_default())
+    }
 }
@@ -1,11 +1,143 @@
+use arrayvec::ArrayVec;
 use x86_64::instructions::port::Port;
 use x86_64::registers::control::Cr2;
 use x86_64::registers::rflags::RFlags;
 
-use crate::device::keyboard;
+use crate::critical;
+use crate::device::{apic, keyboard};
+use crate::smp;
+use crate::sync::{Arc, Mutex};
 use crate::task::{self, SEG_UCODE, SEG_UDATA};
+use crate::util::EarlyInit;
 
 pub const IRQ_BASE: u8 = 0x20;
+pub const IRQ_COUNT: usize = 0x18;
+
+/// Handlers registered on this line run whenever a timer set by
+/// time::arm_timer fires on any CPU, whether from the PIT or the local APIC
+pub const TIMER_IRQ: u8 = 0;
+
+// lines that exist on the 8259s, the rest need the IO-APIC
+const PIC_IRQ_COUNT: u8 = 0x10;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+const PIC_CASCADE_IRQ: u8 = 2;
+const PIC_EOI: u8 = 0x20;
+
+/// Implemented by drivers for devices that raise interrupts on lines which are
+/// only known at runtime, such as PCI devices. Interrupt lines may be shared
+/// so handlers must tolerate being called for interrupts raised by other
+/// devices.
+pub trait IrqHandler {
+    fn handle_irq(&self);
+}
+
+type IrqHandlers = ArrayVec<[Arc<dyn IrqHandler>; 4]>;
+
+static IRQ_HANDLERS: EarlyInit<Mutex<[IrqHandlers; IRQ_COUNT]>> = EarlyInit::new();
+
+#[derive(Debug)]
+pub enum RegisterIrqError {
+    BadIrq,
+    TooManyHandlers,
+}
+
+pub fn init() {
+    EarlyInit::set(&IRQ_HANDLERS, Mutex::new(Default::default()));
+}
+
+pub fn register_irq(irq: u8, handler: Arc<dyn IrqHandler>) -> Result<(), RegisterIrqError> {
+    {
+        let mut handlers = IRQ_HANDLERS.lock();
+
+        let handlers = handlers.get_mut(irq as usize)
+            .ok_or(RegisterIrqError::BadIrq)?;
+
+        handlers.try_push(handler)
+            .map_err(|_| RegisterIrqError::TooManyHandlers)?;
+    }
+
+    if irq == TIMER_IRQ && apic::is_enabled() {
+        // ticks come from the local APIC timer, the PIT line stays masked
+        return Ok(());
+    }
+
+    unmask_irq(irq)
+}
+
+pub fn unmask_irq(irq: u8) -> Result<(), RegisterIrqError> {
+    if apic::is_enabled() {
+        return apic::route_irq(irq);
+    }
+
+    if irq >= PIC_IRQ_COUNT {
+        return Err(RegisterIrqError::BadIrq);
+    }
+
+    critical::section(|| {
+        let (mut port, line) = if irq < 8 {
+            (Port::<u8>::new(PIC1_DATA), irq)
+        } else {
+            (Port::<u8>::new(PIC2_DATA), irq - 8)
+        };
+
+        unsafe {
+            let mask = port.read();
+            port.write(mask & !(1 << line));
+        }
+    });
+
+    if irq >= 8 {
+        // lines on the slave PIC need the cascade line open too
+        unmask_irq(PIC_CASCADE_IRQ)?;
+    }
+
+    Ok(())
+}
+
+fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::eoi();
+        return;
+    }
+
+    unsafe {
+        Port::<u8>::new(PIC1_COMMAND).write(PIC_EOI);
+
+        if irq >= 8 {
+            // irq from pic 2, send separate ack
+            Port::<u8>::new(PIC2_COMMAND).write(PIC_EOI);
+        }
+    }
+}
+
+fn timer_tick(frame: &mut TrapFrame) {
+    // handle and acknowledge first, the scheduler may idle waiting for other
+    // interrupts before it returns:
+    dispatch_irq_handlers(TIMER_IRQ);
+    end_of_interrupt(TIMER_IRQ);
+
+    // only switch tasks if this interrupt arrived from user mode. in the
+    // kernel, interrupt rearms the timer before going back to user mode:
+    if let TrapOrigin::User = frame.origin() {
+        unsafe { task::preempt(frame); }
+    }
+}
+
+fn dispatch_irq_handlers(irq: u8) {
+    // clone handlers out so that none of them run with the table locked:
+    let handlers = match IRQ_HANDLERS.lock().get(irq as usize) {
+        Some(handlers) => handlers.clone(),
+        None => return,
+    };
+
+    for handler in handlers.iter() {
+        handler.handle_irq();
+    }
+}
 
 macro_rules! interrupts {
     ($($vector:expr => $name:ident,)*) => {
@@ -21,7 +153,7 @@ macro_rules! interrupts {
                 match vector {
                     $($vector => Interrupt::$name,)*
                     _ => {
-                        if vector >= IRQ_BASE && vector < IRQ_BASE + 0x10 {
+                        if vector >= IRQ_BASE && vector < IRQ_BASE + IRQ_COUNT as u8 {
                             Interrupt::Irq(vector - IRQ_BASE)
                         } else {
                             Interrupt::Other(vector)
@@ -64,6 +196,9 @@ interrupts! {
     0x13 => SimdException,
     0x14 => VirtualizationException,
     0x1e => SecurityException,
+    0x40 => ApicTimer,
+    0x41 => Reschedule,
+    0x42 => TlbShootdown,
     0x7f => Syscall,
 }
 
@@ -149,42 +284,38 @@ pub extern "C" fn interrupt(frame: &mut TrapFrame) {
     x86_64::instructions::interrupts::enable();
 
     match frame.interrupt() {
+        Interrupt::Irq(TIMER_IRQ) => {
+            // PIT
+            timer_tick(frame);
+        }
+        Interrupt::ApicTimer => {
+            timer_tick(frame);
+        }
+        Interrupt::Reschedule => {
+            // only here to wake the CPU from hlt in task::switch
+            apic::eoi();
+        }
+        Interrupt::TlbShootdown => {
+            smp::flush_tlb_requested();
+            apic::eoi();
+        }
         Interrupt::Irq(irq) => {
-            let mut pic1 = Port::<u8>::new(0x20);
-            let mut pic2 = Port::<u8>::new(0xa0);
-
-            if irq == 0 {
-                // PIT
-
-                // only switch tasks if this interrupt arrived from user mode:
-                match frame.origin() {
-                    TrapOrigin::User => {
-                        unsafe { task::switch(frame); }
-                    }
-                    TrapOrigin::Kernel => {
-                        // do nothing
-                    }
-                }
-            }
-
-            if irq == 1 {
+            if irq == keyboard::IRQ {
                 // keyboard
                 unsafe { keyboard::interrupt(); }
             }
 
-            // acknowledge interupt:
-            unsafe { pic1.write(0x20); }
+            dispatch_irq_handlers(irq);
 
-            if irq >= 0x08 {
-                // irq from pic 2, send separate ack
-                unsafe { pic2.write(0x20); }
-            }
+            // acknowledge interupt:
+            end_of_interrupt(irq);
         }
         Interrupt::PageFault => {
             use crate::mem::fault::{fault, Flags};
 
-            let flags = Flags::from_bits(frame.error_code)
-                .expect("mem::fault::Flags::from_bits");
+            // the error code has bits we don't handle yet (reserved bit
+            // violations, protection keys, shadow stacks), drop them:
+            let flags = Flags::from_bits_truncate(frame.error_code);
 
             let address = Cr2::read().as_ptr();
 
@@ -207,4 +338,12 @@ pub extern "C" fn interrupt(frame: &mut TrapFrame) {
             panic!("CPU exception: {:?}", exception);
         }
     }
+
+    // a timer that fired while we were handling this found the kernel
+    // running and was dropped. arm it again with interrupts off until the
+    // iret, so the next one can only arrive from user mode:
+    if let TrapOrigin::User = frame.origin() {
+        x86_64::instructions::interrupts::disable();
+        task::rearm_timer();
+    }
 }
@@ -1,7 +1,9 @@
 bits 64
 
 global isrs_init
+global isrs_load
 global interrupt_return
+global pic_active
 extern panic
 extern interrupt
 
@@ -89,10 +91,28 @@ isrs_init:
     ENTRY 0x2d, irq13,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
     ENTRY 0x2e, irq14,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
     ENTRY 0x2f, irq15,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x30, irq16,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x31, irq17,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x32, irq18,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x33, irq19,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x34, irq20,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x35, irq21,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x36, irq22,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x37, irq23,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
+
+    ENTRY 0x40, lapic_timer,                SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x41, reschedule,                 SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x42, tlb_shootdown,              SEG_KCODE, IDT_PRESENT | IDT_INT64
 
     ENTRY 0x7f, syscall_,                   SEG_KCODE, IDT_PRESENT | IDT_INT64 | IDT_DPL3
 
-    ; load IDT
+    ; spurious interrupts from the 8259s once they are moved out of the way
+    ; for the APIC, and from the local APIC itself
+    ENTRY 0xf7, spurious,                   SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0xff, spurious,                   SEG_KCODE, IDT_PRESENT | IDT_INT64
+
+isrs_load:
+    ; load IDT, application processors share the one built above
     lidt [rel idtr]
     ret
 
@@ -119,10 +139,30 @@ DISPATCH_0 0x2c, irq12
 DISPATCH_0 0x2d, irq13
 DISPATCH_0 0x2e, irq14
 ; DISPATCH_0 0x2f, irq15
+; IRQs 16 and up only exist on the IO-APIC
+DISPATCH_0 0x30, irq16
+DISPATCH_0 0x31, irq17
+DISPATCH_0 0x32, irq18
+DISPATCH_0 0x33, irq19
+DISPATCH_0 0x34, irq20
+DISPATCH_0 0x35, irq21
+DISPATCH_0 0x36, irq22
+DISPATCH_0 0x37, irq23
+
+DISPATCH_0 0x40, lapic_timer
+DISPATCH_0 0x41, reschedule
+DISPATCH_0 0x42, tlb_shootdown
 
 DISPATCH_0 0x7f, syscall_
 
 interrupt_common:
+    ; user mode runs with the GS bases swapped, so that GS always points at
+    ; the per-CPU area in the kernel. see cpu.rs
+    test qword [rsp + 24], 3 ; code segment of the interrupted context
+    jz .from_kernel
+    swapgs
+.from_kernel:
+
     ; TODO - check SS and other seg regs
     ; do we need to fix up ds/es if coming from ring 3?
 
@@ -177,6 +217,11 @@ interrupt_return:
     ; pop interrupt vector and error code
     add rsp, 16
 
+    test qword [rsp + 8], 3 ; code segment we're returning to
+    jz .to_kernel
+    swapgs
+.to_kernel:
+
     ; TODO figure out other return stuff
     iretq
 
@@ -277,6 +322,9 @@ security_exception:
 
 ; LPT1/spurious
 irq7:
+    ; once the APIC has taken over, this vector is only raised by the IO-APIC
+    cmp byte [rel pic_active], 0
+    je .dispatch
     ; we need to test whether this was a genuine IRQ or spurious
     push ax
     mov al, 0x0b ; read in-service register from PIC1
@@ -287,6 +335,7 @@ irq7:
     jnc .spurious
     ; dispatch interrupt if legit
     pop ax
+.dispatch:
     push 0      ; error code - unused
     push 0x27   ; interrupt number
     jmp interrupt_common
@@ -297,6 +346,9 @@ irq7:
 
 ; ATA2/spurious
 irq15:
+    ; once the APIC has taken over, this vector is only raised by the IO-APIC
+    cmp byte [rel pic_active], 0
+    je .dispatch
     ; we need to test whether this was a genuine IRQ or spurious
     push ax
     mov al, 0x0b ; read in-service register from PIC2
@@ -307,6 +359,7 @@ irq15:
     jnc .spurious
     ; dispatch interrupt if legit
     pop ax
+.dispatch:
     push 0      ; error code - unused
     push 0x2f   ; interrupt number
     jmp interrupt_common
@@ -315,7 +368,14 @@ irq15:
     pop ax
     iretq
 
+; raised by an interrupt controller with nothing to deliver, needs no EOI
+spurious:
+    iretq
+
 section .data
+; cleared when interrupts are routed through the APIC instead of the 8259s
+pic_active db 1
+
 align 4
 idtr:
     dw IDT_SIZE - 1
@@ -0,0 +1,278 @@
+use core::fmt::{self, Write};
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+
+use arrayvec::{ArrayString, ArrayVec};
+use interface::LogRecordHeader;
+
+use crate::sync::Mutex;
+
+pub use interface::LogLevel as Level;
+
+const RECORD_COUNT: usize = 256;
+const MAX_FILTERS: usize = 16;
+
+// records are padded to this alignment in ReadLog buffers
+const RECORD_ALIGN: usize = 8;
+
+pub const DEFAULT_LEVEL: Level = Level::Info;
+
+type Message = ArrayString<[u8; 128]>;
+type ModuleName = ArrayString<[u8; 32]>;
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::record($level, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    seq: u64,
+    level: Level,
+    module: &'static str,
+    message: Message,
+}
+
+impl Record {
+    fn encoded_len(&self) -> usize {
+        let len = mem::size_of::<LogRecordHeader>() + self.module.len() + self.message.len();
+        (len + RECORD_ALIGN - 1) & !(RECORD_ALIGN - 1)
+    }
+}
+
+#[derive(Debug)]
+struct Filter {
+    module: ModuleName,
+    level: Level,
+}
+
+// Ring of the latest records. At around 40 KB it's too big to build on the
+// boot stack and move into place, so it's a static of its own that starts
+// out empty rather than part of Log.
+struct Records {
+    ring: [MaybeUninit<Record>; RECORD_COUNT],
+    // index of the oldest record
+    head: usize,
+    len: usize,
+    next_seq: u64,
+}
+
+impl Records {
+    const fn new() -> Self {
+        Records {
+            ring: [MaybeUninit::uninit(); RECORD_COUNT],
+            head: 0,
+            len: 0,
+            next_seq: 0,
+        }
+    }
+
+    /// Adds a record with the next sequence number, dropping the oldest if
+    /// the ring is full
+    fn push(&mut self, level: Level, module: &'static str, message: Message) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let index = (self.head + self.len) % RECORD_COUNT;
+        self.ring[index] = MaybeUninit::new(Record { seq, level, module, message });
+
+        if self.len < RECORD_COUNT {
+            self.len += 1;
+        } else {
+            self.head = (self.head + 1) % RECORD_COUNT;
+        }
+    }
+
+    /// Oldest first
+    fn iter(&self) -> impl Iterator<Item = &Record> {
+        // every slot in head..head + len has been written by push:
+        (0..self.len).map(move |offset| unsafe {
+            &*self.ring[(self.head + offset) % RECORD_COUNT].as_ptr()
+        })
+    }
+}
+
+struct Log {
+    filters: ArrayVec<[Filter; MAX_FILTERS]>,
+    default_level: Level,
+}
+
+impl Log {
+    fn level_for(&self, module: &str) -> Level {
+        // the most specific matching filter wins:
+        self.filters.iter()
+            .filter(|filter| module_matches(&filter.module, module))
+            .max_by_key(|filter| filter.module.len())
+            .map(|filter| filter.level)
+            .unwrap_or(self.default_level)
+    }
+}
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+static RECORDS: Mutex<Records> = Mutex::new(Records::new());
+
+/// Returns true if `module` is `prefix` or one of its submodules
+fn module_matches(prefix: &str, module: &str) -> bool {
+    if !module.starts_with(prefix) {
+        return false;
+    }
+
+    let rest = &module[prefix.len()..];
+    rest.is_empty() || rest.starts_with("::")
+}
+
+fn module_tag(module_path: &'static str) -> &'static str {
+    let tag = module_path.trim_start_matches("kernel::");
+
+    if tag.is_empty() { "kernel" } else { tag }
+}
+
+/// Formats into a fixed size buffer, silently dropping what doesn't fit
+struct Truncate<'a>(&'a mut Message);
+
+impl<'a> Write for Truncate<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.0.try_push(c).is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Safety: must not be called more than once
+pub unsafe fn init() {
+    *LOG.lock() = Some(Log {
+        filters: ArrayVec::new(),
+        default_level: DEFAULT_LEVEL,
+    });
+}
+
+pub fn record(level: Level, module_path: &'static str, args: fmt::Arguments) {
+    let module = module_tag(module_path);
+
+    let level_for_module = LOG.lock().as_ref().map(|log| log.level_for(module));
+
+    match level_for_module {
+        Some(level_for_module) => {
+            if level > level_for_module {
+                return;
+            }
+
+            let mut message = Message::new();
+            let _ = fmt::write(&mut Truncate(&mut message), args);
+
+            RECORDS.lock().push(level, module, message);
+        }
+        None => {
+            // not initialised yet, records still go to the console
+            if level > DEFAULT_LEVEL {
+                return;
+            }
+        }
+    }
+
+    crate::println!("[{:>5}] {}: {}", level.name(), module, args);
+}
+
+#[derive(Debug)]
+pub enum SetLevelError {
+    ModuleNameTooLong,
+    FilterTableFull,
+}
+
+/// Sets the level filter for `module` and its submodules. An empty module
+/// sets the default level for modules without a filter of their own.
+pub fn set_level(module: &str, level: Level) -> Result<(), SetLevelError> {
+    let mut log = LOG.lock();
+    let log = log.as_mut().expect("log to be initialized");
+
+    if module.is_empty() {
+        log.default_level = level;
+        return Ok(());
+    }
+
+    if let Some(filter) = log.filters.iter_mut().find(|filter| &filter.module[..] == module) {
+        filter.level = level;
+        return Ok(());
+    }
+
+    let module = ModuleName::from(module)
+        .map_err(|_| SetLevelError::ModuleNameTooLong)?;
+
+    log.filters.try_push(Filter { module, level })
+        .map_err(|_| SetLevelError::FilterTableFull)
+}
+
+/// Encodes records with sequence numbers from `start_seq` onwards into
+/// `buf`, returning the number of bytes used. Only whole records are
+/// written. If `start_seq` has already been overwritten, encoding starts at
+/// the oldest record still held.
+pub fn read(buf: &mut [u8], start_seq: u64) -> usize {
+    let records = RECORDS.lock();
+
+    let mut offset = 0;
+
+    for record in records.iter().filter(|record| record.seq >= start_seq) {
+        let len = record.encoded_len();
+
+        if offset + len > buf.len() {
+            break;
+        }
+
+        let header = LogRecordHeader {
+            seq: record.seq,
+            len: len as u16,
+            level: record.level as u8,
+            module_len: record.module.len() as u8,
+            message_len: record.message.len() as u16,
+            _reserved: 0,
+        };
+
+        let record_buf = &mut buf[offset..(offset + len)];
+        let (header_buf, rest) = record_buf.split_at_mut(mem::size_of::<LogRecordHeader>());
+        let (module_buf, rest) = rest.split_at_mut(record.module.len());
+        let (message_buf, padding) = rest.split_at_mut(record.message.len());
+
+        unsafe { ptr::write_unaligned(header_buf.as_mut_ptr() as *mut LogRecordHeader, header); }
+        module_buf.copy_from_slice(record.module.as_bytes());
+        message_buf.copy_from_slice(record.message.as_bytes());
+
+        for b in padding {
+            *b = 0;
+        }
+
+        offset += len;
+    }
+
+    offset
+}
@@ -18,26 +18,32 @@
 #[macro_use]
 extern crate kernel_derive;
 
+mod acpi;
 mod console;
+mod cpu;
 mod critical;
 mod device;
+mod elf;
 mod fs;
 mod interrupt;
+mod log;
 mod mem;
 mod object;
 mod panic;
+mod process;
+mod smp;
 mod sync;
 mod syscall;
 mod task;
+mod time;
+mod tty;
 mod util;
 
-use core::slice;
-
 use futures::future::{Future, FutureExt, OptionFuture};
 
-use fs::vfs::Filesystem;
+use fs::vfs::{Filesystem, OpenError, Volume};
 use interrupt::TrapFrame;
-use mem::page::{self, PageFlags, PAGE_SIZE};
+use mem::page;
 use mem::phys;
 use object::ObjectRef;
 use sync::Arc;
@@ -46,11 +52,113 @@ extern "C" {
     static mut _end: u8;
 }
 
+/// Opens the FAT16 filesystem on the first partition of the boot disk, if
+/// there is one
+async fn open_disk() -> Option<Volume> {
+    use device::block::{self, BlockDevice};
+    use device::ide::{self, Drive, DriveKind};
+    use device::mbr::Mbr;
+    use fs::fat16::Fat16;
+
+    let drive = match block::take() {
+        Some(drive) => drive,
+        None => {
+            let ide = ide::PRIMARY.open(Drive::A)
+                .expect("ide::open");
+
+            info!("detecting primary master...");
+
+            match ide.detect().await {
+                Ok(detect) if detect.kind() == DriveKind::Ata => {
+                    info!("---> {:?}", detect);
+                    BlockDevice::Ide(ide)
+                }
+                other => {
+                    info!("---> {:?}", other);
+                    return None;
+                }
+            }
+        }
+    };
+
+    let mbr = Mbr::open(drive)
+        .expect("Mbr::open");
+
+    let mut partitions = mbr.partitions().await
+        .expect("mbr.partitions");
+
+    for part in partitions.iter() {
+        if let Some(part) = part {
+            info!("#{} - {}, {}", part.number, part.lba, part.sectors);
+        }
+    }
+
+    let fat = Fat16::open(partitions.remove(0).expect("partitions[0]")).await
+        .expect("Fat16::open");
+
+    Some(Volume::Fat(fat))
+}
+
+/// Opens the ISO9660 filesystem on a CD in the secondary master drive, which
+/// is where `-cdrom` attaches it
+async fn open_cdrom() -> Option<Volume> {
+    use device::ide::{self, Drive, DriveKind};
+    use fs::iso9660::Iso9660;
+
+    let ide = ide::SECONDARY.open(Drive::A)
+        .expect("ide::open");
+
+    info!("detecting secondary master...");
+
+    match ide.detect().await {
+        Ok(detect) if detect.kind() == DriveKind::Atapi => {
+            info!("---> {:?}", detect);
+        }
+        other => {
+            info!("---> {:?}", other);
+            return None;
+        }
+    }
+
+    match Iso9660::open(ide.into_atapi()).await {
+        Ok(iso) => Some(Volume::Iso9660(iso)),
+        Err(e) => {
+            warn!("could not open CD: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Mounts the boot disk at / with any CD at /cdrom, or the CD at / when
+/// booting from CD alone
+async fn mount_filesystem() -> Filesystem {
+    let disk = open_disk().await;
+    let cdrom = open_cdrom().await;
+
+    match (disk, cdrom) {
+        (Some(disk), cdrom) => {
+            let mut filesystem = Filesystem::new(disk);
+
+            if let Some(cdrom) = cdrom {
+                filesystem.mount(b"/cdrom", cdrom)
+                    .expect("filesystem.mount");
+            }
+
+            filesystem
+        }
+        (None, Some(cdrom)) => Filesystem::new(cdrom),
+        (None, None) => panic!("no boot disk or CD found"),
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn main() -> ! {
     unsafe {
         let crit = critical::begin();
 
+        // start recording log messages
+        log::init();
+
         // perform follow up init for phys allocator
         phys::init_ref_counts(&crit);
 
@@ -60,87 +168,91 @@ pub extern "C" fn main() -> ! {
         // init object space
         object::init();
 
+        // locate ACPI tables
+        acpi::init();
+
+        // init irq handler table
+        interrupt::init();
+
         // init pit
         device::pit::init();
 
+        // switch from the 8259 PICs to the APIC, if there is one
+        device::apic::init();
+
+        // set up the per-CPU area for the bootstrap processor
+        cpu::init_bsp(device::apic::id());
+
+        // init monotonic clock and timers
+        time::init();
+
         // init keyboard
         device::keyboard::init();
-    }
 
-    task::init();
+        // init console line discipline
+        tty::init();
 
-    unsafe {
-        let page_ctx = ObjectRef::new(page::current_ctx())
-            .expect("ObjectRef::new");
+        // init serial ports
+        device::serial::init();
 
-        task::spawn(page_ctx, None, |task| async move {
-            use device::ide::{self, Drive};
-            use device::mbr::Mbr;
-            use fs::fat16::{Open, Fat16, DirEntry};
+        // init mouse
+        device::mouse::init();
 
-            let ide = ide::PRIMARY.open(Drive::A)
-                .expect("ide::open");
+        // enumerate pci bus
+        device::pci::init();
 
-            println!("detecting primary master...");
-            println!("---> {:?}", ide.detect().await);
+        // probe pci device drivers
+        device::virtio_blk::init();
+        device::ahci::init();
+        device::ide::init();
+    }
 
-            let mbr = Mbr::open(ide)
-                .expect("Mbr::open");
+    task::init();
 
-            let mut partitions = mbr.partitions().await
-                .expect("mbr.partitions");
+    unsafe {
+        // bring up the application processors
+        smp::init();
 
-            for part in partitions.iter() {
-                if let Some(part) = part {
-                    crate::println!("#{} - {}, {}", part.number, part.lba, part.sectors);
-                }
-            }
+        let page_ctx = ObjectRef::new(page::current_ctx())
+            .expect("ObjectRef::new");
 
-            let fat = Fat16::open(partitions.remove(0).expect("partitions[0]")).await
-                .expect("Fat16::open");
+        task::spawn(page_ctx, None, |task| async move {
+            let filesystem = mount_filesystem().await;
 
             // find init:
-            let entry = fat.root().entry(b"init.bin")
-                .await
-                .expect("entry")
-                .map(|entry| entry.open().expect("open"));
-
-            let mut init = match entry {
-                Some(Open::File(init)) => init,
-                Some(Open::Dir(_)) => {
-                    panic!("/init.bin is directory");
-                }
-                None => {
+            let init = match filesystem.open(b"/init.bin").await {
+                Ok(init) => init,
+                Err(OpenError::NotFound) => {
                     panic!("/init.bin does not exist");
                 }
+                Err(e) => {
+                    panic!("could not open /init.bin: {:?}", e);
+                }
             };
 
-            let filesystem = Filesystem::new(fat);
             task::set_filesystem(Some(Arc::new(filesystem)
                 .expect("Arc::new")));
 
-            // setup init task
-            let mut addr = 0x1000_0000 as *mut u8;
-            let mut task = task.setup(TrapFrame::new(addr as u64, 0x0));
+            // load init into userspace
+            let image = match elf::load(&init).await {
+                Ok(image) => image,
+                Err(e) => panic!("could not load /init.bin: {:?}", e),
+            };
 
-            // read init into userspace
-            loop {
-                let phys = phys::alloc()
-                    .expect("phys::alloc");
+            let stack_top = mem::user::alloc_stack(0)
+                .expect("user::alloc_stack");
 
-                page::map(phys, addr, PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER)
-                    .expect("page::map");
+            // init holds just the console, see below
+            let rsp = {
+                let mut args = process::Args::new();
 
-                let read = init.read(slice::from_raw_parts_mut(addr, PAGE_SIZE))
-                    .await
-                    .expect("init.read");
+                args.push_arg(b"/init.bin")
+                    .expect("args.push_arg");
 
-                addr = addr.add(read);
+                process::push_start_info(&image, stack_top, &args, 1)
+            };
 
-                if read < PAGE_SIZE {
-                    break;
-                }
-            }
+            let mut task = task.setup(TrapFrame::new(image.entry, rsp));
 
             // set up initial console object
             let console = ObjectRef::new(crate::fs::File::Console)
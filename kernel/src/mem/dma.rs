@@ -0,0 +1,67 @@
+use core::ptr::NonNull;
+
+use crate::mem::{kvirt, MemoryExhausted};
+use crate::mem::page::{PageFlags, PAGE_SIZE};
+use crate::mem::phys::{self, Phys, RawPhys};
+
+/// A physically contiguous, kernel mapped region of memory suitable for
+/// handing to bus mastering devices. DMA regions are allocated by drivers at
+/// init time and live for the lifetime of the kernel.
+#[derive(Debug)]
+pub struct DmaRegion {
+    virt: NonNull<u8>,
+    phys: RawPhys,
+    len: usize,
+}
+
+// the first 4 GiB, all that devices limited to 32 bit addresses can reach
+const LIMIT_32BIT: u64 = 0x1_0000_0000;
+
+impl DmaRegion {
+    pub fn alloc(page_count: usize) -> Result<Self, MemoryExhausted> {
+        Self::alloc_below(page_count, u64::max_value())
+    }
+
+    /// Like alloc, but the region lies entirely in the first 4 GiB so that
+    /// its physical addresses fit in 32 bits
+    pub fn alloc_32bit(page_count: usize) -> Result<Self, MemoryExhausted> {
+        Self::alloc_below(page_count, LIMIT_32BIT)
+    }
+
+    fn alloc_below(page_count: usize, limit: u64) -> Result<Self, MemoryExhausted> {
+        let phys = phys::alloc_contiguous_below(page_count, limit)?;
+
+        let virt = unsafe {
+            kvirt::map_physical(phys, page_count,
+                PageFlags::PRESENT | PageFlags::WRITE | PageFlags::CACHE_DISABLED)
+        };
+
+        // the kernel mapping now holds a reference to each page, so release
+        // the one we were handed by the allocator. if mapping failed that
+        // frees them again:
+        for index in 0..page_count {
+            unsafe { Phys::from_raw(RawPhys(phys.0 + (index * PAGE_SIZE) as u64)); }
+        }
+
+        Ok(DmaRegion { virt: virt?, phys, len: page_count * PAGE_SIZE })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns a pointer to the byte at `offset` into the region
+    pub fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset < self.len, "offset out of bounds for DmaRegion");
+        unsafe { self.virt.as_ptr().add(offset) as *mut T }
+    }
+
+    /// Returns the physical address of the byte at `offset` into the region
+    pub fn phys(&self, offset: usize) -> u64 {
+        assert!(offset < self.len, "offset out of bounds for DmaRegion");
+        self.phys.0 + offset as u64
+    }
+}
+
+unsafe impl Send for DmaRegion {}
+unsafe impl Sync for DmaRegion {}
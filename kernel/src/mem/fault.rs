@@ -1,4 +1,8 @@
-use crate::interrupt::TrapFrame;
+use crate::critical;
+use crate::interrupt::{TrapFrame, TrapOrigin};
+use crate::mem::page::{self, CommitError, CopyError};
+use crate::mem::user;
+use crate::task;
 
 use bitflags::bitflags;
 
@@ -7,6 +11,8 @@ bitflags! {
         const PRESENT   = 0x001;
         const WRITE     = 0x002;
         const USER      = 0x004;
+        // set when executing from a NO_EXECUTE page
+        const INSTRUCTION_FETCH = 0x010;
     }
 }
 
@@ -15,9 +21,48 @@ extern {
     static _bss_end: u8;
 }
 
-pub fn fault(frame: &TrapFrame, flags: Flags, address: *const u8) {
-    panic!("Page fault! rip: {:x?}, address: {:?}, flags: {:?}",
-        frame.rip,
-        address,
-        flags);
+pub fn fault(frame: &mut TrapFrame, flags: Flags, address: *const u8) {
+    let reason = if !flags.contains(Flags::PRESENT) {
+        // the first touch of a reserved page:
+        match unsafe { page::commit_reserved(address as *mut u8) } {
+            Ok(()) => return,
+            Err(CommitError::MemoryExhausted) => "out of memory",
+            Err(CommitError::NotReserved) => {
+                if user::is_guard(address as u64, &critical::begin()) {
+                    "stack overflow"
+                } else {
+                    "page fault"
+                }
+            }
+        }
+    } else if flags.contains(Flags::WRITE) {
+        // a write to memory shared with another page context:
+        match unsafe { page::copy_on_write(address as *mut u8) } {
+            Ok(()) => return,
+            Err(CopyError::MemoryExhausted) => "out of memory",
+            Err(CopyError::NotCopyOnWrite) => "page fault",
+        }
+    } else {
+        "page fault"
+    };
+
+    match frame.origin() {
+        TrapOrigin::User => {
+            crate::warn!("killing task {:?}: {}! rip: {:x?}, address: {:?}, flags: {:?}",
+                task::current(),
+                reason,
+                frame.rip,
+                address,
+                flags);
+
+            unsafe { task::kill_current(frame); }
+        }
+        TrapOrigin::Kernel => {
+            panic!("Page fault! {}, rip: {:x?}, address: {:?}, flags: {:?}",
+                reason,
+                frame.rip,
+                address,
+                flags);
+        }
+    }
 }
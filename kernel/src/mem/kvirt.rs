@@ -2,7 +2,7 @@ use core::ops::{Deref, DerefMut};
 
 use crate::mem::MemoryExhausted;
 use crate::mem::page::{self, PageFlags, MapError, PAGE_SIZE};
-use crate::mem::phys;
+use crate::mem::phys::{self, Phys, RawPhys};
 use crate::sync::Mutex;
 
 use core::ptr::{self, NonNull};
@@ -24,6 +24,22 @@ pub unsafe fn free_page<T: PageSized>(page: NonNull<T>) {
     ALLOCATOR.free(page.cast())
 }
 
+/// Allocates a kernel stack of `page_count` pages, returning its top. The
+/// page below is left unmapped, so an overflow faults rather than running
+/// into whatever was allocated before it.
+pub fn alloc_stack(page_count: usize) -> Result<NonNull<u8>, MemoryExhausted> {
+    ALLOCATOR.alloc_stack(page_count)
+}
+
+/// Maps `page_count` pages of physical memory starting at `phys` into kernel
+/// virtual space. This is intended for MMIO regions and firmware tables, and
+/// the mapping lives for the lifetime of the kernel.
+pub unsafe fn map_physical(phys: RawPhys, page_count: usize, flags: PageFlags)
+    -> Result<NonNull<u8>, MemoryExhausted>
+{
+    ALLOCATOR.map_physical(phys, page_count, flags)
+}
+
 struct PageAllocator {
     inner: Mutex<PageAllocatorInner>,
 }
@@ -66,12 +82,7 @@ impl PageAllocator {
         let phys = phys::alloc()?;
 
         unsafe {
-            let ptr = {
-                let mut inner = self.inner.lock();
-                let ptr = inner.ptr;
-                inner.ptr = inner.ptr.add(PAGE_SIZE);
-                ptr
-            };
+            let ptr = self.reserve(1);
 
             match page::map(phys, ptr, PageFlags::PRESENT | PageFlags::WRITE) {
                 Ok(()) => {}
@@ -83,6 +94,59 @@ impl PageAllocator {
         }
     }
 
+    fn reserve(&self, page_count: usize) -> *mut u8 {
+        let mut inner = self.inner.lock();
+        let ptr = inner.ptr;
+        inner.ptr = unsafe { inner.ptr.add(PAGE_SIZE * page_count) };
+        ptr
+    }
+
+    pub fn alloc_stack(&self, page_count: usize) -> Result<NonNull<u8>, MemoryExhausted> {
+        unsafe {
+            let guard = self.reserve(page_count + 1);
+            let base = guard.add(PAGE_SIZE);
+
+            for index in 0..page_count {
+                let phys = phys::alloc()?;
+
+                match page::map(phys, base.add(index * PAGE_SIZE), PageFlags::PRESENT | PageFlags::WRITE) {
+                    Ok(()) => {}
+                    Err(MapError::CannotAllocatePageTable) => return Err(MemoryExhausted),
+                    Err(MapError::AlreadyMapped) => panic!("MapError::AlreadyMapped in PageAllocator::alloc_stack"),
+                }
+            }
+
+            Ok(NonNull::new_unchecked(base.add(page_count * PAGE_SIZE)))
+        }
+    }
+
+    pub unsafe fn map_physical(&self, phys: RawPhys, page_count: usize, flags: PageFlags)
+        -> Result<NonNull<u8>, MemoryExhausted>
+    {
+        let ptr = self.reserve(page_count);
+
+        for index in 0..page_count {
+            let phys = Phys::new(RawPhys(phys.0 + (index * PAGE_SIZE) as u64));
+
+            match page::map(phys, ptr.add(index * PAGE_SIZE), flags) {
+                Ok(()) => {}
+                Err(MapError::CannotAllocatePageTable) => {
+                    // let go of the pages mapped so far, the caller still
+                    // owns its own references:
+                    for mapped in 0..index {
+                        page::unmap(ptr.add(mapped * PAGE_SIZE))
+                            .expect("page::unmap in PageAllocator::map_physical");
+                    }
+
+                    return Err(MemoryExhausted);
+                }
+                Err(MapError::AlreadyMapped) => panic!("MapError::AlreadyMapped in PageAllocator::map_physical"),
+            }
+        }
+
+        Ok(NonNull::new_unchecked(ptr))
+    }
+
     pub unsafe fn free(&self, page: NonNull<u8>) {
         let mut inner = self.inner.lock();
 
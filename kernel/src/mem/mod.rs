@@ -2,6 +2,7 @@ use core::ptr;
 
 use interface::SysError;
 
+pub mod dma;
 pub mod fault;
 pub mod kalloc;
 pub mod kvirt;
@@ -1,20 +1,28 @@
+use core::mem;
+use core::ops::Range;
 use core::ptr;
+use core::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use bitflags::bitflags;
 use x86_64::registers::control::Cr3;
 
+use crate::cpu;
 use crate::critical::{self, Critical};
 use crate::mem::MemoryExhausted;
 use crate::mem::phys::{self, Phys, RawPhys};
+use crate::smp;
 
 pub const PAGE_SIZE: usize = 0x1000;
 
+// flag bits in a page table entry, the rest is the physical address
+const FLAGS_MASK: u64 = 0xfff0_0000_0000_0fff;
+
 #[repr(transparent)]
 pub struct PmlEntry(pub u64);
 
 impl PmlEntry {
     fn raw_phys(&self) -> Option<RawPhys> {
-        let raw = self.0 & !0xfff;
+        let raw = self.0 & !FLAGS_MASK;
 
         if raw != 0 {
             Some(RawPhys(raw))
@@ -24,11 +32,11 @@ impl PmlEntry {
     }
 
     pub fn flags(&self) -> PageFlags {
-        PageFlags::from_bits(self.0 & 0xfff).expect("PageFlags::from_bits in PmlEntry::flags")
+        PageFlags::from_bits(self.0 & FLAGS_MASK).expect("PageFlags::from_bits in PmlEntry::flags")
     }
 
     pub fn set_flags(&mut self, flags: PageFlags) {
-        let new_entry = (self.0 & !0xfff) | flags.bits();
+        let new_entry = (self.0 & !FLAGS_MASK) | flags.bits();
         self.0 = new_entry;
     }
 }
@@ -43,6 +51,20 @@ bitflags! {
         const ACCESSED          = 0x020;
         const DIRTY             = 0x040;
         const GLOBAL            = 0x080;
+        // ignored by the CPU, only ever set on entries without PRESENT. marks
+        // the page below a user stack, see user::alloc_stack
+        const GUARD             = 0x200;
+        // likewise never with PRESENT. marks a page reserved to be allocated
+        // on first access, holding the flags it gets then
+        const RESERVED          = 0x400;
+        // on present entries without WRITE, for pages shared between page
+        // contexts by copy_current_ctx until one of them writes
+        const COPY_ON_WRITE     = 0x800;
+        // ignored by the CPU. pages of a memory object, which stay shared
+        // rather than copy-on-write in copy_current_ctx
+        const SHARED            = 0x0010_0000_0000_0000;
+        // needs EFER.NXE, which start.asm sets
+        const NO_EXECUTE        = 0x8000_0000_0000_0000;
     }
 }
 
@@ -69,6 +91,24 @@ impl PageCtx {
         let pml4 = unsafe { Phys::from_raw(pml4_raw) };
         Ok(PageCtx { pml4 })
     }
+
+    /// Consumes the context, returning the physical address of its PML4
+    /// without releasing the reference held on it
+    pub fn into_raw(self) -> RawPhys {
+        let raw = self.pml4.raw();
+        mem::forget(self);
+        raw
+    }
+}
+
+impl Drop for PageCtx {
+    fn drop(&mut self) {
+        // a loaded context holds a reference through CR3, so if ours is the
+        // last one nothing can be running in it anymore:
+        if phys::references(self.pml4.raw()) == Some(1) {
+            unsafe { release_user_half(self.pml4.raw()); }
+        }
+    }
 }
 
 pub unsafe fn init_kernel_pml4_entries(_crit: &Critical) {
@@ -97,11 +137,12 @@ pub unsafe fn set_ctx(ctx: PageCtx) {
     let old_cr3;
     asm!("movq %cr3, $0" : "=r"(old_cr3));
 
-    let new_cr3 = ctx.pml4.into_raw();
+    let new_cr3 = ctx.into_raw();
     asm!("movq $0, %cr3" :: "r"(new_cr3));
 
-    // ensure we decrement the ref count of the old previous cr3
-    Phys::from_raw(old_cr3);
+    // ensure we decrement the ref count of the old previous cr3, tearing it
+    // down if that was the last one
+    drop(PageCtx { pml4: Phys::from_raw(old_cr3) });
 }
 
 const CURRENT_PML: u64 = 0xffffff8000000000;
@@ -172,10 +213,24 @@ extern "C" {
     static mut temp_page: u8;
 }
 
+// every CPU shares the one temp page
+static TEMP_LOCKED: AtomicBool = AtomicBool::new(false);
+// id + 1 of the CPU using the temp page
+static TEMP_OWNER: AtomicUsize = AtomicUsize::new(0);
+
 pub fn invlpg(virt: *mut u8) {
     unsafe { asm!("invlpg ($0)" :: "r"(virt) : "memory" : "volatile"); }
 }
 
+/// Invalidates every non-global page in this CPU's TLB by reloading CR3
+pub fn flush_all() {
+    unsafe {
+        let cr3: u64;
+        asm!("movq %cr3, $0" : "=r"(cr3));
+        asm!("movq $0, %cr3" :: "r"(cr3) : "memory" : "volatile");
+    }
+}
+
 pub struct TempMap<'a, T> {
     ptr: *mut T,
     _critical: &'a Critical,
@@ -190,6 +245,9 @@ impl<'a, T> TempMap<'a, T> {
 impl<'a, T> Drop for TempMap<'a, T> {
     fn drop(&mut self) {
         unsafe { temp_reset(); }
+
+        TEMP_OWNER.store(0, Ordering::Relaxed);
+        TEMP_LOCKED.store(false, Ordering::Release);
     }
 }
 
@@ -205,10 +263,17 @@ pub unsafe fn temp_map<'a, T>(phys: RawPhys, critical: &'a Critical) -> TempMap<
     let virt = &mut temp_page as *mut u8;
     let entry = pml1_entry(CURRENT_PML, virt as u64);
 
-    if (*entry).0 != 0 {
+    if TEMP_OWNER.load(Ordering::Relaxed) == cpu::id() + 1 {
         panic!("temp page already mapped");
     }
 
+    while TEMP_LOCKED.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        smp::flush_tlb_requested();
+        atomic::spin_loop_hint();
+    }
+
+    TEMP_OWNER.store(cpu::id() + 1, Ordering::Relaxed);
+
     *entry = PmlEntry(phys.0 | (PageFlags::PRESENT | PageFlags::WRITE).bits());
     invlpg(virt);
 
@@ -254,6 +319,23 @@ pub fn is_mapped(virt: *const u8) -> bool {
 }
 
 pub unsafe fn map(phys: Phys, virt: *mut u8, flags: PageFlags) -> Result<(), MapError> {
+    map_entry(virt, || PmlEntry(phys.into_raw().0 | flags.bits()))
+}
+
+/// Reserves a page as a guard, which faults on any access and can't be
+/// mapped over
+pub unsafe fn map_guard(virt: *mut u8) -> Result<(), MapError> {
+    map_entry(virt, || PmlEntry(PageFlags::GUARD.bits()))
+}
+
+/// Reserves a page to be backed by memory with `flags` the first time it's
+/// touched, see commit_reserved
+pub unsafe fn reserve(virt: *mut u8, flags: PageFlags) -> Result<(), MapError> {
+    let flags = (flags - PageFlags::PRESENT) | PageFlags::RESERVED;
+    map_entry(virt, || PmlEntry(flags.bits()))
+}
+
+unsafe fn map_entry(virt: *mut u8, entry: impl FnOnce() -> PmlEntry) -> Result<(), MapError> {
     critical::section(|| {
         let virt = virt as u64;
 
@@ -295,7 +377,7 @@ pub unsafe fn map(phys: Phys, virt: *mut u8, flags: PageFlags) -> Result<(), Map
             return Err(MapError::AlreadyMapped);
         }
 
-        *pml1_ent = PmlEntry(phys.into_raw().0 | flags.bits());
+        *pml1_ent = entry();
         invlpg(virt as *mut u8);
 
         Ok(())
@@ -353,10 +435,18 @@ pub unsafe fn unmap(virt: *mut u8) -> Result<(), NotMapped> {
 
     match (*pml1_ent).raw_phys() {
         Some(raw_phys) => {
-            // ensure we decrement the ref count of the physical page:
-            Phys::from_raw(raw_phys);
             *pml1_ent = PmlEntry(0);
             invlpg(virt as *mut u8);
+            smp::flush_tlb(virt);
+
+            // ensure we decrement the ref count of the physical page, only
+            // once no CPU can reach it through a stale TLB entry:
+            Phys::from_raw(raw_phys);
+            Ok(())
+        }
+        None if (*pml1_ent).flags().contains(PageFlags::RESERVED) => {
+            // never touched, so there's nothing to release:
+            *pml1_ent = PmlEntry(0);
             Ok(())
         }
         None => {
@@ -365,11 +455,288 @@ pub unsafe fn unmap(virt: *mut u8) -> Result<(), NotMapped> {
     }
 }
 
+#[derive(Debug)]
+pub enum CommitError {
+    NotReserved,
+    MemoryExhausted,
+}
+
+/// Backs a page left by reserve with a zeroed page of memory. Succeeds
+/// without doing anything if it's already backed, as happens when tasks on
+/// two CPUs fault on the same page at once.
+pub unsafe fn commit_reserved(virt: *mut u8) -> Result<(), CommitError> {
+    let crit = critical::begin();
+
+    let pml1_ent = checked_pml1_entry(CURRENT_PML, virt, &crit)
+        .map_err(|_| CommitError::NotReserved)?;
+
+    // other CPUs may be committing the same page:
+    let pml1_ent = &*(pml1_ent as *const AtomicU64);
+    let entry = pml1_ent.load(Ordering::SeqCst);
+    let flags = PmlEntry(entry).flags();
+
+    if flags.contains(PageFlags::PRESENT) {
+        return Ok(());
+    }
+
+    if !flags.contains(PageFlags::RESERVED) {
+        return Err(CommitError::NotReserved);
+    }
+
+    let raw_phys = phys::alloc()
+        .map_err(|_| CommitError::MemoryExhausted)?
+        .into_raw();
+
+    let flags = (flags - PageFlags::RESERVED) | PageFlags::PRESENT;
+
+    match pml1_ent.compare_exchange(entry, raw_phys.0 | flags.bits(), Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => {
+            invlpg(virt);
+        }
+        Err(_) => {
+            // lost the race, release the page we allocated:
+            Phys::from_raw(raw_phys);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum CopyError {
+    NotCopyOnWrite,
+    MemoryExhausted,
+}
+
+/// Gives the current page context its own writable copy of a page it shares
+/// copy-on-write, or just makes it writable if nothing else references it
+/// anymore. Succeeds without doing anything if another CPU got there first.
+pub unsafe fn copy_on_write(virt: *mut u8) -> Result<(), CopyError> {
+    let crit = critical::begin();
+
+    let pml1_ent = checked_pml1_entry(CURRENT_PML, virt, &crit)
+        .map_err(|_| CopyError::NotCopyOnWrite)?;
+
+    let pml1_ent = &*(pml1_ent as *const AtomicU64);
+    let entry = pml1_ent.load(Ordering::SeqCst);
+    let flags = PmlEntry(entry).flags();
+
+    if !flags.contains(PageFlags::COPY_ON_WRITE) {
+        return if flags.contains(PageFlags::PRESENT | PageFlags::WRITE) {
+            Ok(())
+        } else {
+            Err(CopyError::NotCopyOnWrite)
+        };
+    }
+
+    let shared = PmlEntry(entry).raw_phys()
+        .expect("copy-on-write page with no phys");
+
+    let flags = (flags - PageFlags::COPY_ON_WRITE) | PageFlags::WRITE;
+
+    if phys::references(shared) == Some(1) {
+        // the other contexts are done with it, take it over. CPUs with the
+        // read-only entry cached fault once more and find it writable:
+        if pml1_ent.compare_exchange(entry, shared.0 | flags.bits(), Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            invlpg(virt);
+        }
+
+        return Ok(());
+    }
+
+    let copy = phys::alloc()
+        .map_err(|_| CopyError::MemoryExhausted)?
+        .into_raw();
+
+    {
+        let page = (virt as u64 & !(PAGE_SIZE as u64 - 1)) as *const u8;
+        let mapped = temp_map::<u8>(copy, &crit);
+        ptr::copy_nonoverlapping(page, mapped.ptr(), PAGE_SIZE);
+    }
+
+    match pml1_ent.compare_exchange(entry, copy.0 | flags.bits(), Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => {
+            invlpg(virt);
+            smp::flush_tlb(virt);
+
+            // drop this context's reference on the shared page:
+            Phys::from_raw(shared);
+        }
+        Err(_) => {
+            Phys::from_raw(copy);
+        }
+    }
+
+    Ok(())
+}
+
+// Bases of each level of page table in the recursive mapping, pml1 first
+const TABLE_BASES: [u64; 4] = [
+    0xffffff8000000000,
+    0xffffffffc0000000,
+    0xffffffffffe00000,
+    0xfffffffffffff000,
+];
+
+// Returns the entry to give the new context for a page in the current one,
+// marking writable memory copy-on-write in both
+unsafe fn share_page(entry: &mut PmlEntry) -> PmlEntry {
+    let flags = entry.flags();
+
+    let raw_phys = match entry.raw_phys() {
+        // reserved and guard pages are copied as they are
+        None => return PmlEntry(entry.0),
+        Some(raw_phys) => raw_phys,
+    };
+
+    // the new context's reference:
+    Phys::new(raw_phys).into_raw();
+
+    // memory mapped hardware isn't counted and stays shared, as do memory
+    // objects:
+    let private = phys::references(raw_phys).is_some() && !flags.contains(PageFlags::SHARED);
+
+    if flags.contains(PageFlags::WRITE) && private {
+        let flags = (flags - PageFlags::WRITE) | PageFlags::COPY_ON_WRITE;
+
+        // the caller flushes the TLB once it's done with every page
+        entry.set_flags(flags);
+
+        PmlEntry(raw_phys.0 | flags.bits())
+    } else {
+        PmlEntry(entry.0)
+    }
+}
+
+// Fills `new_table` with a copy of the table in the current context at
+// `level`, found by the indexes of the tables above it in `prefix`
+unsafe fn copy_table(level: usize, prefix: u64, new_table: RawPhys, entries: Range<u64>, crit: &Critical)
+    -> Result<(), MemoryExhausted>
+{
+    let table = (TABLE_BASES[level] as *mut PmlEntry).add((prefix << 9) as usize);
+
+    for index in entries {
+        let entry = &mut *table.add(index as usize);
+
+        if entry.0 == 0 {
+            continue;
+        }
+
+        if level == 0 {
+            let copy = share_page(entry);
+
+            let mapped = temp_map::<PmlEntry>(new_table, crit);
+            *mapped.ptr().add(index as usize) = copy;
+        } else {
+            let child = phys::alloc()?.into_raw();
+
+            // linked in before filling it so that if we run out of memory
+            // part way, dropping the new context still finds it:
+            {
+                let mapped = temp_map::<PmlEntry>(new_table, crit);
+                *mapped.ptr().add(index as usize) = PmlEntry(child.0 | entry.flags().bits());
+            }
+
+            copy_table(level - 1, (prefix << 9) | index, child, 0..512, crit)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Releases every page and page table in the user half of the context with
+// PML4 `pml4`, which must not be loaded on any CPU
+unsafe fn release_user_half(pml4: RawPhys) {
+    let _crit = critical::begin();
+
+    // borrow the context just long enough to reach its tables through the
+    // recursive mapping, the references held by CR3 stay as they are:
+    let old_cr3: u64;
+    asm!("movq %cr3, $0" : "=r"(old_cr3));
+    asm!("movq $0, %cr3" :: "r"(pml4.0) : "memory" : "volatile");
+
+    for index in 0..256 {
+        release_entry(3, 0, index);
+    }
+
+    asm!("movq $0, %cr3" :: "r"(old_cr3) : "memory" : "volatile");
+}
+
+// Clears an entry of the table at `level` in the current context, releasing
+// the page it points to and, if that's a table, everything under it first
+unsafe fn release_entry(level: usize, prefix: u64, index: u64) {
+    let entry = &mut *(TABLE_BASES[level] as *mut PmlEntry).add(((prefix << 9) | index) as usize);
+
+    if entry.0 == 0 {
+        return;
+    }
+
+    if level > 0 {
+        for child in 0..512 {
+            release_entry(level - 1, (prefix << 9) | index, child);
+        }
+    }
+
+    let raw_phys = entry.raw_phys();
+    *entry = PmlEntry(0);
+
+    if let Some(raw_phys) = raw_phys {
+        Phys::from_raw(raw_phys);
+    }
+}
+
+/// Creates a page context with the user half of the current one, sharing
+/// its memory copy-on-write
+pub fn copy_current_ctx() -> Result<PageCtx, MemoryExhausted> {
+    let ctx = PageCtx::new()?;
+
+    let result = unsafe {
+        let crit = critical::begin();
+
+        // the lower half of the PML4 maps user space:
+        copy_table(3, 0, ctx.pml4.raw(), 0..256, &crit)
+    };
+
+    // pages made copy-on-write may still be writable in TLBs, even if we
+    // ran out of memory part way:
+    flush_all();
+    smp::flush_tlb_all();
+
+    result.map(|()| ctx)
+}
+
+/// Removes a guard page left by map_guard, if there is one
+pub unsafe fn unmap_guard(virt: *mut u8) {
+    let crit = critical::begin();
+
+    if let Ok(pml1_ent) = checked_pml1_entry(CURRENT_PML, virt, &crit) {
+        if (*pml1_ent).flags() == PageFlags::GUARD {
+            *pml1_ent = PmlEntry(0);
+        }
+    }
+}
+
 pub unsafe fn modify(virt: *mut u8, flags: PageFlags) -> Result<(), NotMapped> {
     let crit = critical::begin();
 
     let pml1_ent = checked_pml1_entry(CURRENT_PML, virt, &crit)?;
+
+    let current = (*pml1_ent).flags();
+    let mut flags = flags;
+
+    // a page not touched yet stays reserved, with the new flags for when it
+    // is:
+    if current.contains(PageFlags::RESERVED) {
+        flags = (flags - PageFlags::PRESENT) | PageFlags::RESERVED;
+    }
+
+    if current.contains(PageFlags::SHARED) {
+        flags.insert(PageFlags::SHARED);
+    }
+
     (*pml1_ent).set_flags(flags);
+    invlpg(virt);
+    smp::flush_tlb(virt);
 
     Ok(())
 }
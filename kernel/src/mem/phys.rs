@@ -39,6 +39,12 @@ impl Phys {
         Phys(raw_phys.0)
     }
 
+    /// Returns the raw address of the physical page, which stays referenced
+    /// only as long as this Phys is alive
+    pub fn raw(&self) -> RawPhys {
+        RawPhys(self.0)
+    }
+
     /// Consumes the Phys, returning the raw address of the physical page. This
     /// method does not affect the reference count of the underlying physical
     /// page, so care must be taken to avoid leaks.
@@ -141,6 +147,47 @@ pub fn alloc() -> Result<Phys, MemoryExhausted> {
     alloc_new(regions, &mut *bump_alloc)
 }
 
+/// Allocates `page_count` physically contiguous pages. Each page is returned
+/// with a reference count of one, owned by the caller. Contiguous runs only
+/// ever come from the bump allocator, as free list pages are scattered.
+pub fn alloc_contiguous(page_count: usize) -> Result<RawPhys, MemoryExhausted> {
+    alloc_contiguous_below(page_count, u64::max_value())
+}
+
+/// Like alloc_contiguous, but every page ends at or below `limit`, for
+/// devices which can't address all of memory
+pub fn alloc_contiguous_below(page_count: usize, limit: u64) -> Result<RawPhys, MemoryExhausted> {
+    let regions = &PHYS_REGIONS;
+    let mut bump_alloc = PHYS_BUMP_ALLOC.lock();
+
+    let len = (page_count * PAGE_SIZE) as u64;
+
+    for (region, alloc) in regions.iter().zip(bump_alloc.iter_mut()) {
+        if alloc.0 + len > region.end.0 || alloc.0 + len > limit {
+            continue;
+        }
+
+        let base = *alloc;
+        alloc.0 += len;
+
+        for index in 0..page_count {
+            let raw_phys = RawPhys(base.0 + (index * PAGE_SIZE) as u64);
+
+            unsafe {
+                Phys::new(raw_phys).into_raw();
+
+                let crit = critical::begin();
+                let mapped = page::temp_map::<u64>(raw_phys, &crit);
+                zero(mapped.ptr() as *mut u8, PAGE_SIZE);
+            }
+        }
+
+        return Ok(base);
+    }
+
+    Err(MemoryExhausted)
+}
+
 impl Drop for Phys {
     fn drop(&mut self) {
         match dec_ref(RawPhys(self.0)) {
@@ -199,6 +246,16 @@ fn ref_count(raw: RawPhys) -> Option<&'static AtomicUsize> {
     Some(unsafe { &*rc })
 }
 
+/// Number of references held on a page of memory, or None for memory that
+/// isn't counted, like memory mapped hardware
+pub fn references(raw: RawPhys) -> Option<usize> {
+    if REF_COUNT_ENABLED.load(Ordering::SeqCst) {
+        ref_count(raw).map(|rc| rc.load(Ordering::SeqCst))
+    } else {
+        None
+    }
+}
+
 fn inc_ref(raw: RawPhys) {
     if REF_COUNT_ENABLED.load(Ordering::SeqCst) {
         if let Some(rc) = ref_count(raw) {
@@ -260,7 +317,7 @@ pub unsafe extern "C" fn phys_init(
     region_count: u16,
     high_memory_boundary: RawPhys,
 ) {
-    crate::println!("Initialising physical page allocator... high_memory_boundary={:?}", high_memory_boundary);
+    crate::info!("initialising physical page allocator... high_memory_boundary={:?}", high_memory_boundary);
 
     // init temp mapping
     page::temp_reset();
@@ -271,8 +328,8 @@ pub unsafe extern "C" fn phys_init(
     for i in 0..region_count {
         let region = &*bios_memory_map.add(i as usize);
 
-        crate::println!("  - Memory region 0x{:016x}, length 0x{:016x}", region.begin.0, region.size);
-        crate::println!("    type {}, acpi ex attrs 0x{:08x}", region.kind, region.acpi_ex_attrs);
+        crate::info!("memory region 0x{:016x}, length 0x{:016x}, type {}, acpi ex attrs 0x{:08x}",
+            region.begin.0, region.size, region.kind, region.acpi_ex_attrs);
 
         if region.kind != REGION_KIND_USABLE {
             continue;
@@ -312,7 +369,7 @@ pub unsafe extern "C" fn phys_init(
     // --- we don't mutate PHYS_REGIONS at all beyond this point
 
     let mibibytes = PHYS_REGIONS.iter().map(|reg| reg.end.0 - reg.begin.0).sum::<u64>() / 1024 / 1024;
-    crate::println!("  {} MiB free", mibibytes);
+    crate::info!("{} MiB free", mibibytes);
 
     // map ref count pages for all phys regions reported by BIOS
     for region in PHYS_REGIONS.iter() {
@@ -330,6 +387,4 @@ pub unsafe extern "C" fn phys_init(
 
         ensure_rc_page(raw_phys);
     }
-
-    crate::println!();
 }
@@ -1,10 +1,15 @@
 use core::{mem, slice};
 
-use crate::mem::page::{self, PAGE_SIZE, PageFlags};
-use crate::critical::Critical;
-use interface::{SysResult, SysError};
+use crate::mem::page::{self, PAGE_SIZE, CopyError, MapError, PageFlags};
+use crate::mem::phys;
+use crate::critical::{self, Critical};
+use interface::{SysResult, SysError, DEFAULT_STACK_PAGES, MAX_STACK_PAGES};
 
-const MAX_USER_ADDR: u64 = 0x0000800000000000; // exclusive max
+pub const MAX_USER_ADDR: u64 = 0x0000800000000000; // exclusive max
+
+// user stacks are allocated downwards from the top of this region
+const STACK_REGION_BASE: u64 = 0x4000_0000;
+const STACK_REGION_TOP: u64 = 0x8000_0000;
 
 pub fn validate_page_align(addr: u64) -> SysResult<()> {
     if (addr & (PAGE_SIZE as u64 - 1)) != 0 {
@@ -33,8 +38,9 @@ impl PageRange {
             return Err(SysError::BadPointer);
         }
 
-        if base_page + byte_len > MAX_USER_ADDR {
-            return Err(SysError::BadPointer);
+        match base_page.checked_add(byte_len) {
+            Some(end) if end <= MAX_USER_ADDR => {}
+            _ => return Err(SysError::BadPointer),
         }
 
         Ok(PageRange {
@@ -72,6 +78,15 @@ impl PageRange {
         PageRange::new(base_page, page_count)
     }
 
+    /// The address just past the last page in the range
+    pub fn end(&self) -> u64 {
+        self.base_page + (self.page_count * PAGE_SIZE) as u64
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.base_page && addr < self.end()
+    }
+
     pub fn pages(&self) -> impl Iterator<Item = u64> {
         let base_page = self.base_page;
 
@@ -87,7 +102,8 @@ pub fn validate_map(page_range: &PageRange, flags: PageFlags, crit: &Critical)
     for addr in page_range.pages() {
         let valid = page::entry(addr as *mut u8, crit)
             .map(|entry|
-                entry.flags().contains(flags))
+                entry.flags().contains(flags) &&
+                    !entry.flags().contains(PageFlags::GUARD))
             .unwrap_or(false);
 
         if !valid {
@@ -121,9 +137,34 @@ pub fn validate_write(addr: u64, len: u64, crit: &Critical) -> SysResult<()> {
     // explicitly not checking for PRESENT flag, as this prevent us from
     // faulting in pages
     let page_range = PageRange::containing(addr, len)?;
+
+    // the kernel runs without CR0.WP, so its writes would go straight
+    // through to memory shared copy-on-write:
+    copy_shared(&page_range, crit)?;
+
     validate_map(&page_range, PageFlags::WRITE, crit)
 }
 
+/// Gives the current page context its own copy of any pages in the range it
+/// shares copy-on-write
+pub fn copy_shared(page_range: &PageRange, crit: &Critical) -> SysResult<()> {
+    for addr in page_range.pages() {
+        let shared = page::entry(addr as *mut u8, crit)
+            .map(|entry| entry.flags().contains(PageFlags::COPY_ON_WRITE))
+            .unwrap_or(false);
+
+        if shared {
+            unsafe { page::copy_on_write(addr as *mut u8) }
+                .map_err(|e| match e {
+                    CopyError::NotCopyOnWrite => SysError::BadPointer,
+                    CopyError::MemoryExhausted => SysError::MemoryExhausted,
+                })?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Borrows a slice from user space. `len` is the number of elements, not the
 /// number of bytes.
 pub fn borrow_slice<T>(addr: u64, len: u64, crit: &Critical) -> SysResult<&[T]> {
@@ -160,3 +201,95 @@ pub fn borrow<T>(addr: u64, crit: &Critical) -> SysResult<&T> {
 
     Ok(&slice[0])
 }
+
+pub fn is_guard(addr: u64, crit: &Critical) -> bool {
+    page::entry(addr as *mut u8, crit)
+        .map(|entry| entry.flags().contains(PageFlags::GUARD))
+        .unwrap_or(false)
+}
+
+/// Size in pages of a stack asked for with `page_count`, as alloc_stack
+/// would allocate it
+pub fn stack_pages(page_count: u64) -> SysResult<u64> {
+    match page_count {
+        0 => Ok(DEFAULT_STACK_PAGES),
+        count if count <= MAX_STACK_PAGES => Ok(count),
+        _ => Err(SysError::IllegalValue),
+    }
+}
+
+// Finds the highest free run of `page_count` pages in the stack region
+fn find_stack_space(page_count: u64, crit: &Critical) -> SysResult<u64> {
+    let byte_len = page_count * PAGE_SIZE as u64;
+    let mut top = STACK_REGION_TOP;
+
+    'search: while top >= STACK_REGION_BASE + byte_len {
+        let base = top - byte_len;
+
+        for addr in PageRange::new(base, page_count)?.pages() {
+            if page::entry(addr as *mut u8, crit).is_ok() {
+                // any run reaching above this page would include it:
+                top = addr;
+                continue 'search;
+            }
+        }
+
+        return Ok(base);
+    }
+
+    Err(SysError::MemoryExhausted)
+}
+
+/// Allocates a stack of `page_count` pages in the current page context,
+/// defaulting to DEFAULT_STACK_PAGES when zero, and returns its top. This is
+/// 16 byte aligned as the ABI requires at process entry. The page below is
+/// left as a guard, so an overflow faults rather than running into whatever
+/// is mapped beneath.
+pub fn alloc_stack(page_count: u64) -> SysResult<u64> {
+    let page_count = stack_pages(page_count)?;
+
+    let crit = critical::begin();
+
+    let guard = find_stack_space(page_count + 1, &crit)?;
+    let stack = PageRange::new(guard + PAGE_SIZE as u64, page_count)?;
+
+    let flags = PageFlags::PRESENT | PageFlags::USER | PageFlags::WRITE | PageFlags::NO_EXECUTE;
+
+    let map_stack = || -> SysResult<()> {
+        unsafe {
+            page::map_guard(guard as *mut u8)
+                .map_err(map_stack_error)?;
+
+            for addr in stack.pages() {
+                let phys = phys::alloc()
+                    .map_err(|_| SysError::MemoryExhausted)?;
+
+                page::map(phys, addr as *mut u8, flags)
+                    .map_err(map_stack_error)?;
+            }
+        }
+
+        Ok(())
+    };
+
+    if let Err(e) = map_stack() {
+        // give back what we got before running out:
+        for addr in stack.pages() {
+            unsafe { let _ = page::unmap(addr as *mut u8); }
+        }
+
+        unsafe { page::unmap_guard(guard as *mut u8); }
+
+        return Err(e);
+    }
+
+    Ok(stack.base_page + (page_count * PAGE_SIZE as u64))
+}
+
+fn map_stack_error(e: MapError) -> SysError {
+    match e {
+        // we found the space free, with interrupts off
+        MapError::AlreadyMapped => panic!("alloc_stack: AlreadyMapped error should never happen"),
+        MapError::CannotAllocatePageTable => SysError::MemoryExhausted,
+    }
+}
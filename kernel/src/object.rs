@@ -1,4 +1,5 @@
 pub mod file;
+pub mod memory;
 
 use core::num::NonZeroU64;
 use core::marker::PhantomData;
@@ -14,10 +15,14 @@ use crate::sync::{Arc, Mutex};
 use crate::task::{TaskId, TaskMap};
 use crate::util::EarlyInit;
 
+use memory::MemoryObject;
+
 #[derive(Debug)]
 pub enum ObjectKind {
     PageCtx(PageCtx),
     File(vfs::File),
+    Task(TaskId),
+    MemoryObject(MemoryObject),
 }
 
 pub trait ObjectKindT {
@@ -53,6 +58,34 @@ impl ObjectKindT for vfs::File {
     }
 }
 
+impl ObjectKindT for TaskId {
+    fn wrap(self) -> ObjectKind {
+        ObjectKind::Task(self)
+    }
+
+    fn as_ref(kind: &ObjectKind) -> SysResult<&Self> {
+        if let ObjectKind::Task(ref a) = kind {
+            Ok(a)
+        } else {
+            Err(SysError::WrongObjectKind)
+        }
+    }
+}
+
+impl ObjectKindT for MemoryObject {
+    fn wrap(self) -> ObjectKind {
+        ObjectKind::MemoryObject(self)
+    }
+
+    fn as_ref(kind: &ObjectKind) -> SysResult<&Self> {
+        if let ObjectKind::MemoryObject(ref a) = kind {
+            Ok(a)
+        } else {
+            Err(SysError::WrongObjectKind)
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Object {
     kind: ObjectKind,
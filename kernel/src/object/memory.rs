@@ -0,0 +1,45 @@
+use core::fmt::{self, Debug};
+
+use alloc_collections::btree_map::BTreeMap;
+
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+use crate::mem::phys::{self, Phys};
+
+/// Largest memory object CreateMemoryObject makes, 256 MiB. Its pages are
+/// all allocated up front, so this keeps one call from taking them all.
+pub const MAX_MEMORY_OBJECT_PAGES: u64 = 0x10000;
+
+/// Pages of memory which can be mapped into any number of page contexts at
+/// once. Each mapping holds its own reference on the pages, so they outlive
+/// the object for as long as they stay mapped.
+pub struct MemoryObject {
+    pages: BTreeMap<u64, Phys, GlobalAlloc>,
+}
+
+impl MemoryObject {
+    pub fn new(page_count: u64) -> Result<Self, MemoryExhausted> {
+        let mut pages = BTreeMap::new();
+
+        for index in 0..page_count {
+            pages.insert(index, phys::alloc()?)
+                .map_err(|_| MemoryExhausted)?;
+        }
+
+        Ok(MemoryObject { pages })
+    }
+
+    pub fn page_count(&self) -> u64 {
+        self.pages.len() as u64
+    }
+
+    pub fn page(&self, index: u64) -> Option<&Phys> {
+        self.pages.get(&index)
+    }
+}
+
+impl Debug for MemoryObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MemoryObject {{ page_count: {} }}", self.page_count())
+    }
+}
@@ -0,0 +1,218 @@
+use core::mem;
+use core::ptr;
+
+use arrayvec::ArrayVec;
+use interface::{SysError, SysResult, AuxEntry};
+use interface::{AT_NULL, AT_PHDR, AT_PHENT, AT_PHNUM, AT_PAGESZ, AT_ENTRY, AT_HANDLE_BASE, AT_HANDLE_COUNT};
+
+use crate::elf::{self, Image};
+use crate::interrupt::TrapFrame;
+use crate::mem::MemoryExhausted;
+use crate::mem::page::{PageCtx, PAGE_SIZE};
+use crate::mem::user;
+use crate::object::{self, DynObjectRef, ObjectRef};
+use crate::task::{self, ActivePageCtx, TaskId};
+
+/// Most bytes of argument strings a new process can be given, including
+/// their terminating nuls. The environment has the same limit.
+pub const ARG_MAX: usize = 4096;
+pub const MAX_ARGS: usize = 64;
+pub const MAX_HANDLES: usize = 16;
+
+// nul terminated strings packed together
+struct Strings {
+    bytes: ArrayVec<[u8; ARG_MAX]>,
+    // end of each string in bytes, not counting its nul
+    ends: ArrayVec<[usize; MAX_ARGS]>,
+}
+
+impl Strings {
+    fn new() -> Self {
+        Strings { bytes: ArrayVec::new(), ends: ArrayVec::new() }
+    }
+
+    fn push(&mut self, string: &[u8]) -> SysResult<()> {
+        if self.ends.is_full() || self.bytes.remaining_capacity() < string.len() + 1 {
+            return Err(SysError::IllegalValue);
+        }
+
+        // nul terminated in the new process, so no nuls within:
+        if string.contains(&0) {
+            return Err(SysError::IllegalValue);
+        }
+
+        self.bytes.try_extend_from_slice(string).expect("capacity checked above");
+        self.ends.push(self.bytes.len());
+        self.bytes.push(0);
+
+        Ok(())
+    }
+
+    // Writes a pointer to each string as copied to `base`, then a null
+    unsafe fn write_pointers(&self, base: u64, words: &mut *mut u64) {
+        let mut start = 0;
+
+        for end in self.ends.iter() {
+            push_word(words, base + start as u64);
+            start = end + 1;
+        }
+
+        push_word(words, 0);
+    }
+}
+
+/// Argument and environment strings for a new process, copied out of the
+/// spawning task's memory before its page context is switched away
+pub struct Args {
+    argv: Strings,
+    env: Strings,
+}
+
+impl Args {
+    pub fn new() -> Self {
+        Args { argv: Strings::new(), env: Strings::new() }
+    }
+
+    pub fn push_arg(&mut self, arg: &[u8]) -> SysResult<()> {
+        self.argv.push(arg)
+    }
+
+    /// Adds an environment variable, given as `NAME=value`
+    pub fn push_env(&mut self, var: &[u8]) -> SysResult<()> {
+        if !var.contains(&b'=') {
+            return Err(SysError::IllegalValue);
+        }
+
+        self.env.push(var)
+    }
+}
+
+unsafe fn push_word(words: &mut *mut u64, word: u64) {
+    words.write(word);
+    *words = words.add(1);
+}
+
+// one of each kind we pass, and AT_NULL
+type AuxVector = ArrayVec<[AuxEntry; 8]>;
+
+fn aux_vector(image: &Image, handle_count: u64) -> AuxVector {
+    let mut aux = AuxVector::new();
+
+    if let Some(program_headers) = image.program_headers {
+        aux.push(AuxEntry { kind: AT_PHDR, value: program_headers });
+        aux.push(AuxEntry { kind: AT_PHENT, value: image.program_header_size() });
+        aux.push(AuxEntry { kind: AT_PHNUM, value: image.program_header_count });
+    }
+
+    aux.push(AuxEntry { kind: AT_PAGESZ, value: PAGE_SIZE as u64 });
+    aux.push(AuxEntry { kind: AT_ENTRY, value: image.entry });
+    aux.push(AuxEntry { kind: AT_HANDLE_BASE, value: 1 });
+    aux.push(AuxEntry { kind: AT_HANDLE_COUNT, value: handle_count });
+    aux.push(AuxEntry { kind: AT_NULL, value: 0 });
+
+    aux
+}
+
+// argc, argv and envp with their nulls, then the aux vector, padded so rsp
+// stays 16 byte aligned
+fn start_info_words(args: &Args, aux: &AuxVector) -> usize {
+    let words = 1
+        + args.argv.ends.len() + 1
+        + args.env.ends.len() + 1
+        + aux.len() * 2;
+
+    words + words % 2
+}
+
+/// Bytes of stack push_start_info takes below a page aligned stack top
+pub fn start_info_size(image: &Image, args: &Args, handle_count: u64) -> u64 {
+    let aux = aux_vector(image, handle_count);
+    let strings = (args.argv.bytes.len() + args.env.bytes.len()) as u64;
+
+    // the strings are followed by padding down to 16 bytes:
+    ((strings + 0xf) & !0xf) + (start_info_words(args, &aux) * mem::size_of::<u64>()) as u64
+}
+
+/// Copies the start info described in the interface crate onto the stack
+/// ending at `stack_top` for a freshly loaded `image`, for a process holding
+/// handles 1 to `handle_count`. Must run in the new process's page context,
+/// with at least start_info_size bytes of stack. Returns the stack pointer to
+/// start with.
+pub unsafe fn push_start_info(image: &Image, stack_top: u64, args: &Args, handle_count: u64) -> u64 {
+    let env_strings = stack_top - args.env.bytes.len() as u64;
+    ptr::copy_nonoverlapping(args.env.bytes.as_ptr(), env_strings as *mut u8, args.env.bytes.len());
+
+    let arg_strings = env_strings - args.argv.bytes.len() as u64;
+    ptr::copy_nonoverlapping(args.argv.bytes.as_ptr(), arg_strings as *mut u8, args.argv.bytes.len());
+
+    let aux = aux_vector(image, handle_count);
+    let words = start_info_words(args, &aux);
+
+    let rsp = (arg_strings & !0xf) - (words * mem::size_of::<u64>()) as u64;
+    let mut cursor = rsp as *mut u64;
+
+    push_word(&mut cursor, args.argv.ends.len() as u64);
+    args.argv.write_pointers(arg_strings, &mut cursor);
+    args.env.write_pointers(env_strings, &mut cursor);
+
+    for entry in aux.iter() {
+        push_word(&mut cursor, entry.kind);
+        push_word(&mut cursor, entry.value);
+    }
+
+    rsp
+}
+
+/// Starts the executable at `path` as a new process in an address space of
+/// its own, holding `handles` and with `args` in its start info. The stack
+/// is `stack_pages` long, see user::alloc_stack.
+pub async fn spawn(path: &[u8], args: &Args, handles: ArrayVec<[DynObjectRef; MAX_HANDLES]>, stack_pages: u64)
+    -> SysResult<TaskId>
+{
+    let filesystem = task::get_filesystem();
+
+    let file = filesystem.as_ref()
+        .ok_or(SysError::NoFile)?
+        .open(path)
+        .await?;
+
+    let page_ctx = PageCtx::new()
+        .map_err(|_| SysError::MemoryExhausted)?;
+
+    let page_ctx = ObjectRef::new(page_ctx)?;
+
+    let (entry, rsp) = {
+        // load through this task, so the scheduler keeps the new context
+        // active while we wait on the disk
+        let _active = ActivePageCtx::enter(page_ctx.clone());
+
+        let image = elf::load(&file).await?;
+        let handle_count = handles.len() as u64;
+
+        // the kernel writes the start info, running onto the guard page
+        // would fault in kernel mode:
+        let stack_size = user::stack_pages(stack_pages)? * PAGE_SIZE as u64;
+
+        if start_info_size(&image, args, handle_count) > stack_size {
+            return Err(SysError::IllegalValue);
+        }
+
+        let stack_top = user::alloc_stack(stack_pages)?;
+        let rsp = unsafe { push_start_info(&image, stack_top, args, handle_count) };
+
+        (image.entry, rsp)
+    };
+
+    let id = task::spawn_prepared(page_ctx, filesystem, move |task| {
+        for handle in handles {
+            object::put(task.id(), handle)
+                .map_err(|_| MemoryExhausted)?;
+        }
+
+        Ok(async move {
+            task.setup(TrapFrame::new(entry, rsp)).run_loop().await
+        })
+    })?;
+
+    Ok(id)
+}
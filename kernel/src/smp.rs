@@ -0,0 +1,226 @@
+use core::ptr;
+use core::sync::atomic::{self, Ordering};
+use core::time::Duration;
+
+use crate::acpi::{self, MadtEntry};
+use crate::cpu::{self, Cpu, MAX_CPUS};
+use crate::device::{apic, pit};
+use crate::mem::kvirt;
+use crate::mem::page::{self, PageFlags};
+use crate::mem::phys::{Phys, RawPhys};
+use crate::mem::MemoryExhausted;
+use crate::sync::Mutex;
+use crate::task;
+
+/// Sent to a CPU halted in the scheduler when work is queued for it
+pub const RESCHEDULE_VECTOR: u8 = 0x41;
+
+/// Asks the other CPUs to invalidate the page in their `tlb_flush`, or their
+/// whole TLB
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0x42;
+
+// see AP_TRAMPOLINE and AP_TRAMPOLINE_PARAMS in consts.asm
+const TRAMPOLINE: u64 = 0x7000;
+const TRAMPOLINE_PARAMS: u64 = 0x7f00;
+
+static TRAMPOLINE_CODE: &[u8] = include_bytes!("../../target/x86_64-kernel/trampoline.bin");
+
+const AP_STACK_PAGES: usize = 16;
+
+// how long an application processor gets to show up after its startup IPIs
+const AP_START_TIMEOUT_MS: usize = 100;
+
+// one shootdown at a time, each CPU only has room for one request
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+
+extern "C" {
+    fn isrs_load();
+}
+
+// must match params in trampoline.asm
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    stack_top: u64,
+    cpu: u64,
+    entry: u64,
+}
+
+#[derive(Debug)]
+enum StartError {
+    MemoryExhausted,
+    Timeout,
+}
+
+impl From<MemoryExhausted> for StartError {
+    fn from(_: MemoryExhausted) -> Self {
+        StartError::MemoryExhausted
+    }
+}
+
+extern "C" fn ap_main(cpu: &'static Cpu, stack_top: *mut u8) -> ! {
+    unsafe {
+        isrs_load();
+        apic::init_ap();
+
+        // publishes this CPU, the BSP is waiting on it:
+        cpu::load(cpu, stack_top);
+
+        crate::info!("cpu {} online, local APIC {}", cpu.id(), cpu.apic_id);
+
+        task::start();
+    }
+}
+
+unsafe fn start_ap(id: usize, apic_id: u8) -> Result<(), StartError> {
+    let cpu = cpu::alloc(id, apic_id)?;
+    let stack_top = kvirt::alloc_stack(AP_STACK_PAGES)?;
+
+    // the AP takes its own reference to our page tables, for task::switch
+    // to release when it first switches tasks
+    let cr3 = page::current_ctx().into_raw();
+
+    ptr::write_volatile(TRAMPOLINE_PARAMS as *mut TrampolineParams, TrampolineParams {
+        cr3: cr3.0,
+        stack_top: stack_top.as_ptr() as u64,
+        cpu: cpu as *const Cpu as u64,
+        entry: ap_main as usize as u64,
+    });
+
+    apic::send_init(apic_id);
+    pit::delay(Duration::from_millis(10));
+
+    // the second startup IPI is for processors that missed the first:
+    for _ in 0..2 {
+        if cpu::get(id).is_some() {
+            break;
+        }
+
+        apic::send_startup(apic_id, (TRAMPOLINE >> 12) as u8);
+        pit::delay(Duration::from_micros(200));
+    }
+
+    for _ in 0..AP_START_TIMEOUT_MS {
+        if cpu::get(id).is_some() {
+            return Ok(());
+        }
+
+        pit::delay(Duration::from_millis(1));
+    }
+
+    // leaks the reference to cr3, in case the AP turns up late after all
+    Err(StartError::Timeout)
+}
+
+// Safety: must not be called more than once, on the bootstrap processor
+// after apic::init, cpu::init_bsp and task::init
+pub unsafe fn init() {
+    if !apic::is_enabled() {
+        return;
+    }
+
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return,
+    };
+
+    let this_apic_id = cpu::current().apic_id;
+
+    // the trampoline has to be below 1 MiB for real mode, and mapped at the
+    // same address for the jump to long mode:
+    let trampoline = TRAMPOLINE as *mut u8;
+
+    page::map(Phys::new(RawPhys(TRAMPOLINE)), trampoline, PageFlags::PRESENT | PageFlags::WRITE)
+        .expect("page::map in smp::init");
+
+    ptr::copy_nonoverlapping(TRAMPOLINE_CODE.as_ptr(), trampoline, TRAMPOLINE_CODE.len());
+
+    let mut next_id = 1;
+
+    for entry in madt.entries() {
+        let apic_id = match entry {
+            MadtEntry::LocalApic { apic_id, enabled: true, .. } => apic_id,
+            _ => continue,
+        };
+
+        if apic_id == this_apic_id {
+            continue;
+        }
+
+        if next_id == MAX_CPUS {
+            crate::warn!("more than {} CPUs, ignoring the rest", MAX_CPUS);
+            break;
+        }
+
+        match start_ap(next_id, apic_id) {
+            Ok(()) => next_id += 1,
+            Err(e) => crate::warn!("could not start CPU with local APIC {}: {:?}", apic_id, e),
+        }
+    }
+
+    page::unmap(trampoline)
+        .expect("page::unmap in smp::init");
+
+    crate::info!("{} CPU(s) online", cpu::count());
+}
+
+/// Invalidates `virt` in the TLBs of the other CPUs, once it's been
+/// invalidated on this one. Returns once they all have.
+pub fn flush_tlb(virt: *mut u8) {
+    shootdown(virt as u64);
+}
+
+/// Like flush_tlb, but invalidates every non-global page, for when so many
+/// changed that one at a time would take longer
+pub fn flush_tlb_all() {
+    shootdown(FLUSH_ALL);
+}
+
+// in place of an address in `tlb_flush`, asks for the whole TLB. never a
+// page address since it isn't aligned
+const FLUSH_ALL: u64 = !0;
+
+fn shootdown(virt: u64) {
+    if cpu::count() == 1 {
+        return;
+    }
+
+    let _shootdown = SHOOTDOWN.lock();
+
+    let this = cpu::id();
+
+    for cpu in cpu::all().filter(|cpu| cpu.id() != this) {
+        cpu.tlb_flush.store(virt, Ordering::SeqCst);
+    }
+
+    apic::broadcast_ipi(TLB_SHOOTDOWN_VECTOR);
+
+    for cpu in cpu::all().filter(|cpu| cpu.id() != this) {
+        while cpu.tlb_flush.load(Ordering::SeqCst) != 0 {
+            // they may be spinning on a lock waiting for us to do the same:
+            flush_tlb_requested();
+            atomic::spin_loop_hint();
+        }
+    }
+}
+
+/// Carries out a TLB flush another CPU asked this one for, if any. Called
+/// from the shootdown IPI and by anything spinning with interrupts disabled.
+pub fn flush_tlb_requested() {
+    // before the per-CPU area is set up there is nobody to ask
+    if cpu::count() == 1 {
+        return;
+    }
+
+    let cpu = cpu::current();
+    let virt = cpu.tlb_flush.load(Ordering::SeqCst);
+
+    // kernel and user alike never map page zero, so it means no request
+    if virt == FLUSH_ALL {
+        page::flush_all();
+        cpu.tlb_flush.store(0, Ordering::SeqCst);
+    } else if virt != 0 {
+        page::invlpg(virt as *mut u8);
+        cpu.tlb_flush.store(0, Ordering::SeqCst);
+    }
+}
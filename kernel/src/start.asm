@@ -63,10 +63,10 @@ protected_mode:
     or eax, CR4_PAGE_SIZE_EXT | CR4_PHYS_ADDR_EXT
     mov cr4, eax
 
-    ; enable long mode
+    ; enable long mode and the no-execute page bit
     mov ecx, 0xc0000080
     rdmsr
-    or eax, 1 << 8
+    or eax, (1 << 8) | (1 << 11)
     wrmsr
 
     ; enable paging
@@ -146,6 +146,13 @@ higher_half:
     shr rdx, 32
     wrmsr
 
+    ; point GS at the per-CPU area of the bootstrap processor, see cpu.rs
+    mov ecx, 0xc0000101 ; MSR_GS_BASE
+    mov rax, bsp_cpu
+    mov rdx, rax
+    shr rdx, 32
+    wrmsr
+
     ; init phys allocator
     mov rdi, EARLY_MEMORY_MAP
     mov rsi, [EARLY_MEMORY_MAP_LEN]
@@ -200,6 +207,7 @@ gdtr:
     .size   dw (gdt.end - gdt) - 1 ; size
     .offset dq EARLY_PHYS(gdt)     ; offset
 
+global gdt
 gdt:
     ; null entry
     dq 0
@@ -261,6 +269,9 @@ section .bss
     global temp_page
     temp_page   resb PAGE_SIZE
 
+    global bsp_cpu
+    bsp_cpu     resb PAGE_SIZE
+
 section .stack
     global stackguard
     align PAGE_SIZE
@@ -1,11 +1,19 @@
 use core::ops::{Drop, Deref, DerefMut};
 use core::cell::UnsafeCell;
 use core::fmt::{self, Debug};
+use core::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
 
+use crate::cpu;
 use crate::critical::{self, Critical};
+use crate::smp;
 
+/// Spinlock held with interrupts disabled, so the holder can be neither
+/// preempted nor interrupted into locking it again
 pub struct Mutex<T> {
-    inner: UnsafeCell<MutexInner<T>>,
+    value: UnsafeCell<T>,
+    locked: AtomicBool,
+    // id + 1 of the CPU holding the lock, zero if none
+    owner: AtomicUsize,
 }
 
 impl<T> Debug for Mutex<T> {
@@ -16,29 +24,20 @@ impl<T> Debug for Mutex<T> {
 
 unsafe impl<T> Sync for Mutex<T> {}
 
-struct MutexInner<T> {
-    value: T,
-    locked: bool,
-}
-
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
         Mutex {
-            inner: UnsafeCell::new(MutexInner {
-                value: value,
-                locked: false,
-            })
+            value: UnsafeCell::new(value),
+            locked: AtomicBool::new(false),
+            owner: AtomicUsize::new(0),
         }
     }
 
+    /// Whether this CPU holds the lock
     pub fn locked(&self, _critical: &Critical) -> bool {
-        let inner = unsafe {
-            // ref to Critical proves we're in a critical section, so this is
-            // safe:
-            &*self.inner.get()
-        };
-
-        inner.locked
+        // ref to Critical proves we're in a critical section, so we can't be
+        // moved to another CPU while we look
+        self.owner.load(Ordering::Relaxed) == cpu::id() + 1
     }
 
     pub fn lock<'a>(&'a self) -> MutexGuard<'a, T> {
@@ -48,29 +47,32 @@ impl<T> Mutex<T> {
             panic!("recursive mutex lock!");
         }
 
-        let inner = unsafe {
-            // we are in critical section and have checked that the mutex is not
-            // already locked, so this is safe:
-            &mut *self.inner.get()
-        };
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            // the holder may be waiting on us to flush our TLB, and we can't
+            // take the IPI with interrupts disabled:
+            smp::flush_tlb_requested();
+            atomic::spin_loop_hint();
+        }
 
-        inner.locked = true;
+        self.owner.store(cpu::id() + 1, Ordering::Relaxed);
 
         MutexGuard {
             _critical: critical,
-            inner: inner,
+            mutex: self,
         }
     }
 }
 
 pub struct MutexGuard<'a, T> {
     _critical: Critical,
-    inner: &'a mut MutexInner<T>,
+    mutex: &'a Mutex<T>,
 }
 
 impl<'a, T> Drop for MutexGuard<'a, T> {
     fn drop(&mut self) {
-        self.inner.locked = false;
+        // unlocks before the critical section ends:
+        self.mutex.owner.store(0, Ordering::Relaxed);
+        self.mutex.locked.store(false, Ordering::Release);
     }
 }
 
@@ -78,12 +80,14 @@ impl<'a, T> Deref for MutexGuard<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        &self.inner.value
+        // Safety: we have the lock
+        unsafe { &*self.mutex.value.get() }
     }
 }
 
 impl<'a, T> DerefMut for MutexGuard<'a, T> {
     fn deref_mut(&mut self) -> &mut T {
-        &mut self.inner.value
+        // Safety: we have the lock
+        unsafe { &mut *self.mutex.value.get() }
     }
 }
@@ -1,16 +1,25 @@
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
+use core::time::Duration;
 
 use bitflags::bitflags;
-use interface::{OK, Syscall, SysError, SysResult};
+use arrayvec::ArrayVec;
+use interface::{OK, FileTimes, Keymap, PciDeviceInfo, Priority, SpawnArgs, Syscall, SysError, SysResult, TtyMode, TtySize, UserSlice};
 
+use crate::device::{keyboard, pci};
 use crate::interrupt::{TrapFrame, Registers};
+use crate::mem::kalloc::Box;
 use crate::mem::page::{self, PageFlags, MapError, PageCtx, PAGE_SIZE};
 use crate::mem::phys::{self, Phys, RawPhys};
 use crate::mem::user::{self, PageRange};
 use crate::object::{self, Handle, Object, ObjectKind, ObjectRef};
+use crate::object::memory::{MemoryObject, MAX_MEMORY_OBJECT_PAGES};
+use crate::process::{self, Args, MAX_HANDLES};
 use crate::fs::vfs::File;
-use crate::task;
-use crate::{critical, println};
+use crate::task::{self, ActivePageCtx};
+use crate::time;
+use crate::tty;
+use crate::critical;
+use crate::log::{self, Level, SetLevelError};
 
 mod args;
 use args::UserArg;
@@ -45,6 +54,22 @@ async fn dispatch0(regs: &mut Registers) -> SyscallReturn {
         Syscall::ReadStream => read_stream(UserArg::from_reg(regs.rdi)?, regs.rsi, regs.rdx).await,
         Syscall::WriteStream => write_stream(UserArg::from_reg(regs.rdi)?, regs.rsi, regs.rdx).await,
         Syscall::OpenFile => open_file(regs.rdi, regs.rsi, regs.rdx).await,
+        Syscall::ListPciDevices => list_pci_devices(regs.rdi, regs.rsi),
+        Syscall::ReadLog => read_log(regs.rdi, regs.rsi, regs.rdx),
+        Syscall::SetLogLevel => set_log_level(regs.rdi, regs.rsi, regs.rdx),
+        Syscall::SetKeymap => set_keymap(regs.rdi),
+        Syscall::SetTtyMode => set_tty_mode(regs.rdi),
+        Syscall::GetTtySize => get_tty_size(regs.rdi),
+        Syscall::Sleep => sleep(regs.rdi).await,
+        Syscall::GetMonotonicTime => get_monotonic_time(),
+        Syscall::GetWallTime => get_wall_time(),
+        Syscall::GetFileTimes => get_file_times(UserArg::from_reg(regs.rdi)?, regs.rsi),
+        Syscall::SetPriority => set_priority(regs.rdi),
+        Syscall::SpawnProcess => spawn_process(regs.rdi).await,
+        Syscall::ReservePages => reserve_pages(regs.rdi, regs.rsi, regs.rdx),
+        Syscall::ClonePageContext => clone_page_context(UserArg::from_reg(regs.rdi)?),
+        Syscall::CreateMemoryObject => create_memory_object(regs.rdi),
+        Syscall::MapMemoryObject => map_memory_object(UserArg::from_reg(regs.rdi)?, regs.rsi, regs.rdx, regs.rcx, regs.r8),
     }
 }
 
@@ -70,7 +95,7 @@ impl From<UserPageFlags> for PageFlags {
 type SyscallReturn = SysResult<u64>;
 
 fn alloc_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn {
-    println!("SYSCALL alloc_page(virt = {:x?}, count = {:x?}, flags = {:x?})",
+    crate::trace!("alloc_page(virt = {:x?}, count = {:x?}, flags = {:x?})",
         virtual_addr,  page_count, flags);
 
     let crit = critical::begin();
@@ -110,8 +135,45 @@ fn alloc_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn {
     Ok(OK)
 }
 
+// Like alloc_page, but memory is only allocated for each page when it's
+// first touched
+fn reserve_pages(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn {
+    crate::trace!("reserve_pages(virt = {:x?}, count = {:x?}, flags = {:x?})",
+        virtual_addr, page_count, flags);
+
+    let crit = critical::begin();
+
+    let page_range = PageRange::new(virtual_addr, page_count)?;
+    user::validate_available(&page_range, &crit)?;
+
+    let flags = UserPageFlags::from_bits(flags)
+        .ok_or(SysError::IllegalValue)?;
+
+    let flags = PageFlags::from(flags);
+
+    for addr in page_range.pages() {
+        let addr = addr as *mut u8;
+
+        // Safety: we validated that this will not violate kernel memory safety
+        // We do not guarantee user space memory safety
+        unsafe {
+            page::reserve(addr, flags)
+                .map_err(|e| match e {
+                    MapError::AlreadyMapped => {
+                        panic!("reserve_pages: AlreadyMapped error should never happen")
+                    }
+                    MapError::CannotAllocatePageTable => {
+                        SysError::MemoryExhausted
+                    }
+                })?;
+        }
+    }
+
+    Ok(OK)
+}
+
 fn release_page(virtual_addr: u64, page_count: u64) -> SyscallReturn {
-    println!("SYSCALL release_page");
+    crate::trace!("release_page(virt = {:x?}, count = {:x?})", virtual_addr, page_count);
 
     let crit = critical::begin();
 
@@ -124,7 +186,7 @@ fn release_page(virtual_addr: u64, page_count: u64) -> SyscallReturn {
         // Safety: we validated that this will not violate kernel memory safety
         // We do not guarantee user space memory safety
         unsafe {
-            println!("releasing {:?}", addr);
+            crate::trace!("releasing {:?}", addr);
 
             page::unmap(addr)
                 .expect("release_page: NotMapped error should never happen");
@@ -135,7 +197,8 @@ fn release_page(virtual_addr: u64, page_count: u64) -> SyscallReturn {
 }
 
 fn modify_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn {
-    println!("SYSCALL release_page");
+    crate::trace!("modify_page(virt = {:x?}, count = {:x?}, flags = {:x?})",
+        virtual_addr, page_count, flags);
 
     let crit = critical::begin();
 
@@ -147,13 +210,16 @@ fn modify_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn
 
     let flags = PageFlags::from(flags);
 
+    // new flags would apply to memory still shared with another context:
+    user::copy_shared(&page_range, &crit)?;
+
     for addr in page_range.pages() {
         let addr = addr as *mut u8;
 
         // Safety: we validated that this will not violate kernel memory safety
         // We do not guarantee user space memory safety
         unsafe {
-            println!("releasing {:?}", addr);
+            crate::trace!("modifying {:?}", addr);
 
             page::modify(addr, flags)
                 .expect("modify_page: NotMapped error should never happen");
@@ -166,7 +232,7 @@ fn modify_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn
 fn map_physical_memory(virtual_addr: u64, physical_addr: u64, page_count: u64, flags: u64)
     -> SyscallReturn
 {
-    println!("SYSCALL map_physical_memory(virt = {:x?}, phys = {:x?}, count = {:x?}, flags = {:x?})",
+    crate::trace!("map_physical_memory(virt = {:x?}, phys = {:x?}, count = {:x?}, flags = {:x?})",
         virtual_addr, physical_addr, page_count, flags);
 
     // TODO - insert capabilities check here. calling process must have DRIVER caps
@@ -199,6 +265,71 @@ fn map_physical_memory(virtual_addr: u64, physical_addr: u64, page_count: u64, f
     Ok(OK)
 }
 
+fn create_memory_object(page_count: u64) -> SyscallReturn {
+    if page_count == 0 || page_count > MAX_MEMORY_OBJECT_PAGES {
+        return Err(SysError::IllegalValue);
+    }
+
+    let memory = ObjectRef::new(MemoryObject::new(page_count)?)?;
+
+    Ok(object::put(task::current(), memory.as_dyn())?.into_u64())
+}
+
+// Maps `page_count` pages of a memory object into the current page context,
+// starting `page_offset` pages into it. Each page mapped holds a reference,
+// released by release_page.
+fn map_memory_object(memory: Handle, virtual_addr: u64, page_offset: u64, page_count: u64, flags: u64)
+    -> SyscallReturn
+{
+    crate::trace!("map_memory_object(virt = {:x?}, offset = {:x?}, count = {:x?}, flags = {:x?})",
+        virtual_addr, page_offset, page_count, flags);
+
+    let memory = object::get(task::current(), memory)
+        .ok_or(SysError::BadHandle)?
+        .downcast::<MemoryObject>()?;
+
+    let memory = memory.object();
+
+    let end = page_offset.checked_add(page_count)
+        .ok_or(SysError::IllegalValue)?;
+
+    if end > memory.page_count() {
+        return Err(SysError::IllegalValue);
+    }
+
+    let crit = critical::begin();
+
+    let page_range = PageRange::new(virtual_addr, page_count)?;
+    user::validate_available(&page_range, &crit)?;
+
+    let flags = UserPageFlags::from_bits(flags)
+        .ok_or(SysError::IllegalValue)?;
+
+    let flags = PageFlags::from(flags) | PageFlags::SHARED;
+
+    for (addr, index) in page_range.pages().zip(page_offset..) {
+        let phys = memory.page(index)
+            .expect("page in memory object")
+            .clone();
+
+        // Safety: we validated that this will not violate kernel memory safety
+        // We do not guarantee user space memory safety
+        unsafe {
+            page::map(phys, addr as *mut u8, flags)
+                .map_err(|e| match e {
+                    MapError::AlreadyMapped => {
+                        panic!("map_memory_object: AlreadyMapped error should never happen")
+                    }
+                    MapError::CannotAllocatePageTable => {
+                        SysError::MemoryExhausted
+                    }
+                })?;
+        }
+    }
+
+    Ok(OK)
+}
+
 fn clone_handle(handle: Handle) -> SyscallReturn  {
     let object_ref = object::get(task::current(), handle)
         .ok_or(SysError::BadHandle)?;
@@ -224,8 +355,26 @@ fn create_page_context() -> SyscallReturn {
     Ok(object::put(task::current(), obj)?.into_u64())
 }
 
+fn clone_page_context(page_ctx: Handle) -> SyscallReturn {
+    let page_ctx = object::get(task::current(), page_ctx)
+        .ok_or(SysError::BadHandle)?
+        .downcast::<PageCtx>()?;
+
+    let clone = {
+        let _active = ActivePageCtx::enter(page_ctx);
+
+        page::copy_current_ctx()
+            .map_err(|_| SysError::MemoryExhausted)?
+    };
+
+    let obj = Object::new(ObjectKind::PageCtx(clone))
+        .map_err(|_| SysError::MemoryExhausted)?;
+
+    Ok(object::put(task::current(), obj)?.into_u64())
+}
+
 fn debug(regs: &mut Registers) -> SyscallReturn {
-    println!("{:#x?}", regs);
+    crate::info!("{:#x?}", regs);
     Ok(OK)
 }
 
@@ -248,12 +397,17 @@ fn get_page_context() -> SyscallReturn {
     Ok(object::put(task::current(), page_ctx.as_dyn())?.into_u64())
 }
 
-fn create_task(page_ctx: Handle, rip: u64, rsp: u64) -> SyscallReturn {
+fn create_task(page_ctx: Handle, rip: u64, stack_pages: u64) -> SyscallReturn {
     let page_ctx = object::get(task::current(), page_ctx)
         .ok_or(SysError::BadHandle)?
         .downcast::<PageCtx>()?
         .clone();
 
+    let rsp = {
+        let _active = ActivePageCtx::enter(page_ctx.clone());
+        user::alloc_stack(stack_pages)?
+    };
+
     let filesystem = task::get_filesystem();
 
     task::spawn(page_ctx, filesystem, |task| async move {
@@ -303,7 +457,7 @@ bitflags! {
 }
 
 async fn open_file(path: u64, path_len: u64, flags: u64) -> SyscallReturn {
-    crate::println!("open_path: {:x?}, {:x?}, {:x?}", path, path_len, flags);
+    crate::trace!("open_file(path = {:x?}, len = {:x?}, flags = {:x?})", path, path_len, flags);
     let crit = critical::begin();
     let path = user::borrow_slice::<u8>(path, path_len, &crit)?;
 
@@ -315,3 +469,144 @@ async fn open_file(path: u64, path_len: u64, flags: u64) -> SyscallReturn {
 
     Ok(object::put(task::current(), file.as_dyn())?.into_u64())
 }
+
+fn list_pci_devices(buf: u64, count: u64) -> SyscallReturn {
+    let crit = critical::begin();
+    let buf = user::borrow_slice_mut::<PciDeviceInfo>(buf, count, &crit)?;
+
+    Ok(pci::list(buf) as u64)
+}
+
+fn read_log(buf: u64, len: u64, start_seq: u64) -> SyscallReturn {
+    let crit = critical::begin();
+    let buf = user::borrow_slice_mut::<u8>(buf, len, &crit)?;
+
+    Ok(log::read(buf, start_seq) as u64)
+}
+
+fn set_log_level(module: u64, module_len: u64, level: u64) -> SyscallReturn {
+    let level = u8::try_from(level).ok()
+        .and_then(Level::from_u8)
+        .ok_or(SysError::IllegalValue)?;
+
+    let crit = critical::begin();
+    let module = user::borrow_slice::<u8>(module, module_len, &crit)?;
+
+    let module = core::str::from_utf8(module)
+        .map_err(|_| SysError::IllegalValue)?;
+
+    log::set_level(module, level)
+        .map_err(|e| match e {
+            SetLevelError::ModuleNameTooLong => SysError::IllegalValue,
+            SetLevelError::FilterTableFull => SysError::MemoryExhausted,
+        })?;
+
+    Ok(OK)
+}
+
+fn set_keymap(keymap: u64) -> SyscallReturn {
+    let keymap = Keymap::from_u64(keymap)
+        .ok_or(SysError::IllegalValue)?;
+
+    keyboard::set_keymap(keymap);
+
+    Ok(OK)
+}
+
+fn set_tty_mode(mode: u64) -> SyscallReturn {
+    let mode = TtyMode::from_u64(mode)
+        .ok_or(SysError::IllegalValue)?;
+
+    Ok(tty::set_mode(mode) as u64)
+}
+
+fn get_tty_size(buf: u64) -> SyscallReturn {
+    let crit = critical::begin();
+    let buf = user::borrow_slice_mut::<TtySize>(buf, 1, &crit)?;
+
+    buf[0] = tty::size();
+
+    Ok(OK)
+}
+
+async fn sleep(nanos: u64) -> SyscallReturn {
+    time::sleep(Duration::from_nanos(nanos)).await?;
+    Ok(OK)
+}
+
+fn get_monotonic_time() -> SyscallReturn {
+    Ok(time::monotonic().as_nanos() as u64)
+}
+
+fn get_wall_time() -> SyscallReturn {
+    Ok(time::wall().as_nanos() as u64)
+}
+
+fn get_file_times(file: Handle, buf: u64) -> SyscallReturn {
+    let file = object::get(task::current(), file)
+        .ok_or(SysError::BadHandle)?
+        .downcast::<File>()?;
+
+    let times = file.object().times()?;
+
+    let crit = critical::begin();
+    let buf = user::borrow_slice_mut::<FileTimes>(buf, 1, &crit)?;
+
+    buf[0] = times;
+
+    Ok(OK)
+}
+
+fn set_priority(priority: u64) -> SyscallReturn {
+    let priority = Priority::from_u64(priority)
+        .ok_or(SysError::IllegalValue)?;
+
+    Ok(task::set_priority(priority) as u64)
+}
+
+async fn spawn_process(args: u64) -> SyscallReturn {
+    let current = task::current();
+
+    let mut path = ArrayVec::<[u8; 256]>::new();
+    let mut handles = ArrayVec::<[_; MAX_HANDLES]>::new();
+
+    // copied out, the new process is loaded with its own page context
+    // active and our memory out of reach:
+    let mut process_args = Box::new(Args::new())
+        .map_err(|_| SysError::MemoryExhausted)?;
+
+    let stack_pages = {
+        let crit = critical::begin();
+        let args = user::borrow_slice::<SpawnArgs>(args, 1, &crit)?[0];
+
+        let user_path = user::borrow_slice::<u8>(args.path.addr, args.path.len, &crit)?;
+
+        path.try_extend_from_slice(user_path)
+            .map_err(|_| SysError::IllegalValue)?;
+
+        for arg in user::borrow_slice::<UserSlice>(args.argv.addr, args.argv.len, &crit)? {
+            process_args.push_arg(user::borrow_slice::<u8>(arg.addr, arg.len, &crit)?)?;
+        }
+
+        for var in user::borrow_slice::<UserSlice>(args.env.addr, args.env.len, &crit)? {
+            process_args.push_env(user::borrow_slice::<u8>(var.addr, var.len, &crit)?)?;
+        }
+
+        for handle in user::borrow_slice::<u64>(args.handles.addr, args.handles.len, &crit)? {
+            let handle = Handle::from_u64(*handle)
+                .ok_or(SysError::BadHandle)?;
+
+            let object = object::get(current, handle)
+                .ok_or(SysError::BadHandle)?;
+
+            handles.try_push(object)
+                .map_err(|_| SysError::IllegalValue)?;
+        }
+
+        args.stack_pages
+    };
+
+    let child = process::spawn(&path, &process_args, handles, stack_pages).await?;
+
+    Ok(object::put(current, ObjectRef::new(child)?.as_dyn())?.into_u64())
+}
@@ -2,18 +2,26 @@ use core::future::Future;
 use core::pin::Pin;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Poll, Context, Waker, RawWaker, RawWakerVTable};
+use core::time::Duration;
 
 use alloc_collections::boxed::Box;
 use alloc_collections::btree_map::BTreeMap;
+use arraydeque::{ArrayDeque, Saturating};
+use interface::{Priority, PRIORITY_COUNT};
 
+use crate::cpu;
+use crate::critical;
+use crate::device::apic;
 use crate::fs::vfs::Filesystem;
 use crate::interrupt::TrapFrame;
 use crate::mem::kalloc::GlobalAlloc;
 use crate::mem::MemoryExhausted;
-use crate::object::ObjectRef;
+use crate::object::{self, ObjectRef};
 use crate::page::{self, PageCtx};
+use crate::smp;
 use crate::sync::{Arc, Mutex};
 use crate::syscall;
+use crate::time;
 use crate::util::EarlyInit;
 
 pub const SEG_UCODE: u16 = 0x1b;
@@ -22,7 +30,7 @@ pub const SEG_UDATA: u16 = 0x23;
 pub type TaskMap<V> = EarlyInit<Mutex<BTreeMap<TaskId, V, GlobalAlloc>>>;
 
 static TASKS: TaskMap<Task> = TaskMap::new();
-static TASK_STATES: TaskMap<TaskState> = TaskMap::new();
+static TASK_STATES: TaskMap<Sched> = TaskMap::new();
 static TASK_FUTURES: TaskMap<TaskFuture> = TaskMap::new();
 
 pub fn init() {
@@ -31,8 +39,6 @@ pub fn init() {
     EarlyInit::set(&TASK_FUTURES, Mutex::new(BTreeMap::new()));
 }
 
-static CURRENT_TASK: Mutex<Option<TaskId>> = Mutex::new(None);
-
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Ord)]
 pub struct TaskId(pub u64);
 
@@ -44,6 +50,143 @@ pub enum TaskState {
     User(TrapFrame),
 }
 
+/// How long a task runs in user mode before making way for others of the
+/// same priority
+const TIME_SLICE: Duration = Duration::from_millis(10);
+
+const RUN_QUEUE_LEN: usize = 256;
+
+type ReadyList = ArrayDeque<[TaskId; RUN_QUEUE_LEN], Saturating>;
+
+/// Ready tasks waiting for a CPU, one list per priority. Sleeping tasks are
+/// never queued, so picking the next task doesn't depend on how many there
+/// are.
+pub struct RunQueue {
+    lists: [ReadyList; PRIORITY_COUNT],
+}
+
+impl RunQueue {
+    pub fn new() -> Self {
+        RunQueue {
+            lists: [ReadyList::new(), ReadyList::new(), ReadyList::new(), ReadyList::new()],
+        }
+    }
+
+    fn push(&mut self, id: TaskId, priority: Priority, front: bool) -> Result<(), TaskId> {
+        let list = &mut self.lists[priority as usize];
+
+        let result = if front {
+            list.push_front(id)
+        } else {
+            list.push_back(id)
+        };
+
+        result.map_err(|e| e.element)
+    }
+
+    /// Next task of the highest priority with any ready
+    fn pop_front(&mut self) -> Option<TaskId> {
+        self.lists.iter_mut().rev().filter_map(|list| list.pop_front()).next()
+    }
+
+    /// Task of the highest priority that was queued last, for another CPU
+    /// to take without disturbing the ones about to run here
+    fn pop_back(&mut self) -> Option<TaskId> {
+        self.lists.iter_mut().rev().filter_map(|list| list.pop_back()).next()
+    }
+
+    fn highest_priority(&self) -> Option<usize> {
+        self.lists.iter().rposition(|list| !list.is_empty())
+    }
+}
+
+#[derive(Debug)]
+struct Sched {
+    state: TaskState,
+    priority: Priority,
+    // left of the current time slice, refilled when it runs out
+    slice_left: Duration,
+    // waiting in some CPU's run queue
+    queued: bool,
+    // in user mode or being polled on some CPU
+    running: bool,
+}
+
+impl Sched {
+    fn new(state: TaskState, priority: Priority) -> Self {
+        Sched {
+            state,
+            priority,
+            slice_left: TIME_SLICE,
+            queued: false,
+            running: false,
+        }
+    }
+
+    /// Queues the task if it has something to do and isn't already queued
+    /// or running. Whoever stops it running calls this again.
+    fn make_ready(&mut self, id: TaskId) -> Result<(), MemoryExhausted> {
+        self.enqueue(id, false)
+    }
+
+    /// Like make_ready, but for a task that was asleep. It goes ahead of
+    /// others of its priority, which keeps tasks that mostly wait on input
+    /// responsive next to ones spinning on the CPU.
+    fn make_woken(&mut self, id: TaskId) -> Result<(), MemoryExhausted> {
+        self.enqueue(id, true)
+    }
+
+    fn enqueue(&mut self, id: TaskId, front: bool) -> Result<(), MemoryExhausted> {
+        if let TaskState::Sleep = self.state {
+            return Ok(());
+        }
+
+        if !self.queued && !self.running {
+            enqueue(id, self.priority, front)?;
+            self.queued = true;
+        }
+
+        Ok(())
+    }
+}
+
+/// Puts a task on this CPU's run queue, or any other with room, and wakes an
+/// idle CPU to take it
+fn enqueue(id: TaskId, priority: Priority, front: bool) -> Result<(), MemoryExhausted> {
+    let this = cpu::current();
+
+    let queued = this.run_queue.lock().push(id, priority, front).is_ok()
+        || cpu::all().any(|cpu| cpu.run_queue.lock().push(id, priority, front).is_ok());
+
+    if !queued {
+        return Err(MemoryExhausted);
+    }
+
+    let idle = cpu::all()
+        .find(|cpu| cpu.id() != this.id() && cpu.idle.load(Ordering::SeqCst));
+
+    if let Some(idle) = idle {
+        apic::send_ipi(idle.apic_id, smp::RESCHEDULE_VECTOR);
+    }
+
+    Ok(())
+}
+
+/// Takes the next task from this CPU's run queue, stealing from another
+/// CPU's if ours is empty
+fn dequeue() -> Option<TaskId> {
+    let this = cpu::current();
+
+    if let Some(id) = this.run_queue.lock().pop_front() {
+        return Some(id);
+    }
+
+    cpu::all()
+        .filter(|cpu| cpu.id() != this.id())
+        .filter_map(|cpu| cpu.run_queue.lock().pop_back())
+        .next()
+}
+
 type TaskFuture = Arc<Mutex<Pin<Box<dyn Future<Output = ()>, GlobalAlloc>>>>;
 
 #[derive(Debug)]
@@ -60,14 +203,29 @@ fn alloc_task_id() -> TaskId {
 
 pub fn spawn<F, Fut>(page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<Filesystem>>, f: F) -> Result<TaskId, MemoryExhausted>
     where F: FnOnce(TaskEmbryo) -> Fut, Fut: Future<Output = ()> + 'static
+{
+    spawn_prepared(page_ctx, filesystem, |task| Ok(f(task)))
+}
+
+/// Like spawn, but `f` may also set up state keyed by the new task's id,
+/// like its handles, before it can run. If `f` fails that state is dropped
+/// and the task never starts.
+pub fn spawn_prepared<F, Fut>(page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<Filesystem>>, f: F) -> Result<TaskId, MemoryExhausted>
+    where F: FnOnce(TaskEmbryo) -> Result<Fut, MemoryExhausted>, Fut: Future<Output = ()> + 'static
 {
     let id = alloc_task_id();
 
-    let state = TaskState::Wake;
+    // tasks start out with the priority of the task creating them:
+    let priority = current_priority().unwrap_or_default();
+    let state = Sched::new(TaskState::Wake, priority);
 
     let future = {
-        let future = Box::new(f(TaskEmbryo { task_id: id }))
-            .map_err(|_| MemoryExhausted)?;
+        let future = f(TaskEmbryo { task_id: id })
+            .and_then(|future| Box::new(future).map_err(|_| MemoryExhausted))
+            .map_err(|e| {
+                object::drop_all_for_task(id);
+                e
+            })?;
 
         let future_obj = future as Box<dyn Future<Output = ()>, GlobalAlloc>;
 
@@ -79,8 +237,20 @@ pub fn spawn<F, Fut>(page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<Filesy
 
     // try inserting all task related data:
     let result: Result<_, MemoryExhausted> = (|| {
-        TASK_STATES.lock().insert(id, state)
-            .map_err(|_| MemoryExhausted)?;
+        {
+            let mut task_states = TASK_STATES.lock();
+
+            // with no more tasks than fit in a single CPU's list for one
+            // priority, every task can always be queued again once it's
+            // running. this doesn't depend on the CPU count, which is still
+            // growing while the APs come online:
+            if task_states.len() >= RUN_QUEUE_LEN {
+                return Err(MemoryExhausted);
+            }
+
+            task_states.insert(id, state)
+                .map_err(|_| MemoryExhausted)?;
+        }
 
         TASK_FUTURES.lock().insert(id, Arc::new(Mutex::new(future))?)
             .map_err(|_| MemoryExhausted)?;
@@ -88,7 +258,10 @@ pub fn spawn<F, Fut>(page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<Filesy
         TASKS.lock().insert(id, task)
             .map_err(|_| MemoryExhausted)?;
 
-        Ok(())
+        TASK_STATES.lock()
+            .get_mut(&id)
+            .expect("id not in TASK_STATES")
+            .make_ready(id)
     })();
 
     // roll back inserts if any error:
@@ -98,16 +271,85 @@ pub fn spawn<F, Fut>(page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<Filesy
             TASKS.lock().remove(&id);
             TASK_FUTURES.lock().remove(&id);
             TASK_STATES.lock().remove(&id);
+            object::drop_all_for_task(id);
             Err(MemoryExhausted)
         }
     }
 }
 
 pub fn current() -> TaskId {
-    CURRENT_TASK.lock()
+    cpu::current().current_task.lock()
         .expect("task::current called with no current task")
 }
 
+fn current_priority() -> Option<Priority> {
+    let current = (*cpu::current().current_task.lock())?;
+
+    TASK_STATES.lock()
+        .get(&current)
+        .map(|sched| sched.priority)
+}
+
+/// Changes the priority of the current task, returning the old one. It takes
+/// effect the next time the task is queued, as it's running now.
+pub fn set_priority(priority: Priority) -> Priority {
+    let current = current();
+
+    let mut task_states = TASK_STATES.lock();
+
+    let sched = task_states.get_mut(&current)
+        .expect("current task not in TASK_STATES");
+
+    core::mem::replace(&mut sched.priority, priority)
+}
+
+/// Called on the way back to user mode from the timer interrupt or a
+/// syscall. Switches tasks if the one running has used up its time slice or
+/// something more important is waiting, otherwise rearms the timer for the
+/// rest of the slice.
+pub unsafe fn preempt(frame: &mut TrapFrame) {
+    fn should_switch(slice_end: Duration) -> bool {
+        let cpu = cpu::current();
+
+        if time::monotonic() >= slice_end {
+            return true;
+        }
+
+        let current = match *cpu.current_task.lock() {
+            Some(current) => current,
+            None => return false,
+        };
+
+        let priority = match TASK_STATES.lock().get(&current) {
+            Some(sched) => sched.priority as usize,
+            None => return false,
+        };
+
+        // anything more important queued here goes first:
+        cpu.run_queue.lock()
+            .highest_priority()
+            .map(|highest| highest > priority)
+            .unwrap_or(false)
+    }
+
+    let slice_end = Duration::from_nanos(cpu::current().slice_end.load(Ordering::SeqCst));
+
+    if should_switch(slice_end) {
+        switch(frame);
+    } else {
+        time::arm_timer(Some(slice_end));
+    }
+}
+
+/// Arms this CPU's timer for the end of the current task's time slice, on
+/// the way back to user mode. Ticks that fire while the kernel handles a
+/// trap are dropped, so without this the slice might never end. Interrupts
+/// must stay disabled until the return to user mode.
+pub fn rearm_timer() {
+    let slice_end = Duration::from_nanos(cpu::current().slice_end.load(Ordering::SeqCst));
+    time::arm_timer(Some(slice_end));
+}
+
 pub fn get_page_ctx() -> ObjectRef<PageCtx> {
     TASKS.lock()
         .get(&current())
@@ -124,6 +366,41 @@ pub fn get_filesystem() -> Option<Arc<Filesystem>> {
         .clone()
 }
 
+/// Replaces the page context of the current task and switches to it,
+/// returning the old one. The task keeps the new context across switches.
+pub fn set_page_ctx(page_ctx: ObjectRef<PageCtx>) -> ObjectRef<PageCtx> {
+    let old = core::mem::replace(
+        &mut TASKS.lock()
+            .get_mut(&current())
+            .expect("task::set_page_ctx called with no current task")
+            .page_ctx,
+        page_ctx.clone());
+
+    unsafe { page::set_ctx(page_ctx.object().clone()); }
+
+    old
+}
+
+/// Switches the current task to another page context until dropped, for
+/// working in an address space that isn't the task's own
+pub struct ActivePageCtx {
+    previous: Option<ObjectRef<PageCtx>>,
+}
+
+impl ActivePageCtx {
+    pub fn enter(page_ctx: ObjectRef<PageCtx>) -> Self {
+        ActivePageCtx { previous: Some(set_page_ctx(page_ctx)) }
+    }
+}
+
+impl Drop for ActivePageCtx {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            set_page_ctx(previous);
+        }
+    }
+}
+
 pub fn set_filesystem(fs: Option<Arc<Filesystem>>) {
     TASKS.lock()
         .get_mut(&current())
@@ -143,46 +420,61 @@ pub unsafe fn start() -> ! {
 }
 
 pub unsafe fn switch(frame: &mut TrapFrame) {
-    fn save_current_task(frame: &mut TrapFrame) -> Option<TaskId> {
-        let current = (*CURRENT_TASK.lock())?;
+    fn save_current_task(frame: &mut TrapFrame) {
+        let cpu = cpu::current();
+
+        let current = match cpu.current_task.lock().take() {
+            Some(current) => current,
+            None => return,
+        };
 
         let mut task_states = TASK_STATES.lock();
 
-        let state = task_states
+        let sched = task_states
             .get_mut(&current)
             .expect("task id not in TASK_STATES");
 
-        match *state {
+        // charge it for the time it spent in user mode:
+        let slice_end = Duration::from_nanos(cpu.slice_end.swap(0, Ordering::SeqCst));
+
+        sched.slice_left = slice_end.checked_sub(time::monotonic())
+            .unwrap_or(Duration::from_secs(0));
+
+        match sched.state {
             TaskState::User(ref mut task_frame) => {
                 *task_frame = frame.clone();
             }
             _ => {}
         }
 
-        Some(current)
+        // back of the queue, for another CPU to pick up if this one is busy:
+        sched.running = false;
+        sched.make_ready(current)
+            .expect("no room to queue a task that was running");
     }
 
     enum WorkItem {
         Kernel(TaskFuture),
-        User(TrapFrame),
+        User(TrapFrame, Duration),
     }
 
-    fn find_next_work_item(previous_task_id: Option<TaskId>) -> (TaskId, WorkItem) {
-        let tasks = TASKS.lock();
+    fn find_next_work_item() -> Option<(TaskId, WorkItem)> {
+        while let Some(id) = dequeue() {
+            let mut task_states = TASK_STATES.lock();
 
-        let previous_task_id = previous_task_id.unwrap_or(TaskId(0));
+            let sched = match task_states.get_mut(&id) {
+                Some(sched) => sched,
+                // killed after it was queued
+                None => continue,
+            };
 
-        let next_tasks = tasks.range(previous_task_id..)
-            .filter(|(id, _)| **id != previous_task_id)
-            .chain(tasks.range(..=previous_task_id));
+            sched.queued = false;
 
-        for (id, _) in next_tasks {
-            let mut task_states = TASK_STATES.lock();
-
-            let state = task_states.get_mut(&id)
-                .expect("id not in TASK_STATES");
+            if sched.slice_left == Duration::from_secs(0) {
+                sched.slice_left = TIME_SLICE;
+            }
 
-            let work_item = match *state {
+            let work_item = match sched.state {
                 TaskState::Sleep => {
                     continue;
                 }
@@ -192,25 +484,61 @@ pub unsafe fn switch(frame: &mut TrapFrame) {
                         .cloned()
                         .expect("id not in TASK_FUTURES");
 
+                    // the task goes to sleep after this poll unless it is
+                    // woken in the meantime. SyscallEntry holds the trap
+                    // frame, TaskResume moves it to Sleep once it's taken.
+                    if let TaskState::Wake = sched.state {
+                        sched.state = TaskState::Sleep;
+                    }
+
                     WorkItem::Kernel(future)
                 }
                 TaskState::User(ref task_frame) => {
-                    WorkItem::User(task_frame.clone())
+                    WorkItem::User(task_frame.clone(), sched.slice_left)
                 }
             };
 
-            return (*id, work_item);
+            sched.running = true;
+
+            return Some((id, work_item));
         }
 
-        panic!("there should always be a task ready to run!");
+        None
     }
 
-    let mut previous_task_id = save_current_task(frame);
+    save_current_task(frame);
+
+    let cpu = cpu::current();
 
     loop {
-        let (task_id, work_item) = find_next_work_item(previous_task_id);
+        let (task_id, work_item) = loop {
+            let _crit = critical::begin();
+
+            if let Some(next) = find_next_work_item() {
+                break next;
+            }
+
+            // let other CPUs know to send us an IPI when they queue work,
+            // then look once more in case some arrived before they could
+            // have seen this:
+            cpu.idle.store(true, Ordering::SeqCst);
 
-        *CURRENT_TASK.lock() = Some(task_id);
+            if let Some(next) = find_next_work_item() {
+                cpu.idle.store(false, Ordering::SeqCst);
+                break next;
+            }
+
+            // nothing is runnable, wait for an interrupt to wake something.
+            // the timer only needs to fire for the next sleeper, if any.
+            // sti only takes effect after the next instruction, so a wake up
+            // can't slip in between the check above and the hlt:
+            time::arm_timer(None);
+            asm!("sti; hlt" :::: "volatile");
+
+            cpu.idle.store(false, Ordering::SeqCst);
+        };
+
+        *cpu.current_task.lock() = Some(task_id);
 
         let page_ctx = TASKS.lock()
             .get(&task_id)
@@ -222,20 +550,14 @@ pub unsafe fn switch(frame: &mut TrapFrame) {
 
         match work_item {
             WorkItem::Kernel(future) => {
-                let waker = Waker::from_raw(task_waker_new(task_id));
-                let mut cx = Context::from_waker(&waker);
-                let mut fut = future.lock();
-
-                match fut.as_mut().poll(&mut cx) {
-                    Poll::Ready(()) => panic!("task finished!"),
-                    Poll::Pending => {
-                        // TODO set task state to sleep
-                    }
-                }
-
-                previous_task_id = Some(task_id);
+                poll_task(task_id, &future);
+                stop_running(task_id);
             }
-            WorkItem::User(task_frame) => {
+            WorkItem::User(task_frame, slice_left) => {
+                let slice_end = time::monotonic() + slice_left;
+                cpu.slice_end.store(slice_end.as_nanos() as u64, Ordering::SeqCst);
+                time::arm_timer(Some(slice_end));
+
                 *frame = task_frame;
                 return;
             }
@@ -243,15 +565,67 @@ pub unsafe fn switch(frame: &mut TrapFrame) {
     }
 }
 
+/// Ends the current task, which trapped from user mode with `frame`, and
+/// switches to the next. Its handles are released, but not the memory in
+/// its page context.
+pub unsafe fn kill_current(frame: &mut TrapFrame) {
+    let cpu = cpu::current();
+
+    let id = cpu.current_task.lock().take()
+        .expect("task::kill_current called with no current task");
+
+    cpu.slice_end.store(0, Ordering::SeqCst);
+
+    // CR3 holds its own reference to the page context until the switch
+    TASKS.lock().remove(&id);
+    TASK_STATES.lock().remove(&id);
+
+    TASK_FUTURES.lock().remove(&id);
+
+    object::drop_all_for_task(id);
+
+    switch(frame);
+}
+
+fn poll_task(task_id: TaskId, future: &TaskFuture) {
+    let waker = unsafe { Waker::from_raw(task_waker_new(task_id)) };
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = future.lock();
+
+    match fut.as_mut().poll(&mut cx) {
+        Poll::Ready(()) => panic!("task finished!"),
+        Poll::Pending => {
+            // the task is now asleep, awake again if it was woken during
+            // the poll, or back in user mode
+        }
+    }
+}
+
+// Takes a task that was polled on this CPU off it, queueing it again if it
+// has more to do
+fn stop_running(task_id: TaskId) {
+    cpu::current().current_task.lock().take();
+
+    let mut task_states = TASK_STATES.lock();
+
+    let sched = task_states.get_mut(&task_id)
+        .expect("id not in TASK_STATES");
+
+    sched.running = false;
+    sched.make_ready(task_id)
+        .expect("no room to queue a task that was running");
+}
+
 pub unsafe fn dispatch_syscall(frame: &mut TrapFrame) {
-    {
-        let current_task = CURRENT_TASK.lock()
-            .expect("no current task for syscall entry");
+    let current_task = cpu::current().current_task.lock()
+        .expect("no current task for syscall entry");
 
+    {
         let mut task_states = TASK_STATES.lock();
 
-        let task_state = task_states.get_mut(&current_task)
-            .expect("current task in TASK_STATES");
+        let task_state = &mut task_states.get_mut(&current_task)
+            .expect("current task in TASK_STATES")
+            .state;
 
         match task_state {
             TaskState::User(_) => {
@@ -265,8 +639,31 @@ pub unsafe fn dispatch_syscall(frame: &mut TrapFrame) {
         *task_state = TaskState::SyscallEntry(frame.clone());
     }
 
-    // TODO don't switch immediately but process syscall on this task first:
-    switch(frame)
+    // most syscalls complete without blocking, so serve them right away on
+    // the calling task rather than waiting for the scheduler to come round:
+    let future = TASK_FUTURES.lock()
+        .get(&current_task)
+        .cloned()
+        .expect("current task in TASK_FUTURES");
+
+    poll_task(current_task, &future);
+
+    let resumed = match TASK_STATES.lock().get(&current_task) {
+        Some(Sched { state: TaskState::User(task_frame), .. }) => Some(task_frame.clone()),
+        _ => None,
+    };
+
+    match resumed {
+        Some(task_frame) => {
+            *frame = task_frame;
+            preempt(frame);
+        }
+        None => {
+            // blocked, it runs again from the scheduler once woken
+            stop_running(current_task);
+            switch(frame);
+        }
+    }
 }
 
 static TASK_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
@@ -287,8 +684,16 @@ unsafe fn task_waker_clone(data: *const ()) -> RawWaker {
 unsafe fn task_waker_wake(data: *const ()) {
     let task_id = TaskId(data as u64);
 
-    if let Some(state) = TASK_STATES.lock().get_mut(&task_id) {
-        *state = TaskState::Wake;
+    let mut task_states = TASK_STATES.lock();
+
+    if let Some(sched) = task_states.get_mut(&task_id) {
+        // stale wakers may fire for tasks running in user mode or with a
+        // syscall pending, whose trap frames must not be lost:
+        if let TaskState::Sleep = sched.state {
+            sched.state = TaskState::Wake;
+            sched.make_woken(task_id)
+                .expect("no room to queue a woken task");
+        }
     }
 }
 
@@ -303,6 +708,10 @@ pub struct TaskEmbryo {
 }
 
 impl TaskEmbryo {
+    pub fn id(&self) -> TaskId {
+        self.task_id
+    }
+
     pub fn setup(self, trap_frame: TrapFrame) -> TaskRun {
         TaskRun {
             task_id: self.task_id,
@@ -320,10 +729,10 @@ impl TaskRun {
     pub fn run(&mut self) -> TaskResume {
         let mut task_states = TASK_STATES.lock();
 
-        let task_state = task_states.get_mut(&self.task_id)
+        let sched = task_states.get_mut(&self.task_id)
             .expect("id not in TASK_STATES");
 
-        *task_state = TaskState::User(self.trap_frame.clone());
+        sched.state = TaskState::User(self.trap_frame.clone());
 
         TaskResume { task_run: self }
     }
@@ -357,16 +766,20 @@ impl<'a> Future for TaskResume<'a> {
     fn poll(mut self: Pin<&mut Self>, _cx: &mut core::task::Context) -> Poll<Self::Output> {
         let mut task_states = TASK_STATES.lock();
 
-        let task_state = task_states.get_mut(&self.task_run.task_id)
-            .expect("id not in TASK_STATES");
+        let task_state = &mut task_states.get_mut(&self.task_run.task_id)
+            .expect("id not in TASK_STATES")
+            .state;
 
         let (trap, frame) = match *task_state {
             TaskState::SyscallEntry(ref frame) => (Trap::Syscall, frame.clone()),
-            TaskState::Wake => return Poll::Pending,
+            TaskState::Wake | TaskState::Sleep => return Poll::Pending,
             TaskState::User(_) => return Poll::Pending,
-            TaskState::Sleep => panic!("task state should not be Sleep"),
         };
 
+        // the task now runs in the kernel until the syscall either completes
+        // or waits on something to wake it:
+        *task_state = TaskState::Sleep;
+
         self.task_run.trap_frame = frame;
         Poll::Ready(trap)
     }
@@ -0,0 +1,220 @@
+use core::arch::x86_64::_rdtsc;
+use core::cmp;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use alloc_collections::btree_map::BTreeMap;
+
+use crate::device::{apic, pit, rtc};
+use crate::interrupt::{self, IrqHandler};
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+use crate::sync::{Arc, Mutex};
+use crate::util::EarlyInit;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 86400;
+
+/// A calendar date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch. Dates before 1970 clamp to it, and
+    /// fields out of range give None.
+    pub fn to_unix(&self) -> Option<u64> {
+        let valid = self.month >= 1 && self.month <= 12
+            && self.day >= 1 && self.day <= 31
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60;
+
+        if !valid {
+            return None;
+        }
+
+        // days since 0000-03-01 in the proleptic Gregorian calendar, with
+        // years starting in March so leap days fall at the end of a year
+        let month = self.month as u64;
+        let year = self.year as u64 - if month <= 2 { 1 } else { 0 };
+
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+        // 1970-01-01 is this many days after 0000-03-01:
+        let days = (era * 146097 + day_of_era).saturating_sub(719468);
+
+        Some(days * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64)
+    }
+}
+
+// the monotonic clock counts TSC cycles since boot_tsc, which stays zero
+// until init
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+// wall clock time at boot in nanoseconds since the Unix epoch
+static BOOT_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+// sleeping futures by (deadline in nanoseconds since boot, timer id)
+static TIMERS: EarlyInit<Mutex<BTreeMap<(u64, u64), Waker, GlobalAlloc>>> = EarlyInit::new();
+
+struct TimerExpired;
+
+impl IrqHandler for TimerExpired {
+    fn handle_irq(&self) {
+        let now = monotonic_nanos();
+
+        let mut timers = TIMERS.lock();
+
+        loop {
+            let key = match timers.keys().next() {
+                Some(key) if key.0 <= now => *key,
+                _ => break,
+            };
+
+            if let Some(waker) = timers.remove(&key) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// Safety: must not be called more than once, and only after interrupt::init,
+// pit::init and apic::init
+pub unsafe fn init() {
+    EarlyInit::set(&TIMERS, Mutex::new(BTreeMap::new()));
+
+    let tsc_hz = pit::calibrate_tsc();
+    TSC_HZ.store(tsc_hz, Ordering::SeqCst);
+    BOOT_TSC.store(_rdtsc(), Ordering::SeqCst);
+
+    crate::info!("TSC runs at {} MHz", tsc_hz / 1_000_000);
+
+    let date_time = rtc::read();
+
+    let unix = date_time.to_unix().unwrap_or_else(|| {
+        crate::warn!("RTC holds an invalid date, counting from the epoch");
+        0
+    });
+
+    let epoch = Duration::from_secs(unix)
+        .checked_sub(monotonic())
+        .unwrap_or(Duration::from_secs(0));
+    BOOT_EPOCH.store(epoch.as_nanos() as u64, Ordering::SeqCst);
+
+    crate::info!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        date_time.year, date_time.month, date_time.day,
+        date_time.hour, date_time.minute, date_time.second);
+
+    let handler = Arc::new(TimerExpired)
+        .expect("Arc::new in time::init");
+
+    interrupt::register_irq(interrupt::TIMER_IRQ, handler)
+        .expect("interrupt::register_irq in time::init");
+}
+
+/// Time since boot, counted by the TSC. Stays at zero until init.
+pub fn monotonic() -> Duration {
+    Duration::from_nanos(monotonic_nanos())
+}
+
+fn monotonic_nanos() -> u64 {
+    let tsc_hz = TSC_HZ.load(Ordering::SeqCst);
+
+    if tsc_hz == 0 {
+        return 0;
+    }
+
+    let elapsed = unsafe { _rdtsc() }.wrapping_sub(BOOT_TSC.load(Ordering::SeqCst));
+
+    (elapsed as u128 * NANOS_PER_SEC as u128 / tsc_hz as u128) as u64
+}
+
+/// Time since the Unix epoch, as read from the RTC at boot and kept by the
+/// monotonic clock since
+pub fn wall() -> Duration {
+    Duration::from_nanos(BOOT_EPOCH.load(Ordering::SeqCst)) + monotonic()
+}
+
+/// Programs this CPU's timer for the earlier of the next sleep deadline and
+/// `slice_end`, or stops it if there's neither. There is no periodic tick,
+/// so whoever leaves the scheduler must call this.
+pub fn arm_timer(slice_end: Option<Duration>) {
+    let next_timer = TIMERS.lock()
+        .keys()
+        .next()
+        .map(|key| Duration::from_nanos(key.0));
+
+    let deadline = match (next_timer, slice_end) {
+        (Some(a), Some(b)) => Some(cmp::min(a, b)),
+        (a, b) => a.or(b),
+    };
+
+    // deadlines already passed fire as soon as the timer allows:
+    let delay = deadline.map(|deadline| {
+        deadline.checked_sub(monotonic()).unwrap_or(Duration::from_secs(0))
+    });
+
+    if apic::is_enabled() {
+        apic::set_timer(delay);
+    } else {
+        pit::set_timer(delay);
+    }
+}
+
+fn alloc_timer_id() -> u64 {
+    static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_TIMER_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// Completes once `duration` has passed
+pub fn sleep(duration: Duration) -> Sleep {
+    let duration = cmp::min(duration.as_nanos(), u64::max_value() as u128) as u64;
+    let deadline = monotonic_nanos().saturating_add(duration);
+
+    Sleep { key: (deadline, alloc_timer_id()) }
+}
+
+pub struct Sleep {
+    key: (u64, u64),
+}
+
+impl Future for Sleep {
+    type Output = Result<(), MemoryExhausted>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut timers = TIMERS.lock();
+
+        if monotonic_nanos() >= self.key.0 {
+            timers.remove(&self.key);
+            return Poll::Ready(Ok(()));
+        }
+
+        match timers.insert(self.key, cx.waker().clone()) {
+            Ok(_) => Poll::Pending,
+            Err(_) => Poll::Ready(Err(MemoryExhausted)),
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        TIMERS.lock().remove(&self.key);
+    }
+}
@@ -0,0 +1,85 @@
+; application processors start executing here in real mode, at the page named
+; by the startup IPI. smp.rs copies this to AP_TRAMPOLINE, identity maps it,
+; and fills in the parameters at AP_TRAMPOLINE_PARAMS
+
+%include "kernel/src/consts.asm"
+
+%define CR4_PAGE_SIZE_EXT (1 << 4)
+%define CR4_PHYS_ADDR_EXT (1 << 5)
+
+org AP_TRAMPOLINE
+
+bits 16
+ap_start:
+    cli
+    cld
+
+    xor ax, ax
+    mov ds, ax
+
+    o32 lgdt [gdtr]
+
+    ; same extensions as start.asm
+    mov eax, cr4
+    or eax, CR4_PAGE_SIZE_EXT | CR4_PHYS_ADDR_EXT
+    mov cr4, eax
+
+    ; the kernel's own page tables, which map this page too
+    mov eax, [params.cr3]
+    mov cr3, eax
+
+    ; enable long mode and the no-execute page bit, as start.asm does
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    ; enable protection and paging at once, going straight to long mode
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 0)
+    mov cr0, eax
+
+    jmp SEG_KCODE:long_mode
+
+bits 64
+long_mode:
+    mov ax, SEG_KDATA
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    mov rsp, [params.stack_top]
+
+    ; ap_main(cpu, stack_top) never returns
+    mov rdi, [params.cpu]
+    mov rsi, [params.stack_top]
+    mov rax, [params.entry]
+    call rax
+
+.hang:
+    cli
+    hlt
+    jmp .hang
+
+align 8
+gdtr:
+    dw (gdt.end - gdt) - 1
+    dd gdt
+
+gdt:
+    ; null entry
+    dq 0
+    ; kernel code entry
+    dq GDT64_DESCRIPTOR | GDT64_PRESENT | GDT64_READWRITE | GDT64_EXECUTABLE | GDT64_64BIT
+    ; kernel data entry
+    dq GDT64_DESCRIPTOR | GDT64_PRESENT | GDT64_READWRITE
+.end:
+
+times (AP_TRAMPOLINE_PARAMS - AP_TRAMPOLINE) - ($ - $$) db 0
+
+; must match TrampolineParams in smp.rs
+params:
+    .cr3        dq 0
+    .stack_top  dq 0
+    .cpu        dq 0
+    .entry      dq 0
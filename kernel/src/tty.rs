@@ -0,0 +1,228 @@
+use core::cmp;
+use core::fmt::Write;
+use core::str;
+
+use arrayvec::ArrayVec;
+use interface::{SysError, SysResult, TtyMode, TtySize};
+
+use crate::console;
+use crate::device::keyboard;
+use crate::sync::{AsyncMutex, Mutex};
+
+const LINE_MAX: usize = 256;
+
+const CTRL_C: char = '\x03';
+const CTRL_D: char = '\x04';
+const CTRL_U: char = '\x15';
+const BACKSPACE: char = '\x08';
+const DELETE: char = '\x7f';
+const ESCAPE: char = '\x1b';
+
+/// Where we are in a terminal escape sequence from the keyboard. These are
+/// swallowed in canonical mode, there's nothing to edit with them yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+#[derive(Debug)]
+struct LineDiscipline {
+    mode: TtyMode,
+    line: ArrayVec<[u8; LINE_MAX]>,
+    // bytes at the front of line terminated by enter or ^D, ready to be read
+    ready: usize,
+    // ^D on an empty line, the next read returns end of file
+    eof: bool,
+    // ^C, the next read fails
+    interrupted: bool,
+    escape: Escape,
+}
+
+static TTY: Mutex<Option<LineDiscipline>> = Mutex::new(None);
+
+// one reader at a time, so that lines are not split between readers
+static READER: AsyncMutex<()> = AsyncMutex::new(());
+
+fn echo(s: &str) {
+    let _ = console::get().write_str(s);
+}
+
+impl LineDiscipline {
+    fn editable(&self) -> bool {
+        self.line.len() > self.ready
+    }
+
+    /// Removes the last character from the line being edited, which may be
+    /// several bytes of UTF-8
+    fn erase_char(&mut self) {
+        while self.editable() {
+            let b = self.line.pop().expect("line.pop");
+
+            if (b & 0xc0) != 0x80 {
+                break;
+            }
+        }
+
+        echo("\x08 \x08");
+    }
+
+    fn input(&mut self, c: char) {
+        match self.escape {
+            Escape::Esc => {
+                self.escape = if c == '[' { Escape::Csi } else { Escape::None };
+                return;
+            }
+            Escape::Csi => {
+                // parameter bytes continue the sequence, anything else ends it
+                if !('\x30'..='\x3f').contains(&c) {
+                    self.escape = Escape::None;
+                }
+                return;
+            }
+            Escape::None => {}
+        }
+
+        match c {
+            ESCAPE => {
+                self.escape = Escape::Esc;
+            }
+            BACKSPACE | DELETE => {
+                if self.editable() {
+                    self.erase_char();
+                }
+            }
+            CTRL_U => {
+                while self.editable() {
+                    self.erase_char();
+                }
+            }
+            CTRL_C => {
+                self.line.truncate(self.ready);
+                self.interrupted = true;
+                echo("^C\n");
+            }
+            CTRL_D => {
+                if self.editable() {
+                    self.ready = self.line.len();
+                } else {
+                    self.eof = true;
+                }
+            }
+            '\n' | '\r' => {
+                // text input always leaves room for this newline, unless
+                // the buffer is full of lines nobody has read yet:
+                if self.line.try_push(b'\n').is_ok() {
+                    self.ready = self.line.len();
+                    echo("\n");
+                }
+            }
+            c if c < ' ' && c != '\t' => {
+                // other control characters have no meaning here
+            }
+            c => {
+                let mut utf8 = [0u8; 4];
+                let s = c.encode_utf8(&mut utf8);
+
+                if self.line.len() + s.len() < LINE_MAX {
+                    self.line.extend(s.bytes());
+                    echo(s);
+                }
+            }
+        }
+    }
+
+    /// Hands out a completed line, or as much of it as fits in `buf`.
+    /// Returns None if the reader has to wait for more input.
+    fn take(&mut self, buf: &mut [u8]) -> Option<SysResult<usize>> {
+        if self.interrupted {
+            self.interrupted = false;
+            return Some(Err(SysError::Interrupted));
+        }
+
+        if self.ready > 0 {
+            let count = cmp::min(self.ready, buf.len());
+            buf[..count].copy_from_slice(&self.line[..count]);
+            self.line.drain(..count);
+            self.ready -= count;
+            return Some(Ok(count));
+        }
+
+        if self.eof {
+            self.eof = false;
+            return Some(Ok(0));
+        }
+
+        None
+    }
+}
+
+// Safety: must not be called more than once
+pub unsafe fn init() {
+    *TTY.lock() = Some(LineDiscipline {
+        mode: TtyMode::Canonical,
+        line: ArrayVec::new(),
+        ready: 0,
+        eof: false,
+        interrupted: false,
+        escape: Escape::None,
+    });
+}
+
+/// Switches the console between canonical and raw mode, returning the mode
+/// it was in. Any partially edited line is discarded.
+pub fn set_mode(mode: TtyMode) -> TtyMode {
+    let mut tty = TTY.lock();
+    let tty = tty.as_mut().expect("tty to be initialized");
+
+    tty.line.truncate(tty.ready);
+    tty.escape = Escape::None;
+
+    let previous = tty.mode;
+    tty.mode = mode;
+    previous
+}
+
+pub fn size() -> TtySize {
+    console::size()
+}
+
+pub async fn read(buf: &mut [u8]) -> SysResult<usize> {
+    if buf.len() == 0 {
+        return Ok(0);
+    }
+
+    let _reader = READER.lock().await?;
+
+    loop {
+        let mode = {
+            let mut tty = TTY.lock();
+            let tty = tty.as_mut().expect("tty to be initialized");
+
+            // lines finished before a switch to raw mode are still
+            // delivered first:
+            if let Some(result) = tty.take(buf) {
+                return result;
+            }
+
+            tty.mode
+        };
+
+        if mode == TtyMode::Raw {
+            return Ok(keyboard::read_text(buf).await?);
+        }
+
+        // keyboard text always comes in whole characters:
+        let mut input = [0u8; 16];
+        let len = keyboard::read_text(&mut input).await?;
+        let input = str::from_utf8(&input[..len]).unwrap_or("");
+
+        let mut tty = TTY.lock();
+        let tty = tty.as_mut().expect("tty to be initialized");
+
+        for c in input.chars() {
+            tty.input(c);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use core::future::Future;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Poll, Waker};
+
+use futures::future;
+
+use crate::mem::MemoryExhausted;
+use crate::util::AtomicList;
+
+/// Wakers of tasks waiting on something an interrupt handler signals. Each
+/// waiter registers once per interrupt rather than on every poll, so the
+/// list doesn't grow while a task is polled for other reasons.
+pub struct IrqWakers {
+    wakers: AtomicList<Waker>,
+    // calls to wake_all so far, each one taking every waker registered
+    wakes: AtomicU64,
+}
+
+impl IrqWakers {
+    pub const fn new() -> Self {
+        IrqWakers {
+            wakers: AtomicList::new(),
+            wakes: AtomicU64::new(0),
+        }
+    }
+
+    /// Wakes every task waiting, called from the interrupt handler
+    pub fn wake_all(&self) {
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+
+        for waker in self.wakers.take_iter() {
+            waker.wake();
+        }
+    }
+
+    /// Completes with the result of `poll` once it's ready, checking again
+    /// each time we're woken
+    pub fn wait<'a, T>(&'a self, mut poll: impl FnMut() -> Poll<T> + 'a)
+        -> impl Future<Output = Result<T, MemoryExhausted>> + 'a
+    {
+        // wakes when our waker was last registered. until another one takes
+        // it, polling again doesn't need another
+        let mut registered = None;
+
+        future::poll_fn(move |ctx| {
+            // register before checking, so that an interrupt in between
+            // can't leave us asleep with what we're waiting for ready:
+            let wakes = self.wakes.load(Ordering::SeqCst);
+
+            if registered != Some(wakes) {
+                if let Err(e) = self.wakers.push_front(ctx.waker().clone()) {
+                    return Poll::Ready(Err(e));
+                }
+
+                registered = Some(wakes);
+            }
+
+            poll().map(Ok)
+        })
+    }
+}
@@ -4,6 +4,9 @@ pub use atomic_list::AtomicList;
 mod early_init;
 pub use early_init::EarlyInit;
 
+mod irq_wakers;
+pub use irq_wakers::IrqWakers;
+
 use core::iter;
 use core::str::{self, Utf8Error};
 
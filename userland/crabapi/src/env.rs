@@ -0,0 +1,124 @@
+use core::ptr;
+use core::slice;
+
+pub use interface::{AuxEntry, AT_NULL, AT_PHDR, AT_PHENT, AT_PHNUM, AT_PAGESZ, AT_ENTRY};
+pub use interface::{AT_HANDLE_BASE, AT_HANDLE_COUNT};
+
+// start info the kernel left on our stack, see crt0.asm
+static mut START_INFO: *const u64 = ptr::null();
+
+#[export_name = "crabapi_start"]
+pub unsafe extern "C" fn start(start_info: *const u64) {
+    START_INFO = start_info;
+}
+
+unsafe fn c_str(ptr: *const u8) -> &'static [u8] {
+    let mut len = 0;
+
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+
+    slice::from_raw_parts(ptr, len)
+}
+
+// Pointers up to the null ending them
+unsafe fn pointers(start: *const u64) -> &'static [u64] {
+    let mut len = 0;
+
+    while *start.add(len) != 0 {
+        len += 1;
+    }
+
+    slice::from_raw_parts(start, len)
+}
+
+fn argv() -> &'static [u64] {
+    unsafe {
+        if START_INFO.is_null() {
+            return &[];
+        }
+
+        let argc = *START_INFO as usize;
+        slice::from_raw_parts(START_INFO.add(1), argc)
+    }
+}
+
+// envp starts past argc, argv and its null
+fn envp() -> &'static [u64] {
+    unsafe {
+        if START_INFO.is_null() {
+            return &[];
+        }
+
+        pointers(START_INFO.add(1 + argv().len() + 1))
+    }
+}
+
+/// Iterates over the arguments this program was started with, usually
+/// beginning with its path
+pub struct Args(slice::Iter<'static, u64>);
+
+impl Iterator for Args {
+    type Item = &'static [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|arg| unsafe { c_str(*arg as *const u8) })
+    }
+}
+
+pub fn args() -> Args {
+    Args(argv().iter())
+}
+
+/// Iterates over the environment this program was started with, as name
+/// and value pairs
+pub struct Vars(slice::Iter<'static, u64>);
+
+impl Iterator for Vars {
+    type Item = (&'static [u8], &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|var| {
+            let var = unsafe { c_str(*var as *const u8) };
+
+            // the kernel only passes on vars containing '='
+            let split = var.iter().position(|b| *b == b'=').unwrap_or(var.len());
+            let value = var.get(split + 1..).unwrap_or(&[]);
+
+            (&var[..split], value)
+        })
+    }
+}
+
+pub fn vars() -> Vars {
+    Vars(envp().iter())
+}
+
+pub fn var(name: &[u8]) -> Option<&'static [u8]> {
+    vars().find(|(n, _)| *n == name).map(|(_, value)| value)
+}
+
+/// Looks up an entry in the auxiliary vector, such as AT_PAGESZ or
+/// AT_HANDLE_COUNT
+pub fn aux(kind: u64) -> Option<u64> {
+    unsafe {
+        if START_INFO.is_null() {
+            return None;
+        }
+
+        // past envp and its null:
+        let envp = envp();
+        let mut entry = envp.as_ptr().add(envp.len() + 1) as *const AuxEntry;
+
+        while (*entry).kind != AT_NULL {
+            if (*entry).kind == kind {
+                return Some((*entry).value);
+            }
+
+            entry = entry.add(1);
+        }
+
+        None
+    }
+}
@@ -2,6 +2,8 @@ use crate::Handle;
 use crate::io::{Result, Read, Write};
 use crate::syscall;
 
+pub use interface::FileTimes;
+
 #[derive(Clone)]
 pub struct File(Handle);
 
@@ -13,6 +15,17 @@ impl File {
 
         Result::from(ret).map(File)
     }
+
+    /// Creation and modification times, where the filesystem records them
+    pub fn times(&self) -> Result<FileTimes> {
+        let mut times = FileTimes::default();
+
+        let result = unsafe {
+            syscall::get_file_times(self.0.as_raw(), &mut times)
+        };
+
+        Result::<u64>::from(result).map(|_| times)
+    }
 }
 
 impl Read for File {
@@ -0,0 +1,40 @@
+use core::mem;
+use core::ptr;
+
+use crate::fs::File;
+use crate::io::{Read, Result};
+use crate::syscall;
+
+pub use interface::{KeyCode, KeyEvent, Keymap};
+
+/// Sets the layout the kernel uses to turn key presses into console text
+pub fn set_keymap(keymap: Keymap) -> Result<()> {
+    let result = unsafe { syscall::set_keymap(keymap as u64) };
+    Result::<u64>::from(result).map(|_| ())
+}
+
+/// Raw key press and release events from /dev/keyboard
+pub struct Keyboard(File);
+
+impl Keyboard {
+    pub fn open() -> Result<Keyboard> {
+        File::open(b"/dev/keyboard").map(Keyboard)
+    }
+
+    /// Waits for at least one key event, filling as much of `events` as are
+    /// already available. Returns the number of events read.
+    pub fn read(&mut self, events: &mut [KeyEvent]) -> Result<usize> {
+        const EVENT_SIZE: usize = mem::size_of::<KeyEvent>();
+
+        let mut buf = [0u8; 16 * EVENT_SIZE];
+        let max = core::cmp::min(events.len(), 16) * EVENT_SIZE;
+
+        let len = self.0.read(&mut buf[..max])?;
+
+        for (event, raw) in events.iter_mut().zip(buf[..len].chunks_exact(EVENT_SIZE)) {
+            *event = unsafe { ptr::read_unaligned(raw.as_ptr() as *const KeyEvent) };
+        }
+
+        Ok(len / EVENT_SIZE)
+    }
+}
@@ -4,10 +4,18 @@
 #![feature(panic_info_message)]
 #![feature(start)]
 
+pub mod env;
 pub mod fs;
 pub mod io;
+pub mod keyboard;
+pub mod log;
+pub mod mouse;
+pub mod pci;
+pub mod process;
 pub mod syscall;
 pub mod task;
+pub mod time;
+pub mod tty;
 
 mod panic;
 
@@ -0,0 +1,70 @@
+use core::mem;
+use core::ptr;
+use core::str;
+
+use crate::io::Result;
+use crate::syscall;
+
+pub use interface::{LogLevel, LogRecordHeader};
+
+/// A single kernel log record, borrowed from a buffer filled by `read`
+#[derive(Debug, Clone, Copy)]
+pub struct Record<'a> {
+    pub seq: u64,
+    pub level: Option<LogLevel>,
+    pub module: &'a str,
+    pub message: &'a str,
+}
+
+/// Fills `buf` with whole kernel log records, starting at the record with
+/// sequence number `start_seq` or the oldest one the kernel still holds.
+/// Returns the number of bytes used, which is zero once caught up.
+pub fn read(buf: &mut [u8], start_seq: u64) -> Result<usize> {
+    let result = unsafe {
+        syscall::read_log(buf.as_mut_ptr(), buf.len() as u64, start_seq)
+    };
+
+    result.into()
+}
+
+/// Sets the most verbose level recorded for `module` and its submodules.
+/// An empty module sets the default for the whole kernel.
+pub fn set_level(module: &str, level: LogLevel) -> Result<()> {
+    let result = unsafe {
+        syscall::set_log_level(module.as_ptr(), module.len() as u64, level as u64)
+    };
+
+    Result::<u64>::from(result).map(|_| ())
+}
+
+/// Iterates the records in the filled portion of a buffer from `read`
+pub fn records(buf: &[u8]) -> impl Iterator<Item = Record<'_>> {
+    let mut buf = buf;
+
+    core::iter::from_fn(move || {
+        if buf.len() < mem::size_of::<LogRecordHeader>() {
+            return None;
+        }
+
+        let header = unsafe { ptr::read_unaligned(buf.as_ptr() as *const LogRecordHeader) };
+
+        let len = header.len as usize;
+
+        if len < mem::size_of::<LogRecordHeader>() || len > buf.len() {
+            return None;
+        }
+
+        let body = &buf[mem::size_of::<LogRecordHeader>()..len];
+        let module = body.get(..header.module_len as usize)?;
+        let message = body.get(module.len()..(module.len() + header.message_len as usize))?;
+
+        buf = &buf[len..];
+
+        Some(Record {
+            seq: header.seq,
+            level: LogLevel::from_u8(header.level),
+            module: str::from_utf8(module).unwrap_or("?"),
+            message: str::from_utf8(message).unwrap_or("?"),
+        })
+    })
+}
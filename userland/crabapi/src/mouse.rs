@@ -0,0 +1,33 @@
+use core::mem;
+use core::ptr;
+
+use crate::fs::File;
+use crate::io::{Read, Result};
+
+pub use interface::{MouseEvent, MOUSE_LEFT, MOUSE_MIDDLE, MOUSE_RIGHT};
+
+/// Relative motion and button events from /dev/mouse
+pub struct Mouse(File);
+
+impl Mouse {
+    pub fn open() -> Result<Mouse> {
+        File::open(b"/dev/mouse").map(Mouse)
+    }
+
+    /// Waits for at least one mouse event, filling as much of `events` as are
+    /// already available. Returns the number of events read.
+    pub fn read(&mut self, events: &mut [MouseEvent]) -> Result<usize> {
+        const EVENT_SIZE: usize = mem::size_of::<MouseEvent>();
+
+        let mut buf = [0u8; 16 * EVENT_SIZE];
+        let max = core::cmp::min(events.len(), 16) * EVENT_SIZE;
+
+        let len = self.0.read(&mut buf[..max])?;
+
+        for (event, raw) in events.iter_mut().zip(buf[..len].chunks_exact(EVENT_SIZE)) {
+            *event = unsafe { ptr::read_unaligned(raw.as_ptr() as *const MouseEvent) };
+        }
+
+        Ok(len / EVENT_SIZE)
+    }
+}
@@ -0,0 +1,14 @@
+use crate::io::Result;
+use crate::syscall;
+
+pub use interface::PciDeviceInfo;
+
+/// Fills `buf` with information about devices on the PCI bus. Returns the
+/// total number of devices present, which may be larger than `buf`.
+pub fn devices(buf: &mut [PciDeviceInfo]) -> Result<usize> {
+    let result = unsafe {
+        syscall::list_pci_devices(buf.as_mut_ptr(), buf.len() as u64)
+    };
+
+    result.into()
+}
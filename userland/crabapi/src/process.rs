@@ -0,0 +1,95 @@
+use arrayvec::ArrayVec;
+
+use crate::Handle;
+use crate::io::{Error, Result};
+use crate::syscall;
+
+pub use interface::{SpawnArgs, UserSlice, DEFAULT_STACK_PAGES, MAX_STACK_PAGES};
+
+// the kernel's limits, more than this is refused by SpawnProcess anyway
+const MAX_ARGS: usize = 64;
+const MAX_HANDLES: usize = 16;
+
+fn user_slice<T>(slice: &[T]) -> UserSlice {
+    UserSlice { addr: slice.as_ptr() as u64, len: slice.len() as u64 }
+}
+
+/// Builds up the arguments for starting a program in a new process
+pub struct Command<'a> {
+    path: &'a [u8],
+    args: ArrayVec<[UserSlice; MAX_ARGS]>,
+    env: ArrayVec<[UserSlice; MAX_ARGS]>,
+    handles: ArrayVec<[u64; MAX_HANDLES]>,
+    stack_pages: u64,
+    overflowed: bool,
+}
+
+impl<'a> Command<'a> {
+    /// Starts a command for the executable at `path`, which is also passed
+    /// as the first argument
+    pub fn new(path: &'a [u8]) -> Self {
+        let mut command = Command {
+            path,
+            args: ArrayVec::new(),
+            env: ArrayVec::new(),
+            handles: ArrayVec::new(),
+            stack_pages: 0,
+            overflowed: false,
+        };
+
+        command.arg(path);
+        command
+    }
+
+    pub fn arg(&mut self, arg: &'a [u8]) -> &mut Self {
+        if self.args.try_push(user_slice(arg)).is_err() {
+            self.overflowed = true;
+        }
+
+        self
+    }
+
+    /// Sets an environment variable in the new process, given as
+    /// `NAME=value`. Nothing is inherited from this process.
+    pub fn env(&mut self, var: &'a [u8]) -> &mut Self {
+        if self.env.try_push(user_slice(var)).is_err() {
+            self.overflowed = true;
+        }
+
+        self
+    }
+
+    /// Gives the new process a handle to the same object, numbered by the
+    /// order handles are added in, starting from 1
+    pub fn handle(&mut self, handle: &'a Handle) -> &mut Self {
+        if self.handles.try_push(handle.as_raw()).is_err() {
+            self.overflowed = true;
+        }
+
+        self
+    }
+
+    /// Sets the size of the stack the process starts on, up to
+    /// MAX_STACK_PAGES. The default is DEFAULT_STACK_PAGES.
+    pub fn stack_pages(&mut self, pages: u64) -> &mut Self {
+        self.stack_pages = pages;
+        self
+    }
+
+    /// Starts the process, returning a handle to its task
+    pub fn spawn(&self) -> Result<Handle> {
+        if self.overflowed {
+            return Err(Error::IllegalValue);
+        }
+
+        let args = SpawnArgs {
+            path: user_slice(self.path),
+            argv: user_slice(&self.args),
+            env: user_slice(&self.env),
+            handles: user_slice(&self.handles),
+            stack_pages: self.stack_pages,
+        };
+
+        unsafe { syscall::spawn_process(&args) }.into()
+    }
+}
@@ -1,6 +1,6 @@
 use core::convert::TryInto;
 
-use interface::{SysResult, SysError, Syscall};
+use interface::{FileTimes, PciDeviceInfo, SpawnArgs, SysResult, SysError, Syscall, TtySize};
 use interface::ERR_FLAG;
 
 use crate::Handle;
@@ -111,11 +111,33 @@ unsafe fn syscall4(vector: Syscall, a: u64, b: u64, c: u64, d: u64) -> SyscallRe
     ret
 }
 
+unsafe fn syscall5(vector: Syscall, a: u64, b: u64, c: u64, d: u64, e: u64) -> SyscallResult {
+    let ret: SyscallResult;
+
+    asm!("int 0x7f" :
+        "={rax}"(ret)
+    :
+        "{rax}"(vector as u64),
+        "{rdi}"(a),
+        "{rsi}"(b),
+        "{rdx}"(c),
+        "{rcx}"(d),
+        "{r8}"(e)
+    :: "intel");
+
+    ret
+}
+
 #[export_name = "syscall_alloc_page"]
 pub unsafe extern "C" fn alloc_page(base_addr: *mut u8, page_count: u64, flags: u64) -> SyscallResult {
     syscall3(Syscall::AllocPage, base_addr as u64, page_count, flags)
 }
 
+#[export_name = "syscall_reserve_pages"]
+pub unsafe extern "C" fn reserve_pages(base_addr: *mut u8, page_count: u64, flags: u64) -> SyscallResult {
+    syscall3(Syscall::ReservePages, base_addr as u64, page_count, flags)
+}
+
 #[export_name = "syscall_release_page"]
 pub unsafe extern "C" fn release_page(base_addr: *mut u8, page_count: u64) -> SyscallResult {
     syscall2(Syscall::ReleasePage, base_addr as u64, page_count)
@@ -146,6 +168,21 @@ pub unsafe extern "C" fn create_page_context() -> SyscallResult {
     syscall0(Syscall::CreatePageContext)
 }
 
+#[export_name = "syscall_clone_page_context"]
+pub unsafe extern "C" fn clone_page_context(page_ctx: u64) -> SyscallResult {
+    syscall1(Syscall::ClonePageContext, page_ctx)
+}
+
+#[export_name = "syscall_create_memory_object"]
+pub unsafe extern "C" fn create_memory_object(page_count: u64) -> SyscallResult {
+    syscall1(Syscall::CreateMemoryObject, page_count)
+}
+
+#[export_name = "syscall_map_memory_object"]
+pub unsafe extern "C" fn map_memory_object(memory: u64, base_addr: *mut u8, page_offset: u64, page_count: u64, flags: u64) -> SyscallResult {
+    syscall5(Syscall::MapMemoryObject, memory, base_addr as u64, page_offset, page_count, flags)
+}
+
 #[export_name = "syscall_debug"]
 pub unsafe extern "C" fn debug() -> SyscallResult {
     syscall0(Syscall::Debug)
@@ -162,8 +199,8 @@ pub unsafe extern "C" fn get_page_context() -> SyscallResult {
 }
 
 #[export_name = "syscall_create_task"]
-pub unsafe extern "C" fn create_task(page_ctx: u64, rip: u64, rsp: u64) -> SyscallResult {
-    syscall3(Syscall::CreateTask, page_ctx, rip, rsp)
+pub unsafe extern "C" fn create_task(page_ctx: u64, rip: u64, stack_pages: u64) -> SyscallResult {
+    syscall3(Syscall::CreateTask, page_ctx, rip, stack_pages)
 }
 
 #[export_name = "syscall_exit"]
@@ -185,3 +222,63 @@ pub unsafe extern "C" fn write_stream(file: u64, buff: *const u8, buff_len: u64)
 pub unsafe extern "C" fn open_file(path: *const u8, path_len: u64, flags: u64) -> SyscallResult {
     syscall3(Syscall::OpenFile, path as u64, path_len, flags)
 }
+
+#[export_name = "syscall_list_pci_devices"]
+pub unsafe extern "C" fn list_pci_devices(buf: *mut PciDeviceInfo, count: u64) -> SyscallResult {
+    syscall2(Syscall::ListPciDevices, buf as u64, count)
+}
+
+#[export_name = "syscall_read_log"]
+pub unsafe extern "C" fn read_log(buf: *mut u8, buf_len: u64, start_seq: u64) -> SyscallResult {
+    syscall3(Syscall::ReadLog, buf as u64, buf_len, start_seq)
+}
+
+#[export_name = "syscall_set_log_level"]
+pub unsafe extern "C" fn set_log_level(module: *const u8, module_len: u64, level: u64) -> SyscallResult {
+    syscall3(Syscall::SetLogLevel, module as u64, module_len, level)
+}
+
+#[export_name = "syscall_set_keymap"]
+pub unsafe extern "C" fn set_keymap(keymap: u64) -> SyscallResult {
+    syscall1(Syscall::SetKeymap, keymap)
+}
+
+#[export_name = "syscall_set_tty_mode"]
+pub unsafe extern "C" fn set_tty_mode(mode: u64) -> SyscallResult {
+    syscall1(Syscall::SetTtyMode, mode)
+}
+
+#[export_name = "syscall_get_tty_size"]
+pub unsafe extern "C" fn get_tty_size(size: *mut TtySize) -> SyscallResult {
+    syscall1(Syscall::GetTtySize, size as u64)
+}
+
+#[export_name = "syscall_sleep"]
+pub unsafe extern "C" fn sleep(nanos: u64) -> SyscallResult {
+    syscall1(Syscall::Sleep, nanos)
+}
+
+#[export_name = "syscall_get_monotonic_time"]
+pub unsafe extern "C" fn get_monotonic_time() -> SyscallResult {
+    syscall0(Syscall::GetMonotonicTime)
+}
+
+#[export_name = "syscall_get_wall_time"]
+pub unsafe extern "C" fn get_wall_time() -> SyscallResult {
+    syscall0(Syscall::GetWallTime)
+}
+
+#[export_name = "syscall_get_file_times"]
+pub unsafe extern "C" fn get_file_times(file: u64, times: *mut FileTimes) -> SyscallResult {
+    syscall2(Syscall::GetFileTimes, file, times as u64)
+}
+
+#[export_name = "syscall_set_priority"]
+pub unsafe extern "C" fn set_priority(priority: u64) -> SyscallResult {
+    syscall1(Syscall::SetPriority, priority)
+}
+
+#[export_name = "syscall_spawn_process"]
+pub unsafe extern "C" fn spawn_process(args: *const SpawnArgs) -> SyscallResult {
+    syscall1(Syscall::SpawnProcess, args as u64)
+}
@@ -1,6 +1,18 @@
+use crate::io::{Error, Result};
 use crate::syscall;
 
+pub use interface::Priority;
+
 pub fn exit(status: u64) -> ! {
     unsafe { syscall::exit(status); }
     unreachable!()
 }
+
+/// Changes the scheduling priority of the calling task, returning the
+/// previous priority. Tasks it creates start with the same priority.
+pub fn set_priority(priority: Priority) -> Result<Priority> {
+    let result = unsafe { syscall::set_priority(priority as u64) };
+
+    Result::<u64>::from(result)
+        .and_then(|priority| Priority::from_u64(priority).ok_or(Error::IllegalValue))
+}
@@ -0,0 +1,31 @@
+use core::cmp;
+use core::time::Duration;
+
+use crate::io::Result;
+use crate::syscall;
+
+/// Puts the calling task to sleep for at least `duration`
+pub fn sleep(duration: Duration) -> Result<()> {
+    let nanos = cmp::min(duration.as_nanos(), u64::max_value() as u128) as u64;
+    let result = unsafe { syscall::sleep(nanos) };
+
+    Result::<u64>::from(result).map(|_| ())
+}
+
+/// Time since boot, which never goes backwards
+pub fn monotonic() -> Duration {
+    let result = unsafe { syscall::get_monotonic_time() };
+
+    Result::<u64>::from(result)
+        .map(Duration::from_nanos)
+        .expect("syscall::get_monotonic_time")
+}
+
+/// Time since the Unix epoch, from the real-time clock
+pub fn wall() -> Duration {
+    let result = unsafe { syscall::get_wall_time() };
+
+    Result::<u64>::from(result)
+        .map(Duration::from_nanos)
+        .expect("syscall::get_wall_time")
+}
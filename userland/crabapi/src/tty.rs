@@ -0,0 +1,21 @@
+use crate::io::{Error, Result};
+use crate::syscall;
+
+pub use interface::{TtyMode, TtySize};
+
+/// Switches the console between line at a time canonical reads and raw
+/// reads, returning the previous mode
+pub fn set_mode(mode: TtyMode) -> Result<TtyMode> {
+    let result = unsafe { syscall::set_tty_mode(mode as u64) };
+
+    Result::<u64>::from(result)
+        .and_then(|mode| TtyMode::from_u64(mode).ok_or(Error::IllegalValue))
+}
+
+/// Returns the console size in character cells
+pub fn size() -> Result<TtySize> {
+    let mut size = TtySize::default();
+    let result = unsafe { syscall::get_tty_size(&mut size) };
+
+    Result::<u64>::from(result).map(|_| size)
+}
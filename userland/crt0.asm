@@ -1,26 +1,16 @@
 global _start
 extern main
-extern syscall_alloc_page
+extern crabapi_start
 extern syscall_exit
 
-%define STACK_TOP   0x80000000
-%define STACK_SIZE  (64 * 1024)
-%define PAGE_SIZE   4096
-%define PAGE_WRITE  2
-
 _start:
     xchg bx, bx
 
-    ; allocate stack
-    mov rdi, STACK_TOP - STACK_SIZE
-    mov rsi, STACK_SIZE / PAGE_SIZE
-    mov rdx, PAGE_WRITE
-    mov rax, 1 ; SYSCALL_ALLOC_PAGE
-    int 0x7f
-    ; assume that works for now, TODO check error
+    ; the kernel maps our stack and starts us with rsp pointing at argc, see
+    ; the start info layout in the interface crate
 
-    ; setup stack
-    mov rsp, STACK_TOP
+    mov rdi, rsp
+    call crabapi_start
 
     call main
 